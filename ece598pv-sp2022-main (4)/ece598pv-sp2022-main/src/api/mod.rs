@@ -1,9 +1,17 @@
 use serde::Serialize;
 use crate::blockchain::Blockchain;
 use crate::miner::Handle as MinerHandle;
+use crate::miner::strategy::Strategy;
 use crate::types::hash::Hashable;
 use crate::network::server::Handle as NetworkServerHandle;
+use crate::network::allowlist::Allowlist;
+use crate::network::banman::{BanKey, BanManager};
 use crate::network::message::Message;
+use crate::network::outbound::Handle as OutboundHandle;
+use crate::network::peer::Direction;
+use crate::network::ping::PingStats;
+use crate::network::queue::QueueStats;
+use crate::network::sync::SyncManager;
 use crate::types::transaction::generate_tx_loop;
 use crate::types::block::Block;
 use log::info;
@@ -20,6 +28,10 @@ pub struct Server {
     miner: MinerHandle,
     network: NetworkServerHandle,
     blockchain: Arc<Mutex<Blockchain>>,
+    sync: Arc<Mutex<SyncManager>>,
+    outbound: OutboundHandle,
+    banman: Arc<Mutex<BanManager>>,
+    allowlist: Option<Arc<Mutex<Allowlist>>>,
 }
 
 #[derive(Serialize)]
@@ -28,6 +40,28 @@ struct ApiResponse {
     message: String,
 }
 
+#[derive(Serialize)]
+struct PeerLatency {
+    addr: std::net::SocketAddr,
+    #[serde(flatten)]
+    stats: PingStats,
+}
+
+#[derive(Serialize)]
+struct PeerConnection {
+    addr: std::net::SocketAddr,
+    direction: Direction,
+    /// Seconds since the handshake
+    uptime_secs: u64,
+}
+
+#[derive(Serialize)]
+struct PeerQueue {
+    addr: std::net::SocketAddr,
+    #[serde(flatten)]
+    stats: QueueStats,
+}
+
 macro_rules! respond_result {
     ( $req:expr, $success:expr, $message:expr ) => {{
         let content_type = "Content-Type: application/json".parse::<Header>().unwrap();
@@ -50,11 +84,16 @@ macro_rules! respond_json {
 }
 
 impl Server {
+    #[allow(clippy::too_many_arguments)]
     pub fn start(
         addr: std::net::SocketAddr,
         miner: &MinerHandle,
         network: &NetworkServerHandle,
         blockchain: &Arc<Mutex<Blockchain>>,
+        sync: &Arc<Mutex<SyncManager>>,
+        outbound: &OutboundHandle,
+        banman: &Arc<Mutex<BanManager>>,
+        allowlist: &Option<Arc<Mutex<Allowlist>>>,
     ) {
         let handle = HTTPServer::http(&addr).unwrap();
         let server = Self {
@@ -62,6 +101,10 @@ impl Server {
             miner: miner.clone(),
             network: network.clone(),
             blockchain: Arc::clone(blockchain),
+            sync: Arc::clone(sync),
+            outbound: outbound.clone(),
+            banman: Arc::clone(banman),
+            allowlist: allowlist.clone(),
         };
         thread::spawn(move || {
             let started_tx_gen = Arc::new(Mutex::new(false));
@@ -70,6 +113,10 @@ impl Server {
                 let miner = server.miner.clone();
                 let network = server.network.clone();
                 let blockchain = Arc::clone(&server.blockchain);
+                let sync = Arc::clone(&server.sync);
+                let outbound = server.outbound.clone();
+                let banman = Arc::clone(&server.banman);
+                let allowlist = server.allowlist.clone();
                 let started_tx_gen = Arc::clone(&started_tx_gen);
                 thread::spawn(move || {
                     // a valid url requires a base
@@ -106,6 +153,30 @@ impl Server {
                             miner.start(lambda);
                             respond_result!(req, true, "ok");
                         }
+                        "/miner/strategy" => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let strategy = match params.get("strategy") {
+                                Some(v) => v,
+                                None => {
+                                    respond_result!(req, true, miner.strategy());
+                                    return;
+                                }
+                            };
+                            let strategy = match Strategy::parse(strategy) {
+                                Ok(v) => v,
+                                Err(e) => {
+                                    respond_result!(
+                                        req,
+                                        false,
+                                        format!("error parsing strategy: {}", e)
+                                    );
+                                    return;
+                                }
+                            };
+                            miner.set_strategy(strategy);
+                            respond_result!(req, true, "ok");
+                        }
                         "/tx-generator/start" => {
                             let params = url.query_pairs();
                             let params: HashMap<_, _> = params.into_owned().collect();
@@ -128,10 +199,25 @@ impl Server {
                                 }
                             };
 
+                            let stake_percent = match params.get("stake") {
+                                Some(v) => match v.parse::<u32>() {
+                                    Ok(v) => v,
+                                    Err(e) => {
+                                        respond_result!(
+                                            req,
+                                            false,
+                                            format!("error parsing stake: {}", e)
+                                        );
+                                        return;
+                                    }
+                                },
+                                None => 0,
+                            };
+
                             let mut started_tx_gen = started_tx_gen.lock().unwrap();
                             if !*started_tx_gen {
                                 *started_tx_gen = true;
-                                generate_tx_loop(theta, network, blockchain);
+                                generate_tx_loop(theta, stake_percent, network, blockchain);
 
                                 respond_result!(req, true, "started tx generator!");
                             } else {
@@ -139,9 +225,102 @@ impl Server {
                             }
                         }
                         "/network/ping" => {
-                            network.broadcast(Message::Ping(String::from("Test ping")));
+                            network.broadcast(Message::Ping(rand::random()));
                             respond_result!(req, true, "ok");
                         }
+                        "/network/peers" => {
+                            respond_json!(req, outbound.status());
+                        }
+                        "/network/connections" => {
+                            let mut connections: Vec<PeerConnection> = network
+                                .peers()
+                                .iter()
+                                .map(|peer| PeerConnection {
+                                    addr: *peer.addr(),
+                                    direction: peer.direction(),
+                                    uptime_secs: peer.connected().elapsed().as_secs(),
+                                })
+                                .collect();
+                            connections.sort_by_key(|connection| connection.addr);
+                            respond_json!(req, connections);
+                        }
+                        "/network/latency" => {
+                            let mut latencies: Vec<PeerLatency> = network
+                                .peers()
+                                .iter()
+                                .map(|peer| PeerLatency { addr: *peer.addr(), stats: peer.ping_stats() })
+                                .collect();
+                            latencies.sort_by_key(|latency| latency.addr);
+                            respond_json!(req, latencies);
+                        }
+                        "/network/queues" => {
+                            let mut queues: Vec<PeerQueue> = network
+                                .peers()
+                                .iter()
+                                .map(|peer| PeerQueue { addr: *peer.addr(), stats: peer.queue_stats() })
+                                .collect();
+                            queues.sort_by_key(|queue| queue.addr);
+                            respond_json!(req, queues);
+                        }
+                        "/network/bans" => {
+                            respond_json!(req, banman.lock().unwrap().bans());
+                        }
+                        "/network/unban" => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let peer = match params.get("peer") {
+                                Some(v) => v,
+                                None => {
+                                    respond_result!(req, false, "missing peer");
+                                    return;
+                                }
+                            };
+                            let peer = match peer.parse::<BanKey>() {
+                                Ok(v) => v,
+                                Err(e) => {
+                                    respond_result!(
+                                        req,
+                                        false,
+                                        format!("error parsing peer: {}", e)
+                                    );
+                                    return;
+                                }
+                            };
+                            if banman.lock().unwrap().unban(&peer) {
+                                respond_result!(req, true, "ok");
+                            } else {
+                                respond_result!(req, false, format!("{} is not banned", peer));
+                            }
+                        }
+                        "/network/allowlist/reload" => {
+                            let allowlist = match &allowlist {
+                                Some(allowlist) => allowlist,
+                                None => {
+                                    respond_result!(req, false, "not a private network");
+                                    return;
+                                }
+                            };
+                            let mut allowlist = allowlist.lock().unwrap();
+                            match allowlist.reload() {
+                                Ok(()) => {
+                                    let (identities, secrets) = allowlist.counts();
+                                    info!("Reloaded the allowlist, {} identities and {} secrets", identities, secrets);
+                                    respond_result!(
+                                        req,
+                                        true,
+                                        format!("{} identities and {} secrets", identities, secrets)
+                                    );
+                                }
+                                Err(e) => {
+                                    respond_result!(req, false, format!("error reloading allowlist: {}", e));
+                                }
+                            }
+                        }
+                        "/sync/status" => {
+                            let blockchain = blockchain.lock().unwrap();
+                            let status = sync.lock().unwrap().status(&blockchain);
+                            respond_json!(req, status);
+                        }
                         "/blockchain/longest-chain" => {
                             let blockchain = blockchain.lock().unwrap();
                             let v = blockchain.all_blocks_in_longest_chain();
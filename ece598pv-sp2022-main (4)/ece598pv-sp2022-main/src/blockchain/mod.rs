@@ -1,40 +1,77 @@
 use std::cmp;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
 
-use crate::types::block::{Block, do_generate_random_block};
-use crate::types::hash::{H256, Hashable, do_generate_random_hash};
-use crate::types::transaction::State;
+use crate::consensus::{BlockTree, Consensus, pow::ProofOfWork};
+use crate::consensus::genesis::GenesisSpec;
+use crate::types::block::{Block, Header, genesis_block};
+use crate::types::hash::{H256, Hashable};
+use crate::types::transaction::{SignedTransaction, State, execute_tx};
 use crate::types::address::Address;
 
+/// How many generations back a block may reach for uncles
+pub const MAX_UNCLE_DEPTH: u32 = 6;
+/// How many uncles a block may reference
+pub const MAX_UNCLES: usize = 2;
+/// An uncle at depth d earns (UNCLE_REWARD_DENOMINATOR - d) / UNCLE_REWARD_DENOMINATOR of the block reward
+const UNCLE_REWARD_DENOMINATOR: u32 = 8;
+/// A block earns 1 / NEPHEW_REWARD_DENOMINATOR of the block reward for each uncle it references
+const NEPHEW_REWARD_DENOMINATOR: u32 = 32;
+
 pub struct Blockchain {
     blocks: HashMap <H256, Block>,
+    genesis: H256,
+    children: HashMap<H256, Vec<H256>>,
+    // number of blocks in the subtree of each block, for fork choice rules that weigh subtrees
+    weights: HashMap<H256, u64>,
     pub block_states: HashMap<H256, State>,
+    consensus: Arc<dyn Consensus>,
+    block_reward: u32,
 }
 
 impl Blockchain {
     /// Create a new blockchain, only containing the genesis block
     pub fn new() -> Self {
-        let genesis_parent = do_generate_random_hash();
-        let mut genesis_block = do_generate_random_block(&genesis_parent);
-        genesis_block.length = 1;
+        Self::with_consensus(Arc::new(ProofOfWork::default()), &GenesisSpec::default())
+    }
+
+    /// Create a new blockchain, only containing the genesis block, that uses the given consensus
+    /// engine for fork choice and the rewards of the genesis spec
+    pub fn with_consensus(consensus: Arc<dyn Consensus>, spec: &GenesisSpec) -> Self {
+        let difficulty = spec.genesis_difficulty().expect("invalid difficulty in the genesis spec");
+        let genesis_block = genesis_block(&spec.digest(), difficulty);
         let genesis_hash = genesis_block.hash();
 
         // generate genesis block 
         let mut blocks_map = HashMap::new();
         blocks_map.insert(genesis_hash, genesis_block);
+        let mut weights = HashMap::new();
+        weights.insert(genesis_hash, 1);
+
+        let mut blockchain = Self {
+            blocks: blocks_map,
+            genesis: genesis_hash,
+            children: HashMap::new(),
+            weights,
+            block_states: HashMap::new(),
+            consensus,
+            block_reward: spec.block_reward,
+        };
+        let genesis_state = blockchain.genesis_state();
+        blockchain.block_states.insert(genesis_hash, genesis_state);
 
-        // generate the initial ICO state
-        let mut initial_block_state = HashMap::new();
+        blockchain
+    }
+
+    /// The state after a genesis block: the initial ICO, plus whatever the consensus engine needs
+    pub fn genesis_state(&self) -> State {
         let mut ico_state = HashMap::new();
         let ico_acc: u32 = 0;
         let ico_addr = Address::from_public_key_bytes(&ico_acc.to_be_bytes());
         ico_state.insert(ico_addr, (0, 100)); // initial ico account starts with 100 coins
-        initial_block_state.insert(genesis_hash, ico_state);
+        self.consensus.extend_genesis_state(&mut ico_state);
 
-        Self {
-            blocks: blocks_map,
-            block_states: initial_block_state,
-        }
+        ico_state
     }
 
     /// Insert a block into blockchain
@@ -43,27 +80,160 @@ impl Blockchain {
         let mut cloned_block = block.clone();
 
         let cloned_block_hash = cloned_block.hash();
-        if cloned_block.length > 1 { // inserting a non-genesis block
-            let parent_hash = cloned_block.get_parent();
-            cloned_block.length = self.blocks[&parent_hash].length  + 1;
+        if let Some(parent_block) = self.blocks.get(&cloned_block.get_parent()) { // inserting a non-genesis block
+            cloned_block.length = parent_block.length + 1;
+            if !self.blocks.contains_key(&cloned_block_hash) {
+                self.children.entry(cloned_block.get_parent()).or_default().push(cloned_block_hash);
+                // the new block adds to the weight of every ancestor
+                self.weights.insert(cloned_block_hash, 1);
+                let mut ancestor = cloned_block.get_parent();
+                while let Some(weight) = self.weights.get_mut(&ancestor) {
+                    *weight += 1;
+                    ancestor = self.blocks[&ancestor].get_parent();
+                }
+            }
         }
 
+        self.consensus.block_inserted(&cloned_block);
         self.blocks.insert(cloned_block_hash, cloned_block);
     }
 
-    /// Get the last block's hash of the longest chain
-    pub fn tip(&self) -> H256 {
-        let mut max_length = 0;
-        let mut max_hash: H256 = [0; 32].into(); // temp value to start the search
+    /// Get the hashes of `block_hash` and its ancestors, from the block itself up to `count` blocks
+    fn ancestors(&self, block_hash: &H256, count: u32) -> Vec<H256> {
+        let mut ancestors = Vec::new();
+        let mut curr = *block_hash;
+        while ancestors.len() < count as usize {
+            match self.blocks.get(&curr) {
+                Some(block) => {
+                    ancestors.push(curr);
+                    curr = block.get_parent();
+                }
+                None => break,
+            }
+        }
+
+        ancestors
+    }
 
-        for (hash, block) in self.blocks.iter() {
-            if block.length >= max_length {
-                max_length = self.blocks[hash].length;
-                max_hash = *hash;
+    /// Uncles already referenced by `ancestors`
+    fn referenced_uncles(&self, ancestors: &[H256]) -> HashSet<H256> {
+        ancestors
+            .iter()
+            .flat_map(|hash| self.blocks[hash].header.uncles.iter().map(|uncle| uncle.hash()))
+            .collect()
+    }
+
+    /// Get the headers of the stale blocks that a new block on top of `parent` may reference as
+    /// uncles, the most recent first. An uncle is a child of one of the new block's ancestors,
+    /// at most `MAX_UNCLE_DEPTH` generations back, that is neither an ancestor itself nor
+    /// referenced by an ancestor already.
+    pub fn uncle_candidates(&self, parent: &H256) -> Vec<Header> {
+        let ancestors = self.ancestors(parent, MAX_UNCLE_DEPTH + 1);
+        let referenced = self.referenced_uncles(&ancestors);
+
+        let mut candidates = Vec::new();
+        for ancestor in ancestors.iter().skip(1) {
+            if let Some(children) = self.children.get(ancestor) {
+                for child in children.iter() {
+                    if !ancestors.contains(child) && !referenced.contains(child) {
+                        candidates.push(self.blocks[child].header.clone());
+                    }
+                }
             }
         }
 
-        max_hash
+        candidates
+    }
+
+    /// Check the uncles referenced by `block`, whose parent has to be in the blockchain. Returns
+    /// the depth of each uncle, i.e. how many generations the uncle is older than the block.
+    pub fn validate_uncles(&self, block: &Block) -> Result<Vec<u32>, String> {
+        if block.header.uncles.len() > MAX_UNCLES {
+            return Err(format!("{} uncles, at most {} allowed", block.header.uncles.len(), MAX_UNCLES));
+        }
+
+        let ancestors = self.ancestors(&block.get_parent(), MAX_UNCLE_DEPTH + 1);
+        let referenced = self.referenced_uncles(&ancestors);
+        let mut seen = HashSet::new();
+        let mut depths = Vec::new();
+        for uncle in block.header.uncles.iter() {
+            let uncle_hash = uncle.hash();
+            if !seen.insert(uncle_hash) || referenced.contains(&uncle_hash) {
+                return Err(format!("uncle {:?} is referenced twice", uncle_hash));
+            }
+            if ancestors.contains(&uncle_hash) {
+                return Err(format!("uncle {:?} is an ancestor", uncle_hash));
+            }
+            let depth = match ancestors.iter().skip(1).position(|hash| *hash == uncle.parent) {
+                Some(position) => position as u32 + 1,
+                None => return Err(format!("uncle {:?} is not a sibling of a recent ancestor", uncle_hash)),
+            };
+
+            let uncle_parent = &self.blocks[&uncle.parent];
+            let uncle_block = Block {
+                length: uncle_parent.length + 1,
+                header: uncle.clone(),
+                data: Vec::new(),
+            };
+            let uncle_parent_state = match self.block_states.get(&uncle.parent) {
+                Some(state) => state,
+                None => return Err(format!("no state for the parent of uncle {:?}", uncle_hash)),
+            };
+            if !self.consensus.verify_seal(&uncle_block, uncle_parent, uncle_parent_state) {
+                return Err(format!("uncle {:?} has an invalid seal", uncle_hash));
+            }
+            depths.push(depth);
+        }
+
+        Ok(depths)
+    }
+
+    /// Execute the transactions of `block` on top of the state of its parent, then pay the block
+    /// and uncle rewards. Returns the new state and the transactions that are valid, or why the
+    /// block is invalid: its uncles are, or a reward overflows a balance.
+    pub fn execute_block(&self, block: &Block, parent_state: &State) -> Result<(State, Vec<SignedTransaction>), String> {
+        let depths = self.validate_uncles(block)?;
+        let (mut state, valid_tx) = execute_tx(parent_state, &block.data);
+        if self.block_reward == 0 {
+            return Ok((state, valid_tx));
+        }
+
+        let overflow = || "block reward overflows".to_string();
+        let reward = (self.block_reward / NEPHEW_REWARD_DENOMINATOR)
+            .checked_mul(depths.len() as u32)
+            .and_then(|nephew_reward| nephew_reward.checked_add(self.block_reward))
+            .ok_or_else(overflow)?;
+        credit(&mut state, block.header.beneficiary, reward)?;
+        for (uncle, depth) in block.header.uncles.iter().zip(depths.iter()) {
+            let uncle_reward = self
+                .block_reward
+                .checked_mul(UNCLE_REWARD_DENOMINATOR - depth)
+                .ok_or_else(overflow)?
+                / UNCLE_REWARD_DENOMINATOR;
+            credit(&mut state, uncle.beneficiary, uncle_reward)?;
+        }
+
+        Ok((state, valid_tx))
+    }
+
+    /// Get the last block's hash of the best chain, as chosen by the consensus engine
+    pub fn tip(&self) -> H256 {
+        self.consensus.best_tip(&BlockTree {
+            blocks: &self.blocks,
+            genesis: self.genesis,
+            children: &self.children,
+            weights: &self.weights,
+        })
+    }
+
+    /// Get the hash of the genesis block
+    pub fn genesis_hash(&self) -> H256 {
+        self.genesis
+    }
+
+    /// Get the consensus engine of this blockchain
+    pub fn consensus(&self) -> Arc<dyn Consensus> {
+        Arc::clone(&self.consensus)
     }
 
     /// Get all blocks' hashes of the longest chain, ordered from genesis to the tip
@@ -79,6 +249,44 @@ impl Blockchain {
         longest_chain
     }
 
+    /// Get a block locator for the best chain: the hashes of the tip and the blocks below it,
+    /// with exponentially growing gaps after the first ten, ending at the genesis block
+    pub fn block_locator(&self) -> Vec<H256> {
+        let chain = self.all_blocks_in_longest_chain();
+        let mut locator = Vec::new();
+        let mut step = 1;
+        let mut index = 0;
+        while index < chain.len() {
+            locator.push(chain[index]);
+            if locator.len() >= 10 {
+                step *= 2;
+            }
+            index += step;
+        }
+        if locator.last() != chain.last() {
+            locator.push(*chain.last().unwrap());
+        }
+
+        locator
+    }
+
+    /// Get the headers of up to `max` blocks of the best chain that follow the first block of
+    /// `locator` that is on the best chain, ordered from the oldest
+    pub fn headers_after(&self, locator: &[H256], max: usize) -> Vec<Header> {
+        let mut chain = self.all_blocks_in_longest_chain();
+        chain.reverse();
+        let fork_point = locator
+            .iter()
+            .find_map(|hash| chain.iter().position(|h| h == hash))
+            .unwrap_or(0);
+
+        chain[fork_point + 1..]
+            .iter()
+            .take(max)
+            .map(|hash| self.blocks[hash].header.clone())
+            .collect()
+    }
+
     // Returns a cloned block given the hash
     pub fn get_block (&self, block_hash: &H256) -> Option<&Block> {
         self.blocks.get(block_hash)
@@ -100,13 +308,19 @@ impl Blockchain {
 
 }
 
+fn credit(state: &mut State, account: Address, amount: u32) -> Result<(), String> {
+    let entry = state.entry(account).or_insert((0, 0));
+    entry.1 = entry.1.checked_add(amount).ok_or_else(|| format!("reward overflows the balance of {}", account))?;
+    Ok(())
+}
+
 // DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. BEFORE TEST
 
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::types::block::generate_random_block;
-    use crate::types::hash::Hashable;
+    use crate::types::hash::{Hashable, generate_random_hash};
     use ntest::timeout;
 
     #[test]
@@ -166,6 +380,84 @@ mod tests {
         assert_eq!(3,blockchain.blocks[&blockchain.tip()].length);
 
     }
+    #[test]
+    fn uncles_are_referenced_and_rewarded() {
+        let spec = GenesisSpec { block_reward: 64, ..Default::default() };
+        let mut blockchain = Blockchain::with_consensus(Arc::new(crate::consensus::AcceptAll), &spec);
+        let genesis_hash = blockchain.tip();
+        let nephew_owner = Address::from_public_key_bytes(b"nephew");
+        let uncle_owner = Address::from_public_key_bytes(b"uncle");
+
+        let first = generate_random_block(&genesis_hash);
+        let mut stale = generate_random_block(&genesis_hash);
+        stale.header.beneficiary = uncle_owner;
+        blockchain.insert(&first);
+        blockchain.insert(&stale);
+        let second = generate_random_block(&first.hash());
+        blockchain.insert(&second);
+
+        let candidates = blockchain.uncle_candidates(&second.hash());
+        assert_eq!(candidates.len(), 1);
+        assert_eq!(candidates[0].hash(), stale.hash());
+
+        let mut nephew = generate_random_block(&second.hash());
+        nephew.header.beneficiary = nephew_owner;
+        nephew.header.uncles = candidates;
+        assert_eq!(blockchain.validate_uncles(&nephew), Ok(vec![2]));
+        let (state, _) = blockchain.execute_block(&nephew, &State::new()).unwrap();
+        assert_eq!(state[&nephew_owner].1, 64 + 2);
+        assert_eq!(state[&uncle_owner].1, 48);
+
+        // a reward that does not fit in the balance makes the block invalid
+        let mut rich = State::new();
+        rich.insert(uncle_owner, (0, u32::MAX - 10));
+        assert!(blockchain.execute_block(&nephew, &rich).is_err());
+
+        nephew.header.uncles.push(stale.header.clone());
+        assert!(blockchain.validate_uncles(&nephew).is_err());
+        assert!(blockchain.execute_block(&nephew, &State::new()).is_err());
+    }
+    #[test]
+    fn uncle_reward_overflow() {
+        // the nephew reward fits, the uncle reward does not before it is divided
+        let spec = GenesisSpec { block_reward: u32::MAX / 4, ..Default::default() };
+        let mut blockchain = Blockchain::with_consensus(Arc::new(crate::consensus::AcceptAll), &spec);
+        let genesis_hash = blockchain.tip();
+        let first = generate_random_block(&genesis_hash);
+        let stale = generate_random_block(&genesis_hash);
+        blockchain.insert(&first);
+        blockchain.insert(&stale);
+
+        let mut nephew = generate_random_block(&first.hash());
+        nephew.header.uncles = vec![stale.header.clone()];
+        assert_eq!(blockchain.validate_uncles(&nephew), Ok(vec![1]));
+        assert!(blockchain.execute_block(&nephew, &State::new()).is_err());
+    }
+    #[test]
+    fn locator_and_headers() {
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        let mut chain = vec![genesis_hash];
+        for _ in 0..30 {
+            let block = generate_random_block(chain.last().unwrap());
+            blockchain.insert(&block);
+            chain.push(block.hash());
+        }
+
+        let locator = blockchain.block_locator();
+        assert_eq!(locator[..10], chain.iter().rev().take(10).cloned().collect::<Vec<_>>()[..]);
+        assert_eq!(*locator.last().unwrap(), genesis_hash);
+        assert!(locator.len() < 20);
+
+        // a peer that has the first 21 blocks gets the other 10 headers
+        let headers = blockchain.headers_after(&[chain[20], generate_random_hash()], 100);
+        assert_eq!(headers.len(), 10);
+        assert_eq!(headers[0].parent, chain[20]);
+        // a peer that knows nothing starts from the genesis block
+        let headers = blockchain.headers_after(&[generate_random_hash()], 5);
+        assert_eq!(headers.len(), 5);
+        assert_eq!(headers[0].parent, genesis_hash);
+    }
 }
 
 // DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. AFTER TEST
\ No newline at end of file
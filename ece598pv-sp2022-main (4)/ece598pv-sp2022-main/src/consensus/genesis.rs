@@ -0,0 +1,86 @@
+use std::collections::HashMap;
+use std::convert::TryInto;
+
+use ring::digest;
+use serde::{Serialize, Deserialize};
+
+use crate::types::block::GENESIS_DIFFICULTY;
+use crate::types::hash::H256;
+
+/// Chain parameters that every node of a network has to agree on, loaded from a JSON file
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct GenesisSpec {
+    /// Hex-encoded ed25519 public keys of the proof-of-authority validators, in slot order
+    #[serde(default)]
+    pub validators: Vec<String>,
+    /// Length of a proof-of-authority slot, in milliseconds
+    #[serde(default = "default_slot_duration")]
+    pub slot_duration: u64,
+    /// Initial proof-of-stake stakes, from hex-encoded ed25519 public key to amount
+    #[serde(default)]
+    pub stakes: HashMap<String, u32>,
+    /// Probability that a slot has a proof-of-stake leader, if one staker held all the stake
+    #[serde(default = "default_active_slot_coeff")]
+    pub active_slot_coeff: f64,
+    /// Coins paid to the beneficiary of each block, uncles get part of it
+    #[serde(default)]
+    pub block_reward: u32,
+    /// Hex-encoded proof-of-work difficulty of the genesis block, which every block after it keeps.
+    /// Left out for the default one, and then also left out of the digest.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub difficulty: Option<String>,
+}
+
+fn default_slot_duration() -> u64 {
+    1000
+}
+
+fn default_active_slot_coeff() -> f64 {
+    0.5
+}
+
+impl Default for GenesisSpec {
+    fn default() -> Self {
+        Self {
+            validators: Vec::new(),
+            slot_duration: default_slot_duration(),
+            stakes: HashMap::new(),
+            active_slot_coeff: default_active_slot_coeff(),
+            block_reward: 0,
+            difficulty: None,
+        }
+    }
+}
+
+impl GenesisSpec {
+    /// Load the genesis spec from a JSON file
+    pub fn load(path: &str) -> Result<Self, String> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| format!("error reading genesis spec {}: {}", path, e))?;
+        let spec: Self = serde_json::from_str(&content)
+            .map_err(|e| format!("error parsing genesis spec {}: {}", path, e))?;
+        spec.genesis_difficulty()?;
+        Ok(spec)
+    }
+
+    /// Proof-of-work difficulty of the genesis block
+    pub fn genesis_difficulty(&self) -> Result<H256, String> {
+        let difficulty = match &self.difficulty {
+            Some(difficulty) => difficulty,
+            None => return Ok(GENESIS_DIFFICULTY.into()),
+        };
+        let bytes = hex::decode(difficulty).map_err(|e| format!("error parsing difficulty {}: {}", difficulty, e))?;
+        let bytes: [u8; 32] = bytes
+            .try_into()
+            .map_err(|_| format!("difficulty {} is not 32 bytes long", difficulty))?;
+        Ok(bytes.into())
+    }
+
+    /// Hash of the spec, which goes into the genesis block so that networks with different specs
+    /// have different genesis blocks
+    pub fn digest(&self) -> H256 {
+        // going through a JSON value sorts the keys of the stakes
+        let value = serde_json::to_value(self).unwrap();
+        digest::digest(&digest::SHA256, value.to_string().as_bytes()).into()
+    }
+}
@@ -0,0 +1,119 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use super::{BlockTree, Consensus};
+use crate::types::block::{Block, Header};
+use crate::types::hash::H256;
+use crate::types::transaction::State;
+
+/// Greedy heaviest observed subtree (GHOST) fork choice on top of another engine. Sealing is left
+/// to the inner engine; only the best chain changes. Starting from the root, it keeps walking into
+/// the child with the most blocks in its subtree, so blocks on side branches still add weight to
+/// the branch they fork from.
+pub struct Ghost {
+    inner: Arc<dyn Consensus>,
+}
+
+impl Ghost {
+    pub fn new(inner: Arc<dyn Consensus>) -> Self {
+        Self { inner }
+    }
+}
+
+/// Tip of the chain chosen by GHOST. Ties between subtrees of the same weight go to the lower hash,
+/// so that all nodes with the same blocks agree.
+pub fn ghost_tip(tree: &BlockTree) -> H256 {
+    let mut tip = tree.genesis;
+    while let Some(children) = tree.children.get(&tip) {
+        let heaviest = children.iter().copied().max_by(|a, b| tree.weights[a].cmp(&tree.weights[b]).then(b.cmp(a)));
+        match heaviest {
+            Some(child) => tip = child,
+            None => break,
+        }
+    }
+
+    tip
+}
+
+impl Consensus for Ghost {
+    fn name(&self) -> &'static str {
+        self.inner.name()
+    }
+
+    fn seal(&self, block: &mut Block, parent: &Block, parent_state: &State) -> bool {
+        self.inner.seal(block, parent, parent_state)
+    }
+
+    fn verify_seal(&self, block: &Block, parent: &Block, parent_state: &State) -> bool {
+        self.inner.verify_seal(block, parent, parent_state)
+    }
+
+    fn verify_header(&self, header: &Header, parent: &Header) -> bool {
+        self.inner.verify_header(header, parent)
+    }
+
+    fn verify_standalone(&self, header: &Header) -> bool {
+        self.inner.verify_standalone(header)
+    }
+
+    fn best_tip(&self, tree: &BlockTree) -> H256 {
+        ghost_tip(tree)
+    }
+
+    fn retry_delay(&self) -> Duration {
+        self.inner.retry_delay()
+    }
+
+    fn extend_genesis_state(&self, state: &mut State) {
+        self.inner.extend_genesis_state(state)
+    }
+
+    fn block_inserted(&self, block: &Block) {
+        self.inner.block_inserted(block)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::Blockchain;
+    use crate::consensus::AcceptAll;
+    use crate::types::block::generate_random_block;
+    use crate::types::hash::Hashable;
+
+    fn child(chains: &mut [&mut Blockchain], parent: &H256) -> H256 {
+        let block = generate_random_block(parent);
+        for blockchain in chains.iter_mut() {
+            blockchain.insert(&block);
+        }
+        block.hash()
+    }
+
+    #[test]
+    fn heaviest_subtree_beats_longest_chain() {
+        let mut longest = Blockchain::with_consensus(Arc::new(AcceptAll), &Default::default());
+        let mut ghost = Blockchain::with_consensus(Arc::new(Ghost::new(Arc::new(AcceptAll))), &Default::default());
+        let genesis = ghost.genesis_hash();
+
+        // a chain of three blocks
+        let mut long_tip = genesis;
+        for _ in 0..3 {
+            long_tip = child(&mut [&mut longest, &mut ghost], &long_tip);
+        }
+        // a bushy subtree of five blocks, but only two deep
+        let bushy = child(&mut [&mut longest, &mut ghost], &genesis);
+        let mut bushy_tips = vec![];
+        for _ in 0..4 {
+            bushy_tips.push(child(&mut [&mut longest, &mut ghost], &bushy));
+        }
+
+        assert_eq!(longest.tip(), long_tip);
+        assert!(bushy_tips.contains(&ghost.tip()));
+
+        // the weights follow the new blocks: three more on the chain make it the heavier subtree
+        for _ in 0..3 {
+            long_tip = child(&mut [&mut ghost], &long_tip);
+        }
+        assert_eq!(ghost.tip(), long_tip);
+    }
+}
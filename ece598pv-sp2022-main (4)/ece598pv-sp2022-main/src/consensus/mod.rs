@@ -0,0 +1,145 @@
+pub mod genesis;
+pub mod ghost;
+pub mod poa;
+pub mod pos;
+pub mod pow;
+pub mod slot;
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use ring::signature::{self, Ed25519KeyPair, KeyPair, UnparsedPublicKey};
+
+use genesis::GenesisSpec;
+use crate::types::block::{Block, Header};
+use crate::types::hash::H256;
+use crate::types::transaction::State;
+
+/// A consensus engine decides how a block gets sealed, how a seal is checked, and which chain is
+/// the best one. The miner, the network worker and the blockchain only go through this trait, so
+/// another engine can be plugged in without touching them.
+pub trait Consensus: Send + Sync {
+    /// Name of the engine, as given to `--consensus`
+    fn name(&self) -> &'static str;
+
+    /// Make one attempt at sealing `block` on top of `parent`. Returns true if the block is sealed
+    /// and can be broadcast, false if the miner should try again later.
+    fn seal(&self, block: &mut Block, parent: &Block, parent_state: &State) -> bool;
+
+    /// Check the seal of `block` against its parent and the state after the parent
+    fn verify_seal(&self, block: &Block, parent: &Block, parent_state: &State) -> bool;
+
+    /// Check what can be checked of the seal of `header` with only the parent header at hand,
+    /// before the body of the block is downloaded. The full check is `verify_seal`.
+    fn verify_header(&self, _header: &Header, _parent: &Header) -> bool {
+        true
+    }
+
+    /// Check what can be checked of the seal of `header` without its parent, for a block whose
+    /// parent has not arrived yet
+    fn verify_standalone(&self, _header: &Header) -> bool {
+        true
+    }
+
+    /// Return the hash of the tip of the best chain among all known blocks
+    fn best_tip(&self, tree: &BlockTree) -> H256;
+
+    /// How long the miner should wait after a failed sealing attempt before trying again
+    fn retry_delay(&self) -> Duration {
+        Duration::from_micros(0)
+    }
+
+    /// Add the accounts the engine needs to exist from the start, e.g. initial stakes
+    fn extend_genesis_state(&self, _state: &mut State) {}
+
+    /// Called once `block` passed every check and is in the blockchain
+    fn block_inserted(&self, _block: &Block) {}
+}
+
+/// The blocks of a blockchain and how they descend from the genesis block, for fork choice. The
+/// blockchain keeps the children and subtree weights up to date as blocks come in, so that
+/// choosing the best chain does not go over every block.
+pub struct BlockTree<'a> {
+    pub blocks: &'a HashMap<H256, Block>,
+    pub genesis: H256,
+    pub children: &'a HashMap<H256, Vec<H256>>,
+    /// How many blocks are in the subtree of each block, the block itself included
+    pub weights: &'a HashMap<H256, u64>,
+}
+
+/// Create a consensus engine by name. `key` is the key this node signs blocks with, for engines
+/// that need one.
+pub fn new(name: &str, spec: &GenesisSpec, key: Option<Ed25519KeyPair>) -> Result<Arc<dyn Consensus>, String> {
+    match name {
+        "pow" => Ok(Arc::new(pow::ProofOfWork::new(spec)?)),
+        "poa" => Ok(Arc::new(poa::ProofOfAuthority::new(spec, key)?)),
+        "pos" => Ok(Arc::new(pos::ProofOfStake::new(spec, key)?)),
+        _ => Err(format!("unknown consensus engine {}", name)),
+    }
+}
+
+/// Apply a fork choice rule on top of an engine, either its own longest chain rule or GHOST
+pub fn with_fork_choice(engine: Arc<dyn Consensus>, rule: &str) -> Result<Arc<dyn Consensus>, String> {
+    match rule {
+        "longest" => Ok(engine),
+        "ghost" => Ok(Arc::new(ghost::Ghost::new(engine))),
+        _ => Err(format!("unknown fork choice rule {}", rule)),
+    }
+}
+
+/// Longest chain fork choice: the tip is the block with the largest length
+pub fn longest_chain_tip(blocks: &HashMap<H256, Block>) -> H256 {
+    let mut max_length = 0;
+    let mut max_hash: H256 = [0; 32].into(); // temp value to start the search
+
+    for (hash, block) in blocks.iter() {
+        if block.length >= max_length {
+            max_length = block.length;
+            max_hash = *hash;
+        }
+    }
+
+    max_hash
+}
+
+/// Record `key` as the signer of `block` and sign its seal hash
+pub fn sign_block(block: &mut Block, key: &Ed25519KeyPair) {
+    block.header.signer = key.public_key().as_ref().to_vec();
+    block.header.signature = key.sign(block.header.seal_hash().as_ref()).as_ref().to_vec();
+}
+
+/// Check that the signature of `block` was made by its signer
+pub fn verify_block_signature(block: &Block) -> bool {
+    verify_header_signature(&block.header)
+}
+
+/// Check that the signature of a block header was made by its signer
+pub fn verify_header_signature(header: &Header) -> bool {
+    let public_key = UnparsedPublicKey::new(&signature::ED25519, &header.signer);
+    public_key.verify(header.seal_hash().as_ref(), &header.signature).is_ok()
+}
+
+/// An engine that seals every block and accepts every seal, with longest chain fork choice. Tests
+/// use it to feed random blocks to the network worker without mining them.
+#[cfg(test)]
+pub struct AcceptAll;
+
+#[cfg(test)]
+impl Consensus for AcceptAll {
+    fn name(&self) -> &'static str {
+        "accept-all"
+    }
+
+    fn seal(&self, _block: &mut Block, _parent: &Block, _parent_state: &State) -> bool {
+        true
+    }
+
+    fn verify_seal(&self, _block: &Block, _parent: &Block, _parent_state: &State) -> bool {
+        true
+    }
+
+    fn best_tip(&self, tree: &BlockTree) -> H256 {
+        longest_chain_tip(tree.blocks)
+    }
+}
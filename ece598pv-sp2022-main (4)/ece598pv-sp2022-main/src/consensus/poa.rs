@@ -0,0 +1,236 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use log::{debug, info, warn};
+use ring::signature::{Ed25519KeyPair, KeyPair};
+
+use super::{BlockTree, Consensus, sign_block, verify_header_signature};
+use super::genesis::GenesisSpec;
+use super::slot::SlotClock;
+use crate::types::block::{Block, Header};
+use crate::types::hash::{H256, Hashable};
+use crate::types::transaction::State;
+
+/// How many rounds of the validator set back double signing is caught
+const SIGNED_SLOTS_ROUNDS: u64 = 4;
+
+/// Proof of authority: a fixed set of validators take turns in round-robin slots, and the validator
+/// of a slot signs the block instead of hashing it under the difficulty. The best chain is the
+/// longest one.
+pub struct ProofOfAuthority {
+    validators: Vec<Vec<u8>>,
+    clock: SlotClock,
+    key: Option<Ed25519KeyPair>,
+    // the last slot this node sealed a block in, so that the miner never signs twice in a slot
+    last_sealed_slot: Mutex<u64>,
+    // (signer, slot) -> hash of the block in the blockchain for it, to catch double signing. Only
+    // the last few rounds are kept.
+    signed_slots: Mutex<HashMap<(Vec<u8>, u64), H256>>,
+}
+
+impl ProofOfAuthority {
+    pub fn new(spec: &GenesisSpec, key: Option<Ed25519KeyPair>) -> Result<Self, String> {
+        if spec.validators.is_empty() {
+            return Err("proof of authority needs at least one validator in the genesis spec".to_string());
+        }
+        let mut validators = Vec::new();
+        for validator in spec.validators.iter() {
+            let public_key = hex::decode(validator)
+                .map_err(|e| format!("error parsing validator key {}: {}", validator, e))?;
+            validators.push(public_key);
+        }
+
+        if let Some(key) = &key {
+            let public_key = key.public_key().as_ref();
+            if validators.iter().any(|v| v.as_slice() == public_key) {
+                info!("Signing blocks as validator {}", hex::encode(public_key));
+            } else {
+                warn!("Key {} is not in the validator set, will not produce blocks", hex::encode(public_key));
+            }
+        }
+
+        Ok(Self {
+            validators,
+            clock: SlotClock::new(spec.slot_duration)?,
+            key,
+            last_sealed_slot: Mutex::new(0),
+            signed_slots: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// The validator whose turn it is in the given slot
+    fn slot_signer(&self, slot: u64) -> &[u8] {
+        &self.validators[(slot % self.validators.len() as u64) as usize]
+    }
+}
+
+impl Consensus for ProofOfAuthority {
+    fn name(&self) -> &'static str {
+        "poa"
+    }
+
+    fn seal(&self, block: &mut Block, parent: &Block, _parent_state: &State) -> bool {
+        let key = match &self.key {
+            Some(key) => key,
+            None => return false,
+        };
+        let public_key = key.public_key().as_ref();
+
+        let slot = self.clock.current_slot();
+        let mut last_sealed_slot = self.last_sealed_slot.lock().unwrap();
+        if slot <= *last_sealed_slot || slot <= self.clock.slot_of(parent) {
+            return false;
+        }
+        if self.slot_signer(slot) != public_key {
+            return false;
+        }
+
+        block.header.timestamp = self.clock.slot_start(slot);
+        sign_block(block, key);
+        *last_sealed_slot = slot;
+        debug!("Signed block {:?} in slot {}", block.hash(), slot);
+
+        true
+    }
+
+    fn verify_seal(&self, block: &Block, parent: &Block, _parent_state: &State) -> bool {
+        if !self.verify_header(&block.header, &parent.header) {
+            return false;
+        }
+
+        let slot = self.clock.slot_of(block);
+        let signed_slots = self.signed_slots.lock().unwrap();
+        match signed_slots.get(&(block.header.signer.clone(), slot)) {
+            Some(seen) if *seen != block.hash() => {
+                warn!("Block {:?} is double signed in slot {}, already seen {:?}", block.hash(), slot, seen);
+                false
+            }
+            _ => true,
+        }
+    }
+
+    fn verify_header(&self, header: &Header, parent: &Header) -> bool {
+        if self.clock.header_slot(header) <= self.clock.header_slot(parent) {
+            warn!("Block {:?} is not in a later slot than its parent", header.hash());
+            return false;
+        }
+        self.verify_standalone(header)
+    }
+
+    fn verify_standalone(&self, header: &Header) -> bool {
+        let slot = self.clock.header_slot(header);
+        if slot > self.clock.current_slot() + 1 {
+            warn!("Block {:?} is from future slot {}", header.hash(), slot);
+            return false;
+        }
+        if header.signer != self.slot_signer(slot) {
+            warn!("Block {:?} is signed out of turn in slot {}", header.hash(), slot);
+            return false;
+        }
+
+        if !verify_header_signature(header) {
+            warn!("Block {:?} has an invalid signature", header.hash());
+            return false;
+        }
+
+        true
+    }
+
+    fn best_tip(&self, tree: &BlockTree) -> H256 {
+        super::longest_chain_tip(tree.blocks)
+    }
+
+    fn retry_delay(&self) -> Duration {
+        self.clock.until_next_slot()
+    }
+
+    fn block_inserted(&self, block: &Block) {
+        let slot = self.clock.slot_of(block);
+        let window = SIGNED_SLOTS_ROUNDS * self.validators.len() as u64;
+        let mut signed_slots = self.signed_slots.lock().unwrap();
+        signed_slots.entry((block.header.signer.clone(), slot)).or_insert_with(|| block.hash());
+        signed_slots.retain(|(_, signed), _| signed + window >= slot);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::block::generate_random_block;
+    use crate::types::hash::generate_random_hash;
+    use crate::types::key_pair;
+
+    fn validators(keys: &[&Ed25519KeyPair]) -> GenesisSpec {
+        GenesisSpec {
+            validators: keys.iter().map(|k| hex::encode(k.public_key().as_ref())).collect(),
+            slot_duration: 1000,
+            ..Default::default()
+        }
+    }
+
+    fn signed_block(parent: &Block, key: &Ed25519KeyPair, slot: u64) -> Block {
+        let mut block = generate_random_block(&parent.hash());
+        block.header.timestamp = slot as u128 * 1000;
+        sign_block(&mut block, key);
+        block
+    }
+
+    #[test]
+    fn in_turn_and_out_of_turn() {
+        let (key_a, key_b) = (key_pair::random(), key_pair::random());
+        let spec = validators(&[&key_a, &key_b]);
+        let poa = ProofOfAuthority::new(&spec, None).unwrap();
+        let mut parent = generate_random_block(&generate_random_hash());
+        parent.header.timestamp = 0;
+
+        // slot 2 belongs to the first validator, slot 3 to the second
+        assert!(poa.verify_seal(&signed_block(&parent, &key_a, 2), &parent, &State::new()));
+        assert!(!poa.verify_seal(&signed_block(&parent, &key_b, 2), &parent, &State::new()));
+        assert!(poa.verify_seal(&signed_block(&parent, &key_b, 3), &parent, &State::new()));
+    }
+
+    #[test]
+    fn reject_double_signing() {
+        let key = key_pair::random();
+        let poa = ProofOfAuthority::new(&validators(&[&key]), None).unwrap();
+        let mut parent = generate_random_block(&generate_random_hash());
+        parent.header.timestamp = 0;
+
+        let first = signed_block(&parent, &key, 5);
+        let second = signed_block(&parent, &key, 5);
+        // a block that is checked but never makes it into the blockchain claims no slot
+        assert!(poa.verify_seal(&second, &parent, &State::new()));
+        assert!(poa.verify_seal(&first, &parent, &State::new()));
+        poa.block_inserted(&first);
+        assert!(poa.verify_seal(&first, &parent, &State::new()));
+        assert!(!poa.verify_seal(&second, &parent, &State::new()));
+
+        // slots of the old rounds are forgotten as the chain moves on
+        poa.block_inserted(&signed_block(&first, &key, 5 + SIGNED_SLOTS_ROUNDS));
+        assert_eq!(poa.signed_slots.lock().unwrap().len(), 2);
+        poa.block_inserted(&signed_block(&first, &key, 6 + SIGNED_SLOTS_ROUNDS));
+        assert_eq!(poa.signed_slots.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn seal_in_own_slot_only() {
+        let key = key_pair::random();
+        let spec = validators(&[&key]);
+        let outsider_key = key_pair::random();
+        let poa = ProofOfAuthority::new(&spec, Some(key)).unwrap();
+        let mut parent = generate_random_block(&generate_random_hash());
+        parent.header.timestamp = 0;
+
+        let mut block = generate_random_block(&parent.hash());
+        assert!(poa.seal(&mut block, &parent, &State::new()));
+        assert!(poa.verify_seal(&block, &parent, &State::new()));
+        // a second block in the same slot would be double signing
+        let mut again = generate_random_block(&parent.hash());
+        assert!(!poa.seal(&mut again, &parent, &State::new()));
+
+        let outsider = ProofOfAuthority::new(&spec, Some(outsider_key)).unwrap();
+        let mut block = generate_random_block(&parent.hash());
+        assert!(!outsider.seal(&mut block, &parent, &State::new()));
+    }
+}
@@ -0,0 +1,299 @@
+use std::sync::Mutex;
+use std::time::Duration;
+
+use log::{debug, info, warn};
+use ring::digest;
+use ring::signature::{self, Ed25519KeyPair, KeyPair, UnparsedPublicKey};
+
+use super::{BlockTree, Consensus, sign_block, verify_header_signature};
+use super::genesis::GenesisSpec;
+use super::slot::SlotClock;
+use crate::types::address::Address;
+use crate::types::block::{Block, Header};
+use crate::types::hash::{H256, Hashable};
+use crate::types::transaction::State;
+
+/// Longest chain proof of stake. In every slot, each staker runs a private lottery: it signs the
+/// slot number and the randomness of the parent, and wins if the hash of that signature is below a
+/// threshold that grows with its share of the stake in the parent's state. The signature is the
+/// proof of eligibility, which anyone can check with the staker's public key.
+pub struct ProofOfStake {
+    clock: SlotClock,
+    active_slot_coeff: f64,
+    initial_stakes: Vec<(Address, u32)>,
+    key: Option<Ed25519KeyPair>,
+    // the last slot this node sealed a block in, so that the miner proposes at most once per slot
+    last_sealed_slot: Mutex<u64>,
+}
+
+impl ProofOfStake {
+    pub fn new(spec: &GenesisSpec, key: Option<Ed25519KeyPair>) -> Result<Self, String> {
+        if spec.active_slot_coeff <= 0.0 || spec.active_slot_coeff >= 1.0 {
+            return Err("active slot coefficient must be between 0 and 1".to_string());
+        }
+
+        let mut initial_stakes = Vec::new();
+        for (staker, amount) in spec.stakes.iter() {
+            let public_key = hex::decode(staker)
+                .map_err(|e| format!("error parsing staker key {}: {}", staker, e))?;
+            initial_stakes.push((Address::from_public_key_bytes(&public_key), *amount));
+        }
+
+        if let Some(key) = &key {
+            let owner = Address::from_public_key_bytes(key.public_key().as_ref());
+            info!("Proposing blocks with the stake of {}", owner);
+        }
+
+        Ok(Self {
+            clock: SlotClock::new(spec.slot_duration)?,
+            active_slot_coeff: spec.active_slot_coeff,
+            initial_stakes,
+            key,
+            last_sealed_slot: Mutex::new(0),
+        })
+    }
+
+    /// Whether the lottery `proof` wins a slot, given the stake of its owner
+    fn is_leader(&self, proof: &[u8], stake: u32, total_stake: u64) -> bool {
+        if stake == 0 || total_stake == 0 {
+            return false;
+        }
+
+        // the top 64 bits of the hash, as a uniform number in [0, 1)
+        let hash: [u8; 32] = H256::from(digest::digest(&digest::SHA256, proof)).into();
+        let mut top_bytes = [0; 8];
+        top_bytes.copy_from_slice(&hash[..8]);
+        let value = u64::from_be_bytes(top_bytes) as f64 / 2f64.powi(64);
+
+        // 1 - (1 - f)^share, so splitting stake over several keys does not change the odds
+        let share = stake as f64 / total_stake as f64;
+        let threshold = 1.0 - (1.0 - self.active_slot_coeff).powf(share);
+        value < threshold
+    }
+}
+
+/// Randomness of the lottery for blocks on top of `parent`. It chains the proofs of the blocks, so
+/// that a proposer cannot grind it by changing the block content.
+fn lottery_randomness(parent: &Header) -> H256 {
+    if parent.proof.is_empty() { // genesis block
+        parent.hash()
+    } else {
+        digest::digest(&digest::SHA256, &parent.proof).into()
+    }
+}
+
+/// The message a staker signs to take part in the lottery of a slot
+fn lottery_message(slot: u64, parent: &Header) -> Vec<u8> {
+    let mut message = slot.to_be_bytes().to_vec();
+    message.extend_from_slice(lottery_randomness(parent).as_ref());
+    message
+}
+
+/// Stake of the owner of `public_key`
+fn stake_of(state: &State, public_key: &[u8]) -> u32 {
+    let owner = Address::from_public_key_bytes(public_key);
+    match state.get(&Address::stake_account(&owner)) {
+        Some((_, stake)) => *stake,
+        None => 0,
+    }
+}
+
+fn total_stake(state: &State) -> u64 {
+    state
+        .iter()
+        .filter(|(addr, _)| addr.is_stake_account())
+        .map(|(_, (_, stake))| *stake as u64)
+        .sum()
+}
+
+impl Consensus for ProofOfStake {
+    fn name(&self) -> &'static str {
+        "pos"
+    }
+
+    fn seal(&self, block: &mut Block, parent: &Block, parent_state: &State) -> bool {
+        let key = match &self.key {
+            Some(key) => key,
+            None => return false,
+        };
+
+        let slot = self.clock.current_slot();
+        let mut last_sealed_slot = self.last_sealed_slot.lock().unwrap();
+        if slot <= *last_sealed_slot || slot <= self.clock.slot_of(parent) {
+            return false;
+        }
+
+        let proof = key.sign(&lottery_message(slot, &parent.header)).as_ref().to_vec();
+        let stake = stake_of(parent_state, key.public_key().as_ref());
+        if !self.is_leader(&proof, stake, total_stake(parent_state)) {
+            return false;
+        }
+
+        block.header.timestamp = self.clock.slot_start(slot);
+        block.header.proof = proof;
+        sign_block(block, key);
+        *last_sealed_slot = slot;
+        debug!("Won slot {} with stake {}, proposed block {:?}", slot, stake, block.hash());
+
+        true
+    }
+
+    fn verify_seal(&self, block: &Block, parent: &Block, parent_state: &State) -> bool {
+        if !self.verify_header(&block.header, &parent.header) {
+            return false;
+        }
+        let stake = stake_of(parent_state, &block.header.signer);
+        if !self.is_leader(&block.header.proof, stake, total_stake(parent_state)) {
+            warn!("Block {:?} is proposed by a staker that did not win slot {}", block.hash(), self.clock.slot_of(block));
+            return false;
+        }
+
+        true
+    }
+
+    fn verify_header(&self, header: &Header, parent: &Header) -> bool {
+        let slot = self.clock.header_slot(header);
+        if slot <= self.clock.header_slot(parent) {
+            warn!("Block {:?} is not in a later slot than its parent", header.hash());
+            return false;
+        }
+        // the stake is in the state of the parent, which headers do not carry, so only the proof
+        // itself is checked here and the lottery once the block arrives
+        let public_key = UnparsedPublicKey::new(&signature::ED25519, &header.signer);
+        if public_key.verify(&lottery_message(slot, parent), &header.proof).is_err() {
+            warn!("Block {:?} has an invalid lottery proof", header.hash());
+            return false;
+        }
+        self.verify_standalone(header)
+    }
+
+    fn verify_standalone(&self, header: &Header) -> bool {
+        let slot = self.clock.header_slot(header);
+        if slot > self.clock.current_slot() + 1 {
+            warn!("Block {:?} is from future slot {}", header.hash(), slot);
+            return false;
+        }
+        if !verify_header_signature(header) {
+            warn!("Block {:?} has an invalid signature", header.hash());
+            return false;
+        }
+
+        true
+    }
+
+    fn best_tip(&self, tree: &BlockTree) -> H256 {
+        super::longest_chain_tip(tree.blocks)
+    }
+
+    fn retry_delay(&self) -> Duration {
+        self.clock.until_next_slot()
+    }
+
+    fn extend_genesis_state(&self, state: &mut State) {
+        for (owner, amount) in self.initial_stakes.iter() {
+            state.insert(Address::stake_account(owner), (0, *amount));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::block::generate_random_block;
+    use crate::types::hash::generate_random_hash;
+    use crate::types::key_pair;
+
+    fn engine(stakes: &[(&Ed25519KeyPair, u32)], key: Option<Ed25519KeyPair>) -> ProofOfStake {
+        let spec = GenesisSpec {
+            stakes: stakes.iter().map(|(k, s)| (hex::encode(k.public_key().as_ref()), *s)).collect(),
+            active_slot_coeff: 0.9,
+            ..Default::default()
+        };
+        ProofOfStake::new(&spec, key).unwrap()
+    }
+
+    fn genesis_state(pos: &ProofOfStake) -> State {
+        let mut state = State::new();
+        pos.extend_genesis_state(&mut state);
+        state
+    }
+
+    /// Build a block proposed by `key` in the first slot after `parent` that `key` wins
+    fn winning_block(pos: &ProofOfStake, parent: &Block, state: &State, key: &Ed25519KeyPair) -> Block {
+        let mut slot = pos.clock.slot_of(parent);
+        loop {
+            slot += 1;
+            let proof = key.sign(&lottery_message(slot, &parent.header)).as_ref().to_vec();
+            if pos.is_leader(&proof, stake_of(state, key.public_key().as_ref()), total_stake(state)) {
+                let mut block = generate_random_block(&parent.hash());
+                block.header.timestamp = pos.clock.slot_start(slot);
+                block.header.proof = proof;
+                sign_block(&mut block, key);
+                return block;
+            }
+        }
+    }
+
+    #[test]
+    fn verify_stake_weighted_lottery() {
+        let (staker, poor) = (key_pair::random(), key_pair::random());
+        let pos = engine(&[(&staker, 100)], None);
+        let state = genesis_state(&pos);
+        let mut parent = generate_random_block(&generate_random_hash());
+        parent.header.timestamp = 0;
+
+        let block = winning_block(&pos, &parent, &state, &staker);
+        assert!(pos.verify_seal(&block, &parent, &state));
+
+        // a key without stake never wins, whatever it signs
+        let mut stolen = block.clone();
+        stolen.header.proof = poor.sign(&lottery_message(pos.clock.slot_of(&block), &parent.header)).as_ref().to_vec();
+        sign_block(&mut stolen, &poor);
+        assert!(!pos.verify_seal(&stolen, &parent, &state));
+
+        // the proof has to be for the slot of the block
+        let mut moved = block.clone();
+        moved.header.timestamp += 1000;
+        sign_block(&mut moved, &staker);
+        assert!(!pos.verify_seal(&moved, &parent, &state));
+    }
+
+    #[test]
+    fn verify_headers_without_state() {
+        let staker = key_pair::random();
+        let pos = engine(&[(&staker, 100)], None);
+        let state = genesis_state(&pos);
+        let mut parent = generate_random_block(&generate_random_hash());
+        parent.header.timestamp = 0;
+        let block = winning_block(&pos, &parent, &state, &staker);
+        assert!(pos.verify_header(&block.header, &parent.header));
+
+        // not after the parent
+        let mut early = parent.clone();
+        early.header.timestamp = block.header.timestamp;
+        assert!(!pos.verify_header(&block.header, &early.header));
+
+        // too far in the future
+        let mut future = block.clone();
+        let slot = pos.clock.current_slot() + 2;
+        future.header.timestamp = pos.clock.slot_start(slot);
+        future.header.proof = staker.sign(&lottery_message(slot, &parent.header)).as_ref().to_vec();
+        sign_block(&mut future, &staker);
+        assert!(!pos.verify_header(&future.header, &parent.header));
+
+        // signed by someone else than the proposer
+        let mut forged = block.clone();
+        forged.header.signature = key_pair::random().sign(forged.header.seal_hash().as_ref()).as_ref().to_vec();
+        assert!(!pos.verify_header(&forged.header, &parent.header));
+    }
+
+    #[test]
+    fn no_stake_no_block() {
+        let staker = key_pair::random();
+        let pos = engine(&[], Some(staker));
+        let state = genesis_state(&pos);
+        let parent = generate_random_block(&generate_random_hash());
+        let mut block = generate_random_block(&parent.hash());
+        assert!(!pos.seal(&mut block, &parent, &state));
+    }
+}
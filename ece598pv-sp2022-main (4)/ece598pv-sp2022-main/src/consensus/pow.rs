@@ -0,0 +1,122 @@
+use rand::Rng;
+
+use super::{BlockTree, Consensus};
+use super::genesis::GenesisSpec;
+use crate::types::block::{Block, Header, GENESIS_DIFFICULTY};
+use crate::types::hash::{H256, Hashable};
+use crate::types::transaction::State;
+
+/// Proof of work with the fixed difficulty of the genesis spec, and the longest chain as the best
+/// chain
+pub struct ProofOfWork {
+    difficulty: H256,
+}
+
+impl ProofOfWork {
+    pub fn new(spec: &GenesisSpec) -> Result<Self, String> {
+        Ok(Self {
+            difficulty: spec.genesis_difficulty()?,
+        })
+    }
+}
+
+impl Default for ProofOfWork {
+    fn default() -> Self {
+        Self {
+            difficulty: GENESIS_DIFFICULTY.into(),
+        }
+    }
+}
+
+impl Consensus for ProofOfWork {
+    fn name(&self) -> &'static str {
+        "pow"
+    }
+
+    fn seal(&self, block: &mut Block, _parent: &Block, _parent_state: &State) -> bool {
+        let mut rng = rand::thread_rng();
+        block.header.nonce = rng.gen();
+
+        block.hash() <= block.get_difficulty()
+    }
+
+    fn verify_seal(&self, block: &Block, parent: &Block, _parent_state: &State) -> bool {
+        self.verify_header(&block.header, &parent.header)
+    }
+
+    fn verify_header(&self, header: &Header, parent: &Header) -> bool {
+        self.verify_standalone(header) && header.difficulty == parent.difficulty
+    }
+
+    fn verify_standalone(&self, header: &Header) -> bool {
+        // the difficulty a block claims means nothing unless it is the one of the chain
+        header.difficulty == self.difficulty && header.hash() <= header.difficulty
+    }
+
+    fn best_tip(&self, tree: &BlockTree) -> H256 {
+        super::longest_chain_tip(tree.blocks)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use crate::types::block::generate_random_block;
+    use crate::types::hash::generate_random_hash;
+
+    fn easy_spec() -> GenesisSpec {
+        GenesisSpec {
+            difficulty: Some(hex::encode([255; 32])),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn reject_changed_difficulty() {
+        let pow = ProofOfWork::new(&easy_spec()).unwrap();
+        let mut parent = generate_random_block(&generate_random_hash());
+        parent.header.difficulty = [255; 32].into();
+        let mut block = generate_random_block(&parent.hash());
+        block.header.difficulty = [255; 32].into();
+        assert!(pow.verify_seal(&block, &parent, &State::new()));
+
+        block.header.difficulty = [0; 32].into();
+        assert!(!pow.verify_seal(&block, &parent, &State::new()));
+        // without the parent, the difficulty is checked against the one of the chain
+        assert!(!pow.verify_standalone(&block.header));
+        block.header.difficulty = [255; 32].into();
+        assert!(pow.verify_standalone(&block.header));
+    }
+
+    #[test]
+    fn reject_easy_difficulty() {
+        let pow = ProofOfWork::default();
+        let mut parent = generate_random_block(&generate_random_hash());
+        parent.header.difficulty = [255; 32].into();
+        let mut block = generate_random_block(&parent.hash());
+        // any hash is under the difficulty the block declares for itself
+        block.header.difficulty = [255; 32].into();
+        assert!(!pow.verify_standalone(&block.header));
+        assert!(!pow.verify_seal(&block, &parent, &State::new()));
+    }
+
+    #[test]
+    fn longest_chain_is_best() {
+        let pow = ProofOfWork::default();
+        let mut blocks = HashMap::new();
+        let mut short = generate_random_block(&generate_random_hash());
+        short.length = 2;
+        let mut long = generate_random_block(&generate_random_hash());
+        long.length = 3;
+        blocks.insert(short.hash(), short);
+        blocks.insert(long.hash(), long.clone());
+        let tree = BlockTree {
+            blocks: &blocks,
+            genesis: generate_random_hash(),
+            children: &HashMap::new(),
+            weights: &HashMap::new(),
+        };
+        assert_eq!(pow.best_tip(&tree), long.hash());
+    }
+}
@@ -0,0 +1,44 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::types::block::{Block, Header};
+
+/// Wall clock time cut into fixed-length slots, for engines that produce at most one block per
+/// slot. A block belongs to the slot its timestamp falls in.
+pub struct SlotClock {
+    duration: u64, // in milliseconds
+}
+
+impl SlotClock {
+    pub fn new(duration: u64) -> Result<Self, String> {
+        if duration == 0 {
+            return Err("slot duration must be positive".to_string());
+        }
+        Ok(Self { duration })
+    }
+
+    fn now(&self) -> u128 {
+        SystemTime::now().duration_since(UNIX_EPOCH).expect("Clock may have gone backwards").as_millis()
+    }
+
+    pub fn current_slot(&self) -> u64 {
+        (self.now() / self.duration as u128) as u64
+    }
+
+    pub fn slot_of(&self, block: &Block) -> u64 {
+        self.header_slot(&block.header)
+    }
+
+    pub fn header_slot(&self, header: &Header) -> u64 {
+        (header.timestamp / self.duration as u128) as u64
+    }
+
+    /// Timestamp of the beginning of a slot
+    pub fn slot_start(&self, slot: u64) -> u128 {
+        slot as u128 * self.duration as u128
+    }
+
+    pub fn until_next_slot(&self) -> Duration {
+        let duration = self.duration as u128;
+        Duration::from_millis((duration - self.now() % duration) as u64)
+    }
+}
@@ -4,20 +4,24 @@ extern crate hex_literal;
 
 pub mod api;
 pub mod blockchain;
+pub mod consensus;
 pub mod types;
 pub mod miner;
 pub mod network;
+#[cfg(test)]
+pub mod simulator;
 
 use blockchain::Blockchain;
+use consensus::genesis::GenesisSpec;
+use types::address::Address;
+use ring::signature::KeyPair;
 use clap::clap_app;
 use smol::channel;
-use log::{error, info};
+use log::{error, info, warn};
 use api::Server as ApiServer;
 use std::net;
 use std::process;
 use std::sync::{Arc, Mutex};
-use std::thread;
-use std::time;
 use std::collections::HashMap;
 
 fn main() {
@@ -30,14 +34,99 @@ fn main() {
      (@arg api_addr: --api [ADDR] default_value("127.0.0.1:7000") "Sets the IP address and the port of the API server")
      (@arg known_peer: -c --connect ... [PEER] "Sets the peers to connect to at start")
      (@arg p2p_workers: --("p2p-workers") [INT] default_value("4") "Sets the number of worker threads for P2P server")
+     (@arg consensus: --consensus [ENGINE] default_value("pow") "Sets the consensus engine (pow, poa or pos)")
+     (@arg fork_choice: --("fork-choice") [RULE] default_value("longest") "Sets the fork choice rule (longest or ghost)")
+     (@arg genesis: --genesis [FILE] "Sets the genesis spec file with the validators or initial stakes")
+     (@arg key: --key [FILE] "Sets the file holding the hex-encoded ed25519 seed this node signs blocks with")
+     (@arg data_dir: --("data-dir") [DIR] "Sets the directory to keep the known peer addresses in")
+     (@arg outbound: --outbound [INT] default_value("8") "Sets the number of outbound connections to keep open")
+     (@arg max_inbound: --("max-inbound") [INT] default_value("32") "Sets the most peers that can connect to this node, the least useful are evicted for new ones")
+     (@arg max_outbound: --("max-outbound") [INT] default_value("16") "Sets the most peers this node connects to, persistent peers included")
+     (@arg unix_socket_dir: --("unix-socket-dir") [DIR] "Connects to peers over Unix sockets in this directory instead of TCP, named after the P2P addresses of the nodes")
+     (@arg strategy: --strategy [STRATEGY] default_value("honest") "Sets the mining strategy (honest, selfish, double-spend:<depth>:<merchant>:<value> or feather-fork:<address>:<confirmations>)")
+     (@arg ban_duration: --("ban-duration") [SECS] default_value("86400") "Sets how long misbehaving peers stay banned")
+     (@arg encrypt: --encrypt "Encrypts P2P connections and authenticates peers, with the node key as identity (a random one without --key)")
+     (@arg allowlist: --allowlist [FILE] "Makes the network private: only peers with an identity or secret listed in the file can connect (implies --encrypt)")
+     (@arg relay_fanout: --("relay-fanout") [INT] "Sets how many random peers get new blocks and transactions in full, the others only get their hashes (all peers by default)")
+     (@arg trace: --trace [FILE] "Records every frame received from peers to this file, to replay later")
+     (@arg replay: --replay [FILE] "Replays the frames of a trace into a fresh blockchain, prints the tip it ends at and exits")
+     (@arg replay_count: --("replay-count") [INT] "Replays only the first frames of the trace, to bisect it")
     )
     .get_matches();
 
     // init logger
     let verbosity = matches.occurrences_of("verbose") as usize;
     stderrlog::new().verbosity(verbosity).init().unwrap();
-    let blockchain = Blockchain::new();
+    let genesis_spec = match matches.value_of("genesis") {
+        Some(path) => GenesisSpec::load(path).unwrap_or_else(|e| {
+            error!("Error loading genesis spec: {}", e);
+            process::exit(1);
+        }),
+        None => GenesisSpec::default(),
+    };
+    let load_key = |path: &str| {
+        std::fs::read_to_string(path)
+            .map_err(|e| e.to_string())
+            .and_then(|s| hex::decode(s.trim()).map_err(|e| e.to_string()))
+            .and_then(|seed| types::key_pair::from_seed(&seed))
+            .unwrap_or_else(|e| {
+                error!("Error loading key from {}: {}", path, e);
+                process::exit(1);
+            })
+    };
+    let key = matches.value_of("key").map(load_key);
+    // peers allowed on a private network
+    let allowlist = matches.value_of("allowlist").map(|path| {
+        let allowlist = network::allowlist::Allowlist::load(std::path::Path::new(path)).unwrap_or_else(|e| {
+            error!("Error loading allowlist from {}: {}", path, e);
+            process::exit(1);
+        });
+        Arc::new(Mutex::new(allowlist))
+    });
+    // identity of the node on the encrypted transport, which a private network needs
+    let identity = if matches.is_present("encrypt") || allowlist.is_some() {
+        let identity = matches.value_of("key").map(load_key).unwrap_or_else(types::key_pair::random);
+        Some(Arc::new(identity))
+    } else {
+        None
+    };
+    // block rewards go to the address of the node key, if there is one
+    let beneficiary = match &key {
+        Some(key) => Address::from_public_key_bytes(key.public_key().as_ref()),
+        None => Address::default(),
+    };
+    let consensus = consensus::new(matches.value_of("consensus").unwrap(), &genesis_spec, key)
+        .and_then(|engine| consensus::with_fork_choice(engine, matches.value_of("fork_choice").unwrap()))
+        .unwrap_or_else(|e| {
+            error!("Error creating consensus engine: {}", e);
+            process::exit(1);
+        });
+    info!("Using {} consensus", consensus.name());
+    let strategy = miner::strategy::Strategy::parse(matches.value_of("strategy").unwrap()).unwrap_or_else(|e| {
+        error!("Error parsing mining strategy: {}", e);
+        process::exit(1);
+    });
+    let blockchain = Blockchain::with_consensus(consensus, &genesis_spec);
     let blockchain = Arc::new(Mutex::new(blockchain));
+
+    // replay a trace offline instead of running the node
+    if let Some(path) = matches.value_of("replay") {
+        let mut records = network::trace::read_trace(std::path::Path::new(path)).unwrap_or_else(|e| {
+            error!("Error reading trace from {}: {}", path, e);
+            process::exit(1);
+        });
+        if let Some(count) = matches.value_of("replay_count") {
+            let count = count.parse::<usize>().unwrap_or_else(|e| {
+                error!("Error parsing replay count: {}", e);
+                process::exit(1);
+            });
+            records.truncate(count);
+        }
+        let summary = network::trace::replay(&records, &blockchain);
+        println!("Replayed {} frames from {} peers, tip {} at height {}", summary.frames, summary.peers, summary.tip, summary.height);
+        return;
+    }
+
     let mempool = Arc::new(Mutex::new(HashMap::new()));
     // parse p2p server address
     let p2p_addr = matches
@@ -62,8 +151,65 @@ fn main() {
     // create channels between server and worker
     let (msg_tx, msg_rx) = channel::bounded(10000);
 
+    // peers that break the protocol get banned for a while
+    let ban_duration = matches
+        .value_of("ban_duration")
+        .unwrap()
+        .parse::<u64>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing ban duration: {}", e);
+            process::exit(1);
+        });
+    let banman = network::banman::BanManager::new(std::time::Duration::from_secs(ban_duration));
+    let banman = Arc::new(Mutex::new(banman));
+
+    // relay new blocks and transactions to all peers, or to a random few and announce them to the rest
+    let relay_policy = match matches.value_of("relay_fanout") {
+        Some(k) => network::relay::RelayPolicy::Fanout(k.parse::<usize>().unwrap_or_else(|e| {
+            error!("Error parsing relay fanout: {}", e);
+            process::exit(1);
+        })),
+        None => network::relay::RelayPolicy::Flood,
+    };
+
+    // separate caps on the connections peers open to us and the ones we open
+    let parse_limit = |name: &str| {
+        matches.value_of(name).unwrap().parse::<usize>().unwrap_or_else(|e| {
+            error!("Error parsing connection limit: {}", e);
+            process::exit(1);
+        })
+    };
+    let limits = network::limits::ConnectionLimits {
+        max_inbound: parse_limit("max_inbound"),
+        max_outbound: parse_limit("max_outbound"),
+    };
+
     // start the p2p server
-    let (server_ctx, server) = network::server::new(p2p_addr, msg_tx).unwrap();
+    let (server_ctx, server) = network::server::new(
+        p2p_addr,
+        msg_tx,
+        &blockchain,
+        &banman,
+        relay_policy,
+        identity,
+        allowlist.clone(),
+        limits,
+    )
+    .unwrap();
+    let server_ctx = match matches.value_of("unix_socket_dir") {
+        Some(dir) => server_ctx.with_transport(Arc::new(network::transport::UnixTransport::new(dir.into()))),
+        None => server_ctx,
+    };
+    let server_ctx = match matches.value_of("trace") {
+        Some(path) => {
+            let trace = network::trace::TraceWriter::create(std::path::Path::new(path)).unwrap_or_else(|e| {
+                error!("Error creating trace file {}: {}", path, e);
+                process::exit(1);
+            });
+            server_ctx.with_trace(Arc::new(trace))
+        }
+        None => server_ctx,
+    };
     server_ctx.start().unwrap();
 
     // start the worker
@@ -75,54 +221,61 @@ fn main() {
             error!("Error parsing P2P workers: {}", e);
             process::exit(1);
         });
+    let addrman = match matches.value_of("data_dir") {
+        Some(dir) => network::addrman::AddrManager::load(std::path::Path::new(dir)).unwrap_or_else(|e| {
+            error!("Error loading peer addresses from {}: {}", dir, e);
+            process::exit(1);
+        }),
+        None => network::addrman::AddrManager::new(),
+    };
+    let addrman = Arc::new(Mutex::new(addrman));
+    let sync = Arc::new(Mutex::new(network::sync::SyncManager::new()));
     let worker_ctx = network::worker::Worker::new(
         p2p_workers,
         msg_rx,
         &server,
         &blockchain,
         &mempool,
+        &addrman,
+        &sync,
+        &banman,
     );
     worker_ctx.start();
+    network::sync::new(&server, &blockchain, &sync).start();
 
     // start the miner
-    let (miner_ctx, miner, finished_block_chan) = miner::new(&blockchain,&mempool);
-    let miner_worker_ctx = miner::worker::Worker::new(&server, finished_block_chan, &blockchain);
+    let (miner_ctx, miner, finished_block_chan) = miner::new(&blockchain, &mempool, beneficiary, strategy);
+    let miner_worker_ctx = miner::worker::Worker::new(&server, finished_block_chan, &blockchain, &miner.adversary());
     miner_ctx.start();
     miner_worker_ctx.start();
 
-    // connect to known peers
-    if let Some(known_peers) = matches.values_of("known_peer") {
-        let known_peers: Vec<String> = known_peers.map(|x| x.to_owned()).collect();
-        let server = server.clone();
-        thread::spawn(move || {
-            for peer in known_peers {
-                loop {
-                    let addr = match peer.parse::<net::SocketAddr>() {
-                        Ok(x) => x,
-                        Err(e) => {
-                            error!("Error parsing peer address {}: {}", &peer, e);
-                            break;
-                        }
-                    };
-                    match server.connect(addr) {
-                        Ok(_) => {
-                            info!("Connected to outgoing peer {}", &addr);
-                            break;
-                        }
-                        Err(e) => {
-                            error!(
-                                "Error connecting to peer {}, retrying in one second: {}",
-                                addr, e
-                            );
-                            thread::sleep(time::Duration::from_millis(1000));
-                            continue;
-                        }
-                    }
-                }
-            }
+    // keep the outbound connections open, redialing the known peers whenever they drop
+    let outbound = matches
+        .value_of("outbound")
+        .unwrap()
+        .parse::<usize>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing outbound connections: {}", e);
+            process::exit(1);
         });
+    if outbound > limits.max_outbound {
+        warn!("Only {} of the {} outbound connections fit in the outbound limit", limits.max_outbound, outbound);
     }
-
+    let known_peers: Vec<net::SocketAddr> = matches
+        .values_of("known_peer")
+        .map(|peers| {
+            peers
+                .map(|peer| {
+                    peer.parse::<net::SocketAddr>().unwrap_or_else(|e| {
+                        error!("Error parsing peer address {}: {}", peer, e);
+                        process::exit(1);
+                    })
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+    let (outbound_ctx, outbound) = network::outbound::new(&server, &addrman, outbound, p2p_addr, known_peers);
+    outbound_ctx.start();
 
     // start the API server
     ApiServer::start(
@@ -130,6 +283,10 @@ fn main() {
         &miner,
         &server,
         &blockchain,
+        &sync,
+        &outbound,
+        &banman,
+        &allowlist,
     );
 
     loop {
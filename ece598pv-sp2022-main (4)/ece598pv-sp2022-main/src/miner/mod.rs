@@ -1,6 +1,7 @@
+pub mod strategy;
 pub mod worker;
 
-use log::{info, debug};
+use log::{info, debug, warn};
 
 use crossbeam::channel::{unbounded, Receiver, Sender, TryRecvError};
 
@@ -10,12 +11,13 @@ use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use std::sync::{Arc, Mutex};
 use std::collections::HashMap;
 
-use rand::Rng;
-
+use crate::types::address::Address;
 use crate::types::block::{Block, Header};
-use crate::types::transaction::{SignedTransaction, delete_tx_from_mempool, execute_tx};
+use crate::types::transaction::{SignedTransaction, State, delete_tx_from_mempool};
 use crate::types::hash::{H256, Hashable, do_generate_random_hash};
-use crate::blockchain::Blockchain;
+use crate::blockchain::{Blockchain, MAX_UNCLES};
+use crate::consensus::Consensus;
+use strategy::{Adversary, Strategy};
 
 const MAX_TX_PER_BLOCK: u32 = 300;
 
@@ -39,17 +41,28 @@ pub struct Context {
 
     blockchain: Arc<Mutex<Blockchain>>,
     mempool: Arc<Mutex<HashMap<H256, SignedTransaction>>>,
+    consensus: Arc<dyn Consensus>,
+    beneficiary: Address,
+    adversary: Arc<Mutex<Adversary>>,
 }
 
 #[derive(Clone)]
 pub struct Handle {
     /// Channel for sending signal to the miner thread
     control_chan: Sender<ControlSignal>,
+    adversary: Arc<Mutex<Adversary>>,
 }
 
-pub fn new(blockchain: &Arc<Mutex<Blockchain>>, mempool: &Arc<Mutex<HashMap<H256, SignedTransaction>>>,) -> (Context, Handle, Receiver<Block>) {
+pub fn new(
+    blockchain: &Arc<Mutex<Blockchain>>,
+    mempool: &Arc<Mutex<HashMap<H256, SignedTransaction>>>,
+    beneficiary: Address,
+    strategy: Strategy,
+) -> (Context, Handle, Receiver<Block>) {
     let (signal_chan_sender, signal_chan_receiver) = unbounded();
     let (finished_block_sender, finished_block_receiver) = unbounded();
+    let consensus = blockchain.lock().unwrap().consensus();
+    let adversary = Arc::new(Mutex::new(Adversary::new(strategy, beneficiary)));
 
     let ctx = Context {
         control_chan: signal_chan_receiver,
@@ -57,10 +70,14 @@ pub fn new(blockchain: &Arc<Mutex<Blockchain>>, mempool: &Arc<Mutex<HashMap<H256
         finished_block_chan: finished_block_sender,
         blockchain: Arc::clone(blockchain),
         mempool: Arc::clone(mempool),
+        consensus,
+        beneficiary,
+        adversary: Arc::clone(&adversary),
     };
 
     let handle = Handle {
         control_chan: signal_chan_sender,
+        adversary,
     };
 
     (ctx, handle, finished_block_receiver)
@@ -86,6 +103,21 @@ impl Handle {
     pub fn update(&self) {
         self.control_chan.send(ControlSignal::Update).unwrap();
     }
+
+    /// Switch the mining strategy, see `strategy::Strategy`
+    pub fn set_strategy(&self, strategy: Strategy) {
+        self.adversary.lock().unwrap().set_strategy(strategy);
+        self.update();
+    }
+
+    pub fn strategy(&self) -> Strategy {
+        self.adversary.lock().unwrap().strategy().clone()
+    }
+
+    /// The strategy state, which the miner worker needs to decide what to broadcast
+    pub fn adversary(&self) -> Arc<Mutex<Adversary>> {
+        Arc::clone(&self.adversary)
+    }
 }
 
 impl Context {
@@ -99,28 +131,35 @@ impl Context {
         info!("Miner initialized into paused mode");
     }
 
-    fn miner_loop(&mut self) {
-        let mut rng = rand::thread_rng();
+    /// Get the block to mine on as chosen by the strategy, along with its state and the uncles
+    /// a new block on top of it can reference
+    fn mining_parent(&self) -> (Block, State, Vec<Header>) {
+        let blockchain = self.blockchain.lock().unwrap();
+        let parent_block = self.adversary.lock().unwrap().mining_parent(&blockchain);
+        let parent_state = blockchain.get_block_state(&parent_block.hash()).unwrap().clone();
+        let mut uncles = blockchain.uncle_candidates(&parent_block.hash());
+        uncles.truncate(MAX_UNCLES);
+
+        (parent_block, parent_state, uncles)
+    }
 
+    fn miner_loop(&mut self) {
         let mut parent_block: Block;
-        let mut chain_tip;
+        let mut parent_state: State;
+        let mut uncles: Vec<Header>;
         {
             let blockchain = self.blockchain.lock().unwrap();
-            chain_tip = blockchain.tip();
-
-            parent_block = blockchain.get_block(&chain_tip).unwrap().clone();
+            parent_block = blockchain.get_block(&blockchain.tip()).unwrap().clone();
         }
         let genesis_block = parent_block.clone();
 
         // main mining loop
         loop {
-            // update chain tip
-            {
-                let blockchain = self.blockchain.lock().unwrap();
-                chain_tip = blockchain.tip();
-    
-                parent_block = blockchain.get_block(&chain_tip).unwrap().clone();
-            }
+            // update the block to mine on
+            let (new_parent_block, new_parent_state, new_uncles) = self.mining_parent();
+            parent_block = new_parent_block;
+            parent_state = new_parent_state;
+            uncles = new_uncles;
 
             // check and react to control signals
             match self.operating_state {
@@ -156,10 +195,10 @@ impl Context {
                                 self.operating_state = OperatingState::Run(i);
                             }
                             ControlSignal::Update => {
-                                let blockchain = self.blockchain.lock().unwrap();
-                                chain_tip = blockchain.tip();
-                    
-                                parent_block = blockchain.get_block(&chain_tip).unwrap().clone();
+                                let (new_parent_block, new_parent_state, new_uncles) = self.mining_parent();
+                                parent_block = new_parent_block;
+                                parent_state = new_parent_state;
+                                uncles = new_uncles;
                             }
                         };
                     }
@@ -171,30 +210,39 @@ impl Context {
                 return;
             }
 
-            let mut block = get_block_template(&parent_block);
+            let mut block = get_block_template(&parent_block, &uncles, self.beneficiary);
 
-            block.header.nonce = rng.gen();
-            if block.hash() <= block.get_difficulty() {
+            if self.consensus.seal(&mut block, &parent_block, &parent_state) {
                 debug!("Mined a block with hash {:?} and parent hash {:?}!", block.hash(), parent_block.hash());
 
                 // add tx from mempool to blocks
-                let mut tx_data: Vec<SignedTransaction> = Vec::new();
                 let mut mempool = self.mempool.lock().unwrap();
-                let mut counter = 0;
+                let adversary = self.adversary.lock().unwrap();
+                let mut tx_data: Vec<SignedTransaction> = adversary.block_transactions(&parent_block);
+                let mut counter = tx_data.len() as u32;
                 for (_, tx) in mempool.clone() {
                     if counter == MAX_TX_PER_BLOCK {
                         break;
                     }
+                    if adversary.censors(&tx.transaction) {
+                        continue;
+                    }
                     tx_data.push(tx);
                     counter += 1;
                 }
+                drop(adversary);
                 
                 // update new state
                 {
                     let mut blockchain = self.blockchain.lock().unwrap();
-                    let new_state = blockchain.get_block_state(&parent_block.hash()).unwrap().clone();
-
-                    let (new_state, valid_tx) = execute_tx(&new_state, &tx_data);
+                    block.data = tx_data.clone();
+                    let (new_state, valid_tx) = match blockchain.execute_block(&block, &parent_state) {
+                        Ok(result) => result,
+                        Err(e) => {
+                            warn!("Dropping mined block {:?}: {}", block.hash(), e);
+                            continue;
+                        }
+                    };
                     blockchain.block_states.insert(block.hash(), new_state);
 
                     block.data = valid_tx;
@@ -207,6 +255,11 @@ impl Context {
                     self.finished_block_chan.send(genesis_block.clone()).expect("Send genesis block error");
                 }
                 self.finished_block_chan.send(block.clone()).expect("Send finished block error");
+            } else {
+                let delay = self.consensus.retry_delay();
+                if !delay.is_zero() {
+                    thread::sleep(delay);
+                }
             }
 
             if let OperatingState::Run(i) = self.operating_state {
@@ -219,7 +272,7 @@ impl Context {
     }
 }
 
-fn get_block_template (parent_block: &Block) -> Block {
+fn get_block_template (parent_block: &Block, uncles: &[Header], beneficiary: Address) -> Block {
     let now = SystemTime::now();
     let timestamp: u128 = now.duration_since(UNIX_EPOCH).expect("Clock may have gone backwards").as_millis();
     Block {
@@ -229,6 +282,11 @@ fn get_block_template (parent_block: &Block) -> Block {
             difficulty: parent_block.get_difficulty(),
             timestamp: timestamp,
             merkle_root: do_generate_random_hash(),
+            signer: Vec::new(),
+            signature: Vec::new(),
+            proof: Vec::new(),
+            beneficiary,
+            uncles: uncles.to_vec(),
         },
         length: parent_block.length + 1,
         data: Vec::new(),
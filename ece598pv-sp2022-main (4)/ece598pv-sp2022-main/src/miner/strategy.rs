@@ -0,0 +1,564 @@
+use std::convert::TryInto;
+use std::fmt;
+
+use log::{info, warn};
+use ring::signature::KeyPair;
+
+use crate::blockchain::Blockchain;
+use crate::types::address::Address;
+use crate::types::block::Block;
+use crate::types::hash::{H256, Hashable};
+use crate::types::key_pair;
+use crate::types::transaction::{sign, SignedTransaction, Transaction, TxKind};
+
+/// How far the public chain may get ahead of a private double-spend chain before the attacker
+/// gives up on the race
+const DOUBLE_SPEND_MAX_DEFICIT: u64 = 6;
+
+/// How the miner treats the blocks it mines. Everything except `Honest` is an attack, meant for
+/// experiments only.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Strategy {
+    /// Mine on the best chain and broadcast every block right away
+    Honest,
+    /// Withhold mined blocks in a private chain, and release them only as far as needed to beat
+    /// the public chain whenever it catches up
+    Selfish,
+    /// Pay `value` to `merchant` on the public chain while mining a private chain that spends the
+    /// same nonce on a transfer back to ourselves, and release it once the public chain has
+    /// buried the payment under `depth` blocks and the private chain is longer
+    DoubleSpend { depth: u64, merchant: Address, value: u32 },
+    /// Refuse to extend blocks with transactions from or to `target`, and fork them out unless
+    /// they already have more than `confirmations` confirmations
+    FeatherFork { target: Address, confirmations: u64 },
+}
+
+impl Strategy {
+    /// Parse a strategy as given to `--strategy`: `honest`, `selfish`,
+    /// `double-spend:<depth>:<merchant>:<value>` or `feather-fork:<address>:<confirmations>`
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let parts: Vec<&str> = s.split(':').collect();
+        match parts.as_slice() {
+            ["honest"] => Ok(Strategy::Honest),
+            ["selfish"] => Ok(Strategy::Selfish),
+            ["double-spend", depth, merchant, value] => {
+                let depth = depth.parse::<u64>().map_err(|e| format!("error parsing depth: {}", e))?;
+                let merchant = parse_address(merchant, "merchant")?;
+                let value = value.parse::<u32>().map_err(|e| format!("error parsing value: {}", e))?;
+                Ok(Strategy::DoubleSpend { depth, merchant, value })
+            }
+            ["feather-fork", target, confirmations] => {
+                let target = parse_address(target, "target")?;
+                let confirmations = confirmations
+                    .parse::<u64>()
+                    .map_err(|e| format!("error parsing confirmations: {}", e))?;
+                Ok(Strategy::FeatherFork { target, confirmations })
+            }
+            _ => Err(format!("unknown mining strategy {}", s)),
+        }
+    }
+}
+
+fn parse_address(s: &str, name: &str) -> Result<Address, String> {
+    let bytes: [u8; 20] = hex::decode(s)
+        .map_err(|e| format!("error parsing {} address: {}", name, e))?
+        .as_slice()
+        .try_into()
+        .map_err(|_| format!("{} address must be 20 bytes", name))?;
+    Ok(bytes.into())
+}
+
+impl fmt::Display for Strategy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Strategy::Honest => write!(f, "honest"),
+            Strategy::Selfish => write!(f, "selfish"),
+            Strategy::DoubleSpend { depth, merchant, value } => write!(f, "double-spend:{}:{}:{}", depth, merchant, value),
+            Strategy::FeatherFork { target, confirmations } => write!(f, "feather-fork:{}:{}", target, confirmations),
+        }
+    }
+}
+
+/// The two sides of a double spend: the payment to the merchant, broadcast for the public chain,
+/// and a transfer back to the payer with the same nonce, which only goes into the private chain
+struct DoubleSpendAttack {
+    // the public block the private chain forks from
+    base: Block,
+    payment: SignedTransaction,
+    conflict: SignedTransaction,
+    broadcast: bool,
+}
+
+/// The state of the mining strategy, shared by the miner, which asks it what to mine on, and the
+/// miner worker, which asks it which blocks to broadcast
+pub struct Adversary {
+    strategy: Strategy,
+    // the account that pays in a double spend, the beneficiary of the miner
+    payer: Address,
+    // blocks mined on top of the block of length `base_length` that are not on the public chain
+    // yet, the first `released` of them are broadcast already
+    private: Vec<Block>,
+    released: usize,
+    base_length: u32,
+    // length of the public chain the last time it was checked
+    public_length: u32,
+    // for feather forking: the block being forked out, and the tip of our fork
+    fork_target: Option<H256>,
+    fork_tip: Option<Block>,
+    double_spend: Option<DoubleSpendAttack>,
+}
+
+impl Adversary {
+    pub fn new(strategy: Strategy, payer: Address) -> Self {
+        Self {
+            strategy,
+            payer,
+            private: Vec::new(),
+            released: 0,
+            base_length: 0,
+            public_length: 0,
+            fork_target: None,
+            fork_tip: None,
+            double_spend: None,
+        }
+    }
+
+    pub fn strategy(&self) -> &Strategy {
+        &self.strategy
+    }
+
+    /// Switch to another strategy. Withheld blocks are kept, and released on the next check of
+    /// the public chain if the new strategy is honest.
+    pub fn set_strategy(&mut self, strategy: Strategy) {
+        info!("Switching mining strategy from {} to {}", self.strategy, strategy);
+        self.strategy = strategy;
+        self.fork_target = None;
+        self.fork_tip = None;
+        self.double_spend = None;
+    }
+
+    fn private_length(&self) -> u32 {
+        self.base_length + self.private.len() as u32
+    }
+
+    /// Whether the strategy keeps `tx` out of the blocks it mines
+    pub fn censors(&self, tx: &Transaction) -> bool {
+        match &self.strategy {
+            Strategy::FeatherFork { target, .. } => tx.sender == *target || tx.receiver == *target,
+            // the payment, or anything else that takes the nonce of the conflicting transaction
+            Strategy::DoubleSpend { .. } => match &self.double_spend {
+                Some(attack) => {
+                    tx.sender == attack.conflict.transaction.sender
+                        && tx.account_nonce == attack.conflict.transaction.account_nonce
+                }
+                None => false,
+            },
+            _ => false,
+        }
+    }
+
+    /// Transactions the strategy puts into a block on top of `parent`, ahead of the ones from the
+    /// mempool
+    pub fn block_transactions(&self, parent: &Block) -> Vec<SignedTransaction> {
+        match &self.double_spend {
+            Some(attack) if attack.base.hash() == parent.hash() => vec![attack.conflict.clone()],
+            _ => Vec::new(),
+        }
+    }
+
+    /// Take the payment of a double spend that just started, for the miner worker to broadcast
+    pub fn take_payment(&mut self) -> Option<SignedTransaction> {
+        let attack = self.double_spend.as_mut()?;
+        if attack.broadcast {
+            return None;
+        }
+        attack.broadcast = true;
+        Some(attack.payment.clone())
+    }
+
+    /// Get the block the miner should mine on
+    pub fn mining_parent(&mut self, blockchain: &Blockchain) -> Block {
+        if let Some(private_tip) = self.private.last() {
+            return private_tip.clone();
+        }
+
+        let tip = blockchain.get_block(&blockchain.tip()).unwrap().clone();
+        if let Strategy::DoubleSpend { merchant, value, .. } = self.strategy {
+            let payer = self.payer;
+            let attack = self
+                .double_spend
+                .get_or_insert_with(|| start_double_spend(tip, blockchain, payer, merchant, value));
+            return attack.base.clone();
+        }
+        let confirmations = match &self.strategy {
+            Strategy::FeatherFork { confirmations, .. } => *confirmations,
+            _ => return tip,
+        };
+
+        // the oldest block that is still young enough to fork out and that has a censored transaction
+        let mut offending = None;
+        let mut curr = Some(&tip);
+        while let Some(block) = curr {
+            if (tip.length - block.length) as u64 >= confirmations {
+                break;
+            }
+            if block.data.iter().any(|tx| self.censors(&tx.transaction)) {
+                offending = Some(block.clone());
+            }
+            curr = blockchain.get_block(&block.get_parent());
+        }
+
+        let offending = match offending {
+            Some(block) => block,
+            None => {
+                if let Some(target) = self.fork_target.take() {
+                    info!("Done feather forking against block {:?}", target);
+                }
+                self.fork_tip = None;
+                return tip;
+            }
+        };
+        if self.fork_target != Some(offending.hash()) {
+            info!("Feather forking to exclude block {:?} with a censored transaction", offending.hash());
+            self.fork_target = Some(offending.hash());
+            self.fork_tip = None;
+        }
+        match &self.fork_tip {
+            Some(fork_tip) => fork_tip.clone(),
+            None => blockchain.get_block(&offending.get_parent()).unwrap_or(&tip).clone(),
+        }
+    }
+
+    /// Handle a block the miner just mined, given the length of the public chain. Returns the
+    /// blocks to broadcast now.
+    pub fn on_mined(&mut self, block: Block, public_length: u32) -> Vec<Block> {
+        match self.strategy {
+            Strategy::Honest => vec![block],
+            Strategy::FeatherFork { .. } => {
+                if self.fork_target.is_some() {
+                    self.fork_tip = Some(block.clone());
+                }
+                vec![block]
+            }
+            Strategy::Selfish => {
+                if self.private.is_empty() {
+                    self.base_length = block.length - 1;
+                }
+                // all our blocks are out and tie with the public chain, so this one wins the race
+                let racing = !self.private.is_empty() && self.private_length() == public_length;
+                self.private.push(block);
+                if racing {
+                    info!("Won the race against the public chain, releasing {} blocks", self.private.len() - self.released);
+                    return self.release_all();
+                }
+                self.log_withhold(public_length);
+                Vec::new()
+            }
+            Strategy::DoubleSpend { depth, .. } => {
+                if self.private.is_empty() {
+                    self.base_length = block.length - 1;
+                }
+                self.private.push(block);
+                if self.double_spend_done(public_length, depth) {
+                    return self.release_all();
+                }
+                self.log_withhold(public_length);
+                Vec::new()
+            }
+        }
+    }
+
+    /// Check the length of the public chain, and react if it changed. Returns the blocks to
+    /// broadcast now.
+    pub fn on_public(&mut self, public_length: u32) -> Vec<Block> {
+        if self.strategy == Strategy::Honest && self.released < self.private.len() {
+            info!("Mining honestly again, releasing {} withheld blocks", self.private.len() - self.released);
+            return self.release_all();
+        }
+        if public_length == self.public_length {
+            return Vec::new();
+        }
+        self.public_length = public_length;
+        if self.private.is_empty() {
+            return Vec::new();
+        }
+
+        let private_length = self.private_length();
+        match self.strategy {
+            Strategy::Selfish => {
+                if private_length < public_length {
+                    self.abandon(public_length);
+                    Vec::new()
+                } else if private_length == public_length {
+                    info!("Public chain caught up at length {}, racing with the private chain", public_length);
+                    self.release_up_to(private_length)
+                } else if private_length == public_length + 1 {
+                    info!("Public chain got within one block, releasing the private chain");
+                    self.release_all()
+                } else {
+                    self.release_up_to(public_length)
+                }
+            }
+            Strategy::DoubleSpend { depth, .. } => {
+                if self.double_spend_done(public_length, depth) {
+                    self.release_all()
+                } else if public_length >= private_length + DOUBLE_SPEND_MAX_DEFICIT as u32 {
+                    self.abandon(public_length);
+                    Vec::new()
+                } else {
+                    Vec::new()
+                }
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    fn double_spend_done(&self, public_length: u32, depth: u64) -> bool {
+        let buried = (public_length.saturating_sub(self.base_length)) as u64 >= depth;
+        if buried && self.private_length() > public_length {
+            info!("Payment is {} blocks deep and the private chain is longer, releasing it", depth);
+            return true;
+        }
+        false
+    }
+
+    fn log_withhold(&self, public_length: u32) {
+        let block = self.private.last().unwrap();
+        info!(
+            "Withholding block {:?}, private chain at length {} against public length {}",
+            block.hash(),
+            self.private_length(),
+            public_length
+        );
+    }
+
+    fn abandon(&mut self, public_length: u32) {
+        info!(
+            "Abandoning private chain at length {}, public chain is at length {}",
+            self.private_length(),
+            public_length
+        );
+        self.private.clear();
+        self.released = 0;
+        self.double_spend = None;
+    }
+
+    /// Release the withheld blocks up to the given length
+    fn release_up_to(&mut self, length: u32) -> Vec<Block> {
+        let end = (length.saturating_sub(self.base_length) as usize).min(self.private.len());
+        if end <= self.released {
+            return Vec::new();
+        }
+        let blocks = self.private[self.released..end].to_vec();
+        self.released = end;
+        for block in blocks.iter() {
+            info!("Releasing withheld block {:?}", block.hash());
+        }
+        blocks
+    }
+
+    /// Release all withheld blocks and go back to mining on the public chain
+    fn release_all(&mut self) -> Vec<Block> {
+        let blocks = self.release_up_to(self.private_length());
+        self.private.clear();
+        self.released = 0;
+        self.double_spend = None;
+        blocks
+    }
+}
+
+/// Prepare a double spend of `value` forking from `base`, with the next nonce of `payer`
+fn start_double_spend(base: Block, blockchain: &Blockchain, payer: Address, merchant: Address, value: u32) -> DoubleSpendAttack {
+    let (nonce, balance) = blockchain.get_block_state(&base.hash()).and_then(|state| state.get(&payer).copied()).unwrap_or((0, 0));
+    if balance < value {
+        warn!("Double spending {} with a balance of only {}, the payment will not go through", value, balance);
+    }
+    let transfer = |receiver| Transaction {
+        sender: payer,
+        receiver,
+        account_nonce: nonce + 1,
+        value,
+        kind: TxKind::Transfer,
+    };
+    info!("Starting a double spend of {} to {} from block {:?}", value, merchant, base.hash());
+    DoubleSpendAttack {
+        base,
+        payment: signed(transfer(merchant)),
+        conflict: signed(transfer(payer)),
+        broadcast: false,
+    }
+}
+
+fn signed(transaction: Transaction) -> SignedTransaction {
+    let key = key_pair::random();
+    let signature = sign(&transaction, &key);
+    SignedTransaction {
+        transaction,
+        signature: signature.as_ref().to_vec(),
+        public_key: key.public_key().as_ref().to_vec(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use crate::consensus::AcceptAll;
+    use crate::types::block::generate_random_block;
+    use crate::types::hash::generate_random_hash;
+
+    fn private_chain(count: u32, base_length: u32) -> Vec<Block> {
+        let mut blocks = Vec::new();
+        let mut parent = generate_random_hash();
+        for i in 0..count {
+            let mut block = generate_random_block(&parent);
+            block.length = base_length + i + 1;
+            parent = block.hash();
+            blocks.push(block);
+        }
+        blocks
+    }
+
+    /// A block on top of `parent` with the given transactions, with its state stored
+    fn mine(blockchain: &mut Blockchain, parent: &Block, data: Vec<SignedTransaction>) -> Block {
+        let mut block = generate_random_block(&parent.hash());
+        block.length = parent.length + 1;
+        block.data = data;
+        let parent_state = blockchain.get_block_state(&parent.hash()).unwrap().clone();
+        let (state, valid_tx) = blockchain.execute_block(&block, &parent_state).unwrap();
+        assert_eq!(valid_tx.len(), block.data.len());
+        blockchain.block_states.insert(block.hash(), state);
+        block
+    }
+
+    #[test]
+    fn parse_strategies() {
+        assert_eq!(Strategy::parse("selfish"), Ok(Strategy::Selfish));
+        let double_spend = Strategy::parse(&format!("double-spend:6:{}:30", "cd".repeat(20))).unwrap();
+        assert_eq!(double_spend, Strategy::DoubleSpend { depth: 6, merchant: [0xcd; 20].into(), value: 30 });
+        assert_eq!(Strategy::parse(&double_spend.to_string()), Ok(double_spend));
+        assert!(Strategy::parse("double-spend:6").is_err());
+        let feather = Strategy::parse(&format!("feather-fork:{}:3", "ab".repeat(20))).unwrap();
+        assert_eq!(Strategy::parse(&feather.to_string()), Ok(feather));
+        assert!(Strategy::parse("feather-fork:abcd:3").is_err());
+        assert!(Strategy::parse("greedy").is_err());
+    }
+
+    #[test]
+    fn selfish_withholds_and_releases_on_competition() {
+        let mut adversary = Adversary::new(Strategy::Selfish, Address::default());
+        let blocks = private_chain(3, 10);
+        adversary.on_public(10);
+
+        // mine three blocks ahead of the public chain
+        for block in blocks.iter() {
+            assert!(adversary.on_mined(block.clone(), 10).is_empty());
+        }
+        // the public chain gains a block, release just enough to match it
+        let released = adversary.on_public(11);
+        assert_eq!(released.len(), 1);
+        assert_eq!(released[0].hash(), blocks[0].hash());
+        // it gets within one block, release everything
+        let released = adversary.on_public(12);
+        assert_eq!(released.len(), 2);
+        assert_eq!(released[1].hash(), blocks[2].hash());
+    }
+
+    #[test]
+    fn selfish_races_and_abandons() {
+        let mut adversary = Adversary::new(Strategy::Selfish, Address::default());
+        let blocks = private_chain(2, 10);
+        adversary.on_public(10);
+
+        assert!(adversary.on_mined(blocks[0].clone(), 10).is_empty());
+        // a tie, publish the withheld block to race
+        assert_eq!(adversary.on_public(11).len(), 1);
+        // the public chain wins the race, go back to it
+        assert!(adversary.on_public(12).is_empty());
+        let mut next = generate_random_block(&generate_random_hash());
+        next.length = 13;
+        assert!(adversary.on_mined(next, 12).is_empty());
+    }
+
+    #[test]
+    fn double_spend_releases_after_depth() {
+        let mut adversary = Adversary::new(Strategy::DoubleSpend { depth: 2, merchant: Address::default(), value: 0 }, Address::default());
+        let blocks = private_chain(3, 10);
+        adversary.on_public(10);
+
+        assert!(adversary.on_mined(blocks[0].clone(), 10).is_empty());
+        assert!(adversary.on_mined(blocks[1].clone(), 10).is_empty());
+        // the payment is only one block deep
+        assert!(adversary.on_public(11).is_empty());
+        assert!(adversary.on_mined(blocks[2].clone(), 11).is_empty());
+        // two blocks deep and the private chain is longer
+        assert_eq!(adversary.on_public(12).len(), 3);
+    }
+
+    #[test]
+    fn double_spend_reverts_payment() {
+        let mut blockchain = Blockchain::with_consensus(Arc::new(AcceptAll), &Default::default());
+        // the ICO account has the coins to pay with
+        let payer = Address::from_public_key_bytes(&0u32.to_be_bytes());
+        let merchant = Address::from_public_key_bytes(b"merchant");
+        let mut adversary = Adversary::new(Strategy::DoubleSpend { depth: 2, merchant, value: 30 }, payer);
+        let genesis = blockchain.get_block(&blockchain.tip()).unwrap().clone();
+        adversary.on_public(genesis.length);
+
+        // the attack forks from the public tip, and the payment goes out once
+        let base = adversary.mining_parent(&blockchain);
+        assert_eq!(base.hash(), genesis.hash());
+        let payment = adversary.take_payment().unwrap();
+        assert!(adversary.take_payment().is_none());
+        assert_eq!(payment.transaction.receiver, merchant);
+        // the private chain never takes the payment, and starts with a transfer of the same nonce
+        assert!(adversary.censors(&payment.transaction));
+        let conflict = adversary.block_transactions(&base);
+        assert_eq!(conflict.len(), 1);
+        assert_eq!(conflict[0].transaction.receiver, payer);
+        assert_eq!(conflict[0].transaction.account_nonce, payment.transaction.account_nonce);
+        let private = mine(&mut blockchain, &base, conflict);
+        assert!(adversary.on_mined(private, 1).is_empty());
+
+        // honest miners put the payment on the public chain
+        let mut public = mine(&mut blockchain, &genesis, vec![payment]);
+        blockchain.insert(&public);
+        assert_eq!(blockchain.get_block_state(&blockchain.tip()).unwrap()[&merchant].1, 30);
+        assert!(adversary.on_public(2).is_empty());
+
+        let parent = adversary.mining_parent(&blockchain);
+        assert!(adversary.block_transactions(&parent).is_empty());
+        let private = mine(&mut blockchain, &parent, Vec::new());
+        assert!(adversary.on_mined(private, 2).is_empty());
+        public = mine(&mut blockchain, &public, Vec::new());
+        blockchain.insert(&public);
+        // buried two blocks deep, but the private chain is not longer yet
+        assert!(adversary.on_public(3).is_empty());
+
+        let parent = adversary.mining_parent(&blockchain);
+        let private = mine(&mut blockchain, &parent, Vec::new());
+        let released = adversary.on_mined(private, 3);
+        assert_eq!(released.len(), 3);
+        for block in released.iter() {
+            blockchain.insert(block);
+        }
+
+        // the reorg reverts the payment, and the nonce went to the transfer back
+        let state = blockchain.get_block_state(&blockchain.tip()).unwrap();
+        assert!(!state.contains_key(&merchant));
+        assert_eq!(state[&payer], (1, 100));
+    }
+
+    #[test]
+    fn honest_again_releases_withheld() {
+        let mut adversary = Adversary::new(Strategy::Selfish, Address::default());
+        let blocks = private_chain(2, 10);
+        adversary.on_public(10);
+        for block in blocks.iter() {
+            adversary.on_mined(block.clone(), 10);
+        }
+        adversary.set_strategy(Strategy::Honest);
+        assert_eq!(adversary.on_public(10).len(), 2);
+        assert!(adversary.on_public(10).is_empty());
+    }
+}
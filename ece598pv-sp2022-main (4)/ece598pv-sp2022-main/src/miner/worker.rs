@@ -1,13 +1,20 @@
-use crossbeam::channel::{unbounded, Receiver, Sender, TryRecvError};
-use log::{debug, info};
+use crossbeam::channel::{Receiver, RecvTimeoutError};
+use log::info;
 use crate::types::block::Block;
 use crate::network::server::Handle as ServerHandle;
+use crate::network::compact::CompactBlock;
 use crate::network::message::Message;
 
 use std::thread;
+use std::time::Duration;
 use std::sync::{Arc, Mutex};
 
 use crate::blockchain::Blockchain;
+use crate::miner::strategy::Adversary;
+
+/// How often the worker checks the public chain when no block is mined, so that the strategy can
+/// react to blocks from other miners
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
 
 #[derive(Clone)]
 pub struct Worker {
@@ -15,6 +22,7 @@ pub struct Worker {
     finished_block_chan: Receiver<Block>,
 
     blockchain: Arc<Mutex<Blockchain>>,
+    adversary: Arc<Mutex<Adversary>>,
 }
 
 impl Worker {
@@ -22,12 +30,14 @@ impl Worker {
         server: &ServerHandle,
         finished_block_chan: Receiver<Block>,
         blockchain: &Arc<Mutex<Blockchain>>,
+        adversary: &Arc<Mutex<Adversary>>,
     ) -> Self {
         Self {
             server: server.clone(),
             finished_block_chan,
 
             blockchain: Arc::clone(blockchain),
+            adversary: Arc::clone(adversary),
         }
     }
 
@@ -43,13 +53,53 @@ impl Worker {
 
     fn worker_loop(&self) {
         loop {
-            let block = self.finished_block_chan.recv().expect("Receive finished block error");
-            // TODO for student: insert this finished block to blockchain, and broadcast this block hash - DONE
-            {
-                let mut blockchain = self.blockchain.lock().unwrap();
-                blockchain.insert(&block);
+            match self.finished_block_chan.recv_timeout(POLL_INTERVAL) {
+                Ok(block) => {
+                    if block.length == 1 {
+                        // the genesis block is never withheld
+                        self.release(vec![block]);
+                    } else {
+                        let public_length = self.public_length();
+                        let released = self.adversary.lock().unwrap().on_mined(block, public_length);
+                        self.release(released);
+                    }
+                }
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => panic!("Receive finished block error"),
+            }
+
+            let public_length = self.public_length();
+            let released = self.adversary.lock().unwrap().on_public(public_length);
+            self.release(released);
+
+            let payment = self.adversary.lock().unwrap().take_payment();
+            if let Some(payment) = payment {
+                info!("Broadcasting the payment of the double spend");
+                self.server.broadcast(Message::Transactions(vec![payment]));
+            }
+        }
+    }
+
+    /// Length of the best chain, which only holds blocks that were broadcast
+    fn public_length(&self) -> u32 {
+        let blockchain = self.blockchain.lock().unwrap();
+        blockchain.get_block(&blockchain.tip()).unwrap().length
+    }
+
+    /// Insert the blocks to the blockchain, and broadcast them as compact blocks, since peers have
+    /// most of the transactions in their mempool already
+    fn release(&self, blocks: Vec<Block>) {
+        if blocks.is_empty() {
+            return;
+        }
+        {
+            let mut blockchain = self.blockchain.lock().unwrap();
+            for block in blocks.iter() {
+                blockchain.insert(block);
             }
-            self.server.broadcast(Message::Blocks(vec![block]));
+        }
+        for block in blocks.iter() {
+            self.server.broadcast(Message::CompactBlock(CompactBlock::from_block(block)));
         }
     }
 }
@@ -0,0 +1,334 @@
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::debug;
+use rand::seq::SliceRandom;
+use serde::{Serialize, Deserialize};
+
+/// Name of the file in the data dir that holds the known addresses
+const PEERS_FILE: &str = "peers.json";
+/// Addresses that failed this many connection attempts in a row are forgotten
+const MAX_FAILURES: u32 = 10;
+/// Most addresses kept, beyond that new ones take the place of failed or stale ones
+const MAX_ADDRS: usize = 4096;
+/// Most addresses that came from the same peer, so that a single peer cannot fill the table
+const MAX_ADDRS_PER_SOURCE: usize = 256;
+/// Addresses we were not connected to for this many milliseconds are stale
+const STALE_AFTER: u64 = 30 * 24 * 3600 * 1000;
+/// How many milliseconds the last time we were connected to an address may lag behind, so that
+/// the file is not rewritten for every peer at every check
+const SEEN_INTERVAL: u64 = 20 * 60 * 1000;
+
+/// What the address manager knows about a peer address
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct AddrInfo {
+    /// Milliseconds since the UNIX epoch when we were last connected to it, 0 if never
+    pub last_seen: u64,
+    /// Failed connection attempts since the last successful one
+    pub failures: u32,
+    /// Peer that told us about the address, `None` if we learned it some other way
+    #[serde(default)]
+    pub source: Option<IpAddr>,
+}
+
+impl AddrInfo {
+    /// Whether the address may make room for a new one
+    fn is_evictable(&self, now: u64) -> bool {
+        self.failures > 0 || now.saturating_sub(self.last_seen) > STALE_AFTER
+    }
+}
+
+/// Addresses of peers this node heard about, from `Addr` messages, handshakes and the command
+/// line, persisted in the data dir if there is one
+pub struct AddrManager {
+    addrs: HashMap<SocketAddr, AddrInfo>,
+    // number of addresses from each peer
+    sources: HashMap<IpAddr, usize>,
+    path: Option<PathBuf>,
+    dirty: bool,
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64
+}
+
+impl AddrManager {
+    /// Create an address manager that only keeps addresses in memory
+    pub fn new() -> Self {
+        Self {
+            addrs: HashMap::new(),
+            sources: HashMap::new(),
+            path: None,
+            dirty: false,
+        }
+    }
+
+    /// Create an address manager persisted in `data_dir`, loading the addresses saved there
+    pub fn load(data_dir: &Path) -> io::Result<Self> {
+        fs::create_dir_all(data_dir)?;
+        let path = data_dir.join(PEERS_FILE);
+        let addrs: HashMap<SocketAddr, AddrInfo> = match fs::read_to_string(&path) {
+            Ok(content) => serde_json::from_str(&content).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(e),
+        };
+        let mut sources = HashMap::new();
+        for source in addrs.values().filter_map(|info| info.source) {
+            *sources.entry(source).or_insert(0) += 1;
+        }
+        Ok(Self {
+            addrs,
+            sources,
+            path: Some(path),
+            dirty: false,
+        })
+    }
+
+    /// Write the addresses to the data dir, if they changed since the last save
+    pub fn save(&mut self) -> io::Result<()> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        if !self.dirty {
+            return Ok(());
+        }
+        // write a temporary file first, so that a crash never leaves a truncated file behind
+        let tmp_path = path.with_extension("json.tmp");
+        fs::write(&tmp_path, serde_json::to_string_pretty(&self.addrs).unwrap())?;
+        fs::rename(&tmp_path, path)?;
+        self.dirty = false;
+        debug!("Saved {} peer addresses", self.addrs.len());
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize {
+        self.addrs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.addrs.is_empty()
+    }
+
+    pub fn get(&self, addr: &SocketAddr) -> Option<&AddrInfo> {
+        self.addrs.get(addr)
+    }
+
+    /// Record an address we learned about from the command line or a handshake
+    pub fn add(&mut self, addr: SocketAddr) {
+        self.insert(addr, None);
+    }
+
+    /// Record an address that the peer at `source` told us about. Addresses beyond
+    /// `MAX_ADDRS_PER_SOURCE` from the same peer are ignored.
+    pub fn add_from(&mut self, addr: SocketAddr, source: IpAddr) {
+        if self.sources.get(&source).copied().unwrap_or(0) >= MAX_ADDRS_PER_SOURCE {
+            debug!("Ignoring address {} from {}, it sent too many", addr, source);
+            return;
+        }
+        self.insert(addr, Some(source));
+    }
+
+    fn insert(&mut self, addr: SocketAddr, source: Option<IpAddr>) {
+        if addr.ip().is_unspecified() || addr.port() == 0 || self.addrs.contains_key(&addr) {
+            return;
+        }
+        if self.addrs.len() >= MAX_ADDRS && !self.evict() {
+            return;
+        }
+        if let Some(source) = source {
+            *self.sources.entry(source).or_insert(0) += 1;
+        }
+        self.addrs.insert(addr, AddrInfo { source, ..Default::default() });
+        self.dirty = true;
+    }
+
+    /// Forget the failed or stale address with the most failures, the least recently seen
+    /// among them. Returns false if every address is worth keeping.
+    fn evict(&mut self) -> bool {
+        let now = now_millis();
+        let worst = self
+            .addrs
+            .iter()
+            .filter(|(_, info)| info.is_evictable(now))
+            .max_by_key(|(addr, info)| (info.failures, std::cmp::Reverse(info.last_seen), **addr))
+            .map(|(addr, _)| *addr);
+        match worst {
+            Some(addr) => {
+                debug!("Forgetting peer address {} to make room", addr);
+                self.remove(&addr);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn remove(&mut self, addr: &SocketAddr) {
+        let source = match self.addrs.remove(addr) {
+            Some(info) => info.source,
+            None => return,
+        };
+        if let Some(source) = source {
+            if let Entry::Occupied(mut count) = self.sources.entry(source) {
+                *count.get_mut() -= 1;
+                if *count.get() == 0 {
+                    count.remove();
+                }
+            }
+        }
+        self.dirty = true;
+    }
+
+    /// Record that we are connected to `addr`
+    pub fn mark_seen(&mut self, addr: SocketAddr) {
+        self.add(addr);
+        let now = now_millis();
+        if let Some(info) = self.addrs.get_mut(&addr) {
+            if info.failures == 0 && now.saturating_sub(info.last_seen) < SEEN_INTERVAL {
+                return;
+            }
+            info.last_seen = now;
+            info.failures = 0;
+            self.dirty = true;
+        }
+    }
+
+    /// Record a failed connection attempt to `addr`, and forget it after too many of them
+    pub fn mark_failed(&mut self, addr: SocketAddr) {
+        if let Some(info) = self.addrs.get_mut(&addr) {
+            info.failures += 1;
+            self.dirty = true;
+            if info.failures >= MAX_FAILURES {
+                debug!("Forgetting peer address {} after {} failures", addr, info.failures);
+                self.remove(&addr);
+            }
+        }
+    }
+
+    /// Get up to `max` addresses to tell other peers about, the most recently seen first
+    pub fn addresses(&self, max: usize) -> Vec<SocketAddr> {
+        let mut addrs: Vec<(&SocketAddr, &AddrInfo)> = self.addrs.iter().collect();
+        addrs.sort_by_key(|(_, info)| std::cmp::Reverse(info.last_seen));
+        addrs.into_iter().take(max).map(|(addr, _)| *addr).collect()
+    }
+
+    /// Pick up to `count` addresses to connect to, leaving out `exclude`. Addresses with fewer
+    /// failures come first, in random order among the same number of failures.
+    pub fn candidates(&self, exclude: &HashSet<SocketAddr>, count: usize) -> Vec<SocketAddr> {
+        let mut addrs: Vec<(&SocketAddr, &AddrInfo)> = self
+            .addrs
+            .iter()
+            .filter(|(addr, _)| !exclude.contains(addr))
+            .collect();
+        addrs.shuffle(&mut rand::thread_rng());
+        addrs.sort_by_key(|(_, info)| info.failures);
+        addrs.into_iter().take(count).map(|(addr, _)| *addr).collect()
+    }
+}
+
+impl Default for AddrManager {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    #[test]
+    fn failures_and_candidates() {
+        let mut addrman = AddrManager::new();
+        addrman.add(addr(6001));
+        addrman.add(addr(6002));
+        addrman.add(addr(6003));
+        addrman.add(SocketAddr::from(([0, 0, 0, 0], 6004)));
+        assert_eq!(addrman.len(), 3);
+
+        addrman.mark_failed(addr(6001));
+        addrman.mark_seen(addr(6002));
+        let exclude: HashSet<SocketAddr> = vec![addr(6003)].into_iter().collect();
+        assert_eq!(addrman.candidates(&exclude, 5), vec![addr(6002), addr(6001)]);
+        assert_eq!(addrman.addresses(1), vec![addr(6002)]);
+
+        for _ in 1..MAX_FAILURES {
+            addrman.mark_failed(addr(6001));
+        }
+        assert!(addrman.get(&addr(6001)).is_none());
+    }
+
+    #[test]
+    fn capacity_and_sources() {
+        let mut addrman = AddrManager::new();
+        let source = IpAddr::from([10, 0, 0, 1]);
+        for port in 0..MAX_ADDRS_PER_SOURCE as u16 + 10 {
+            addrman.add_from(addr(1000 + port), source);
+        }
+        assert_eq!(addrman.len(), MAX_ADDRS_PER_SOURCE);
+        // forgetting one of them lets the peer add another
+        for _ in 0..MAX_FAILURES {
+            addrman.mark_failed(addr(1000));
+        }
+        addrman.add_from(addr(5000), source);
+        assert_eq!(addrman.len(), MAX_ADDRS_PER_SOURCE);
+        assert!(addrman.get(&addr(5000)).is_some());
+
+        // fill the table with addresses we were connected to
+        let mut port = 10000;
+        while addrman.len() < MAX_ADDRS {
+            addrman.mark_seen(addr(port));
+            port += 1;
+        }
+        // a failed address makes room first
+        addrman.mark_failed(addr(10000));
+        addrman.add(addr(port));
+        assert_eq!(addrman.len(), MAX_ADDRS);
+        assert!(addrman.get(&addr(10000)).is_none());
+        assert!(addrman.get(&addr(port)).is_some());
+        // then the ones we were never connected to, but never the others
+        for next in port + 1..port + 1000 {
+            addrman.add_from(addr(next), IpAddr::from([10, 0, 0, 2]));
+        }
+        assert_eq!(addrman.len(), MAX_ADDRS);
+        assert!((10001..port).all(|seen| addrman.get(&addr(seen)).is_some()));
+    }
+
+    #[test]
+    fn seen_only_dirties_on_change() {
+        let mut addrman = AddrManager::new();
+        addrman.mark_seen(addr(6001));
+        assert!(addrman.dirty);
+        addrman.dirty = false;
+        addrman.mark_seen(addr(6001));
+        assert!(!addrman.dirty);
+        addrman.mark_failed(addr(6001));
+        addrman.dirty = false;
+        addrman.mark_seen(addr(6001));
+        assert!(addrman.dirty);
+        assert_eq!(addrman.get(&addr(6001)).unwrap().failures, 0);
+    }
+
+    #[test]
+    fn persist_in_data_dir() {
+        let data_dir = std::env::temp_dir().join(format!("addrman-test-{}", rand::random::<u64>()));
+        let mut addrman = AddrManager::load(&data_dir).unwrap();
+        assert!(addrman.is_empty());
+        addrman.mark_seen(addr(6001));
+        addrman.add(addr(6002));
+        addrman.save().unwrap();
+
+        let loaded = AddrManager::load(&data_dir).unwrap();
+        assert_eq!(loaded.len(), 2);
+        assert_eq!(loaded.get(&addr(6001)), addrman.get(&addr(6001)));
+        fs::remove_dir_all(&data_dir).unwrap();
+    }
+}
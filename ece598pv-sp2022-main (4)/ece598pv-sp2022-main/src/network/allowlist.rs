@@ -0,0 +1,114 @@
+use std::collections::HashSet;
+use std::convert::TryInto;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use super::secure::{Identity, Session};
+
+/// Prefix of the allowlist lines that hold a network secret instead of a node identity
+const SECRET_PREFIX: &str = "secret:";
+
+/// The peers allowed on a private network: node identities, and network secrets whose holders are
+/// all allowed. Read from a file with one entry per line, either the hex-encoded identity of a
+/// node or `secret:` followed by a secret. Empty lines and lines starting with `#` are skipped.
+#[derive(Debug, Clone)]
+pub struct Allowlist {
+    path: PathBuf,
+    identities: HashSet<Identity>,
+    secrets: Vec<Vec<u8>>,
+}
+
+fn parse(contents: &str) -> io::Result<(HashSet<Identity>, Vec<Vec<u8>>)> {
+    let mut identities = HashSet::new();
+    let mut secrets = Vec::new();
+    for (number, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if let Some(secret) = line.strip_prefix(SECRET_PREFIX) {
+            secrets.push(secret.as_bytes().to_vec());
+            continue;
+        }
+        let identity: Option<Identity> = hex::decode(line).ok().and_then(|bytes| bytes.as_slice().try_into().ok());
+        match identity {
+            Some(identity) => identities.insert(identity),
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("line {} is neither a node identity nor a secret", number + 1),
+                ))
+            }
+        };
+    }
+    Ok((identities, secrets))
+}
+
+impl Allowlist {
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let (identities, secrets) = parse(&std::fs::read_to_string(path)?)?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            identities,
+            secrets,
+        })
+    }
+
+    /// Read the file again. The current entries are kept if it cannot be read.
+    pub fn reload(&mut self) -> io::Result<()> {
+        let (identities, secrets) = parse(&std::fs::read_to_string(&self.path)?)?;
+        self.identities = identities;
+        self.secrets = secrets;
+        Ok(())
+    }
+
+    /// Number of node identities and of network secrets
+    pub fn counts(&self) -> (usize, usize) {
+        (self.identities.len(), self.secrets.len())
+    }
+
+    /// Network secrets this node proves it knows in the handshake
+    pub fn secrets(&self) -> &[Vec<u8>] {
+        &self.secrets
+    }
+
+    /// Whether the peer at the other end of `session` has an allowed identity or knows a secret
+    pub fn admits(&self, session: &Session) -> bool {
+        self.identities.contains(&session.remote) || self.secrets.iter().any(|secret| session.proves_secret(secret))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_entries() {
+        let identity = [7u8; 32];
+        let contents = format!("# test network\n{}\n\nsecret:open sesame\n", hex::encode(identity));
+        let (identities, secrets) = parse(&contents).unwrap();
+        assert!(identities.contains(&identity));
+        assert_eq!(secrets, vec![b"open sesame".to_vec()]);
+
+        assert!(parse("abcd\n").is_err());
+        assert!(parse("not hex\n").is_err());
+    }
+
+    #[test]
+    fn reload_from_file() {
+        let path = std::env::temp_dir().join(format!("allowlist-{}", rand::random::<u64>()));
+        std::fs::write(&path, "secret:one\n").unwrap();
+        let mut allowlist = Allowlist::load(&path).unwrap();
+        assert_eq!(allowlist.counts(), (0, 1));
+
+        std::fs::write(&path, format!("{}\nsecret:two\nsecret:three\n", hex::encode([1u8; 32]))).unwrap();
+        allowlist.reload().unwrap();
+        assert_eq!(allowlist.counts(), (1, 2));
+
+        // a broken file leaves the list as it was
+        std::fs::write(&path, "garbage\n").unwrap();
+        assert!(allowlist.reload().is_err());
+        assert_eq!(allowlist.counts(), (1, 2));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,285 @@
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use log::{info, warn};
+use serde::{Serialize, Serializer};
+
+use super::secure::Identity;
+
+/// Peers whose misbehavior score reaches this are disconnected and banned
+pub const BAN_THRESHOLD: u32 = 100;
+/// How long it takes a misbehavior score to go down by a point
+pub const SCORE_DECAY: Duration = Duration::from_secs(60);
+
+/// The ways a peer can break the protocol, each with the score it adds
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Misbehavior {
+    /// A frame with a bad header or checksum
+    InvalidFrame,
+    /// Bytes that do not decode to a message
+    UndecodableMessage,
+    /// A message over the limits of its type, e.g. too many headers or addresses
+    OversizedMessage,
+    /// A handshake message after the handshake
+    UnexpectedMessage,
+    /// A request for data that cannot exist, e.g. a transaction index past the end of a block
+    InvalidRequest,
+    /// Headers that do not connect or have an invalid seal
+    InvalidHeaders,
+    /// A block with an invalid seal, signature or uncles
+    InvalidBlock,
+    /// A transaction with a bad signature
+    InvalidTransaction,
+}
+
+impl Misbehavior {
+    pub fn score(&self) -> u32 {
+        match self {
+            Misbehavior::InvalidFrame => 50,
+            Misbehavior::UndecodableMessage => 20,
+            Misbehavior::OversizedMessage => 20,
+            Misbehavior::UnexpectedMessage => 10,
+            Misbehavior::InvalidRequest => 20,
+            Misbehavior::InvalidHeaders => 20,
+            Misbehavior::InvalidBlock => 50,
+            Misbehavior::InvalidTransaction => 50,
+        }
+    }
+}
+
+impl fmt::Display for Misbehavior {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Misbehavior::InvalidFrame => "invalid frame",
+            Misbehavior::UndecodableMessage => "undecodable message",
+            Misbehavior::OversizedMessage => "oversized message",
+            Misbehavior::UnexpectedMessage => "unexpected message",
+            Misbehavior::InvalidRequest => "invalid request",
+            Misbehavior::InvalidHeaders => "invalid headers",
+            Misbehavior::InvalidBlock => "invalid block",
+            Misbehavior::InvalidTransaction => "invalid transaction",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// What a score or a ban applies to: the identity a peer proved on the encrypted transport, or
+/// else its IP address. Neither changes when the peer reconnects from another port.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum BanKey {
+    Identity(Identity),
+    Ip(IpAddr),
+}
+
+impl BanKey {
+    /// Key of the peer connected from `addr`, with `identity` if the connection is encrypted
+    pub fn of(addr: &SocketAddr, identity: Option<&Identity>) -> Self {
+        match identity {
+            Some(identity) => BanKey::Identity(*identity),
+            None => BanKey::Ip(addr.ip()),
+        }
+    }
+}
+
+impl fmt::Display for BanKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BanKey::Identity(identity) => write!(f, "{}", hex::encode(identity)),
+            BanKey::Ip(ip) => write!(f, "{}", ip),
+        }
+    }
+}
+
+impl FromStr for BanKey {
+    type Err = String;
+
+    /// Parse an IP address, or the hex of an identity key
+    fn from_str(s: &str) -> Result<Self, String> {
+        if let Ok(ip) = s.parse::<IpAddr>() {
+            return Ok(BanKey::Ip(ip));
+        }
+        let bytes = hex::decode(s).map_err(|e| format!("neither an IP address nor an identity: {}", e))?;
+        let identity = bytes.as_slice().try_into().map_err(|_| format!("identity of {} bytes, expected 32", bytes.len()))?;
+        Ok(BanKey::Identity(identity))
+    }
+}
+
+impl Serialize for BanKey {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Ban {
+    pub peer: BanKey,
+    /// The misbehavior that pushed the score over the threshold
+    pub reason: String,
+    /// Milliseconds since the UNIX epoch when the ban ends
+    pub until: u64,
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64
+}
+
+/// Misbehavior scores of peers and the peers banned for going over the threshold, keyed by
+/// `BanKey` so that reconnecting neither resets the score nor gets around the ban. Scores are kept
+/// after peers disconnect, and go down by a point every `SCORE_DECAY` so that rare slips of honest
+/// peers are forgiven.
+pub struct BanManager {
+    // scores with the time in milliseconds they last decayed at
+    scores: HashMap<BanKey, (u32, u64)>,
+    banned: HashMap<BanKey, Ban>,
+    ban_duration: Duration,
+}
+
+/// Score of a peer after decaying since `since`, and the time the decay got it to
+fn decayed(score: u32, since: u64, now: u64) -> (u32, u64) {
+    let step = SCORE_DECAY.as_millis() as u64;
+    let points = now.saturating_sub(since) / step;
+    (score.saturating_sub(points.min(u32::MAX as u64) as u32), since + points * step)
+}
+
+impl BanManager {
+    pub fn new(ban_duration: Duration) -> Self {
+        Self {
+            scores: HashMap::new(),
+            banned: HashMap::new(),
+            ban_duration,
+        }
+    }
+
+    /// Add the score of `misbehavior` to `peer`. Returns true if the peer is now banned and has
+    /// to be disconnected.
+    pub fn misbehaving(&mut self, peer: BanKey, misbehavior: Misbehavior) -> bool {
+        let now = now_millis();
+        self.decay(now);
+        let (score, _) = self.scores.entry(peer).or_insert((0, now));
+        *score += misbehavior.score();
+        warn!("Peer {} misbehaving ({}), score {}", peer, misbehavior, score);
+        if *score < BAN_THRESHOLD {
+            return false;
+        }
+
+        self.scores.remove(&peer);
+        let ban = Ban {
+            peer,
+            reason: misbehavior.to_string(),
+            until: now + self.ban_duration.as_millis() as u64,
+        };
+        self.banned.insert(peer, ban);
+        info!("Banned peer {} for {:?}", peer, self.ban_duration);
+        true
+    }
+
+    /// Misbehavior score of `peer`
+    pub fn score(&self, peer: &BanKey) -> u32 {
+        match self.scores.get(peer) {
+            Some((score, since)) => decayed(*score, *since, now_millis()).0,
+            None => 0,
+        }
+    }
+
+    pub fn is_banned(&mut self, peer: &BanKey) -> bool {
+        self.expire();
+        self.banned.contains_key(peer)
+    }
+
+    /// Lift the ban on `peer`, returns false if it was not banned
+    pub fn unban(&mut self, peer: &BanKey) -> bool {
+        self.banned.remove(peer).is_some()
+    }
+
+    /// The bans in effect, the one ending first first
+    pub fn bans(&mut self) -> Vec<Ban> {
+        self.expire();
+        let mut bans: Vec<Ban> = self.banned.values().cloned().collect();
+        bans.sort_by_key(|ban| (ban.until, ban.peer));
+        bans
+    }
+
+    fn expire(&mut self) {
+        let now = now_millis();
+        self.banned.retain(|_, ban| ban.until > now);
+    }
+
+    // forget the scores that decayed to nothing
+    fn decay(&mut self, now: u64) {
+        self.scores.retain(|_, (score, since)| {
+            let (decayed_score, decayed_since) = decayed(*score, *since, now);
+            *score = decayed_score;
+            *since = decayed_since;
+            *score > 0
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(last: u8) -> BanKey {
+        BanKey::Ip(IpAddr::from([10, 0, 0, last]))
+    }
+
+    #[test]
+    fn ban_over_threshold() {
+        let mut banman = BanManager::new(Duration::from_secs(60));
+        assert!(!banman.misbehaving(ip(1), Misbehavior::InvalidHeaders));
+        assert_eq!(banman.score(&ip(1)), Misbehavior::InvalidHeaders.score());
+        assert!(!banman.is_banned(&ip(1)));
+
+        assert!(!banman.misbehaving(ip(1), Misbehavior::InvalidBlock));
+        assert!(banman.misbehaving(ip(1), Misbehavior::InvalidTransaction));
+        assert!(banman.is_banned(&ip(1)));
+        assert!(!banman.is_banned(&ip(2)));
+        assert_eq!(banman.score(&ip(1)), 0);
+        assert_eq!(banman.bans().len(), 1);
+        assert_eq!(banman.bans()[0].reason, "invalid transaction");
+
+        assert!(banman.unban(&ip(1)));
+        assert!(!banman.is_banned(&ip(1)));
+    }
+
+    #[test]
+    fn bans_expire() {
+        let mut banman = BanManager::new(Duration::from_millis(0));
+        assert!(!banman.misbehaving(ip(1), Misbehavior::InvalidBlock));
+        assert!(banman.misbehaving(ip(1), Misbehavior::InvalidBlock));
+        assert!(!banman.is_banned(&ip(1)));
+        assert!(banman.bans().is_empty());
+    }
+
+    #[test]
+    fn scores_decay() {
+        let mut banman = BanManager::new(Duration::from_secs(60));
+        assert!(!banman.misbehaving(ip(1), Misbehavior::InvalidBlock));
+        assert!(!banman.misbehaving(ip(2), Misbehavior::InvalidBlock));
+
+        // ten points lost since the misbehavior
+        banman.scores.get_mut(&ip(1)).unwrap().1 -= 10 * SCORE_DECAY.as_millis() as u64;
+        assert_eq!(banman.score(&ip(1)), 40);
+        assert!(!banman.misbehaving(ip(1), Misbehavior::InvalidBlock));
+        assert!(banman.misbehaving(ip(1), Misbehavior::UnexpectedMessage));
+
+        // fully decayed scores are forgotten
+        banman.scores.get_mut(&ip(2)).unwrap().1 -= 60 * SCORE_DECAY.as_millis() as u64;
+        assert_eq!(banman.score(&ip(2)), 0);
+        assert!(!banman.misbehaving(ip(3), Misbehavior::UnexpectedMessage));
+        assert!(!banman.scores.contains_key(&ip(2)));
+    }
+
+    #[test]
+    fn parse_ban_keys() {
+        let identity = BanKey::Identity([7; 32]);
+        assert_eq!(identity.to_string().parse::<BanKey>(), Ok(identity));
+        assert_eq!("10.0.0.1".parse::<BanKey>(), Ok(ip(1)));
+        assert!("10.0.0.1:6000".parse::<BanKey>().is_err());
+        assert!("0707".parse::<BanKey>().is_err());
+    }
+}
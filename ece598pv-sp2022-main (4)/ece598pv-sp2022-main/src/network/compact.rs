@@ -0,0 +1,261 @@
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::time::{Duration, Instant};
+
+use log::debug;
+use ring::digest;
+use serde::{Deserialize, Serialize};
+
+use crate::types::block::{Block, Header};
+use crate::types::hash::{H256, Hashable};
+use crate::types::transaction::SignedTransaction;
+
+/// Most compact blocks kept waiting for their missing transactions
+pub const MAX_PENDING_BLOCKS: usize = 16;
+/// How long a compact block waits for its missing transactions before it is dropped, by then the
+/// request for the full block has been retried with another peer
+pub const PENDING_BLOCK_EXPIRY: Duration = Duration::from_secs(30);
+
+/// Short id of a transaction in a compact block: the first 8 bytes of the SHA256 of the block
+/// hash followed by the transaction hash. Salting with the block hash keeps a collision in one
+/// block from repeating in the next.
+pub fn short_id(block: &H256, tx: &H256) -> u64 {
+    let mut salted = Vec::with_capacity(64);
+    salted.extend_from_slice(block.as_ref());
+    salted.extend_from_slice(tx.as_ref());
+    u64::from_be_bytes(digest::digest(&digest::SHA256, &salted).as_ref()[..8].try_into().unwrap())
+}
+
+/// A block sent as its header and the short ids of its transactions, which the receiver looks up
+/// in its mempool
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CompactBlock {
+    pub length: u32,
+    pub header: Header,
+    pub short_ids: Vec<u64>,
+}
+
+impl Hashable for CompactBlock {
+    fn hash(&self) -> H256 {
+        self.header.hash()
+    }
+}
+
+impl CompactBlock {
+    pub fn from_block(block: &Block) -> Self {
+        let hash = block.hash();
+        Self {
+            length: block.length,
+            header: block.header.clone(),
+            short_ids: block.data.iter().map(|tx| short_id(&hash, &tx.hash())).collect(),
+        }
+    }
+
+    /// Fill in the transactions found in `mempool`. Ids matching several transactions of the
+    /// mempool are left missing, and fetched like the ones not found.
+    pub fn reconstruct(&self, mempool: &HashMap<H256, SignedTransaction>, now: Instant) -> PartialBlock {
+        let hash = self.hash();
+        let mut by_short_id: HashMap<u64, Option<&SignedTransaction>> = HashMap::new();
+        for (tx_hash, tx) in mempool.iter() {
+            by_short_id
+                .entry(short_id(&hash, tx_hash))
+                .and_modify(|found| *found = None)
+                .or_insert(Some(tx));
+        }
+        let data = self
+            .short_ids
+            .iter()
+            .map(|id| by_short_id.get(id).cloned().flatten().cloned())
+            .collect();
+        PartialBlock {
+            length: self.length,
+            header: self.header.clone(),
+            short_ids: self.short_ids.clone(),
+            data,
+            received: now,
+        }
+    }
+}
+
+/// Ask for the transactions of a block at the given positions
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BlockTransactionsRequest {
+    pub block: H256,
+    pub indexes: Vec<u32>,
+}
+
+/// The transactions of a block asked for in a `BlockTransactionsRequest`, in the same order
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BlockTransactions {
+    pub block: H256,
+    pub transactions: Vec<SignedTransaction>,
+}
+
+/// A compact block with the transactions found so far
+#[derive(Debug, Clone)]
+pub struct PartialBlock {
+    length: u32,
+    header: Header,
+    short_ids: Vec<u64>,
+    data: Vec<Option<SignedTransaction>>,
+    received: Instant,
+}
+
+impl PartialBlock {
+    pub fn hash(&self) -> H256 {
+        self.header.hash()
+    }
+
+    /// Positions of the transactions still missing
+    pub fn missing(&self) -> Vec<u32> {
+        self.data
+            .iter()
+            .enumerate()
+            .filter(|(_, tx)| tx.is_none())
+            .map(|(index, _)| index as u32)
+            .collect()
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.data.iter().all(|tx| tx.is_some())
+    }
+
+    /// Fill in the missing transactions, given in the order of `missing`. Fails if their number
+    /// does not match, or if a transaction does not have the short id of its position.
+    pub fn fill(&mut self, transactions: Vec<SignedTransaction>) -> Result<(), String> {
+        let missing = self.missing();
+        if missing.len() != transactions.len() {
+            return Err(format!("expected {} transactions, got {}", missing.len(), transactions.len()));
+        }
+        let hash = self.hash();
+        for (index, tx) in missing.iter().zip(transactions.iter()) {
+            if short_id(&hash, &tx.hash()) != self.short_ids[*index as usize] {
+                return Err(format!("transaction {:?} does not match the short id at {}", tx.hash(), index));
+            }
+        }
+        for (index, tx) in missing.into_iter().zip(transactions) {
+            self.data[index as usize] = Some(tx);
+        }
+        Ok(())
+    }
+
+    /// The full block, `None` while transactions are missing
+    pub fn block(&self) -> Option<Block> {
+        let data = self.data.iter().cloned().collect::<Option<Vec<_>>>()?;
+        Some(Block {
+            length: self.length,
+            header: self.header.clone(),
+            data,
+        })
+    }
+}
+
+/// Compact blocks waiting for the transactions asked from the peer that sent them
+pub struct PendingBlocks {
+    blocks: HashMap<H256, PartialBlock>,
+    capacity: usize,
+    expiry: Duration,
+}
+
+impl PendingBlocks {
+    pub fn new(capacity: usize, expiry: Duration) -> Self {
+        Self {
+            blocks: HashMap::new(),
+            capacity,
+            expiry,
+        }
+    }
+
+    pub fn contains(&self, hash: &H256) -> bool {
+        self.blocks.contains_key(hash)
+    }
+
+    /// Keep a block until its transactions arrive. Returns false if there is no room left, and
+    /// the full block has to be fetched instead.
+    pub fn insert(&mut self, block: PartialBlock, now: Instant) -> bool {
+        let expiry = self.expiry;
+        self.blocks.retain(|hash, pending| {
+            let expired = now.duration_since(pending.received) >= expiry;
+            if expired {
+                debug!("Compact block {:?} expired before its transactions arrived", hash);
+            }
+            !expired
+        });
+        if self.blocks.len() >= self.capacity {
+            return false;
+        }
+        self.blocks.insert(block.hash(), block);
+        true
+    }
+
+    pub fn remove(&mut self, hash: &H256) -> Option<PartialBlock> {
+        self.blocks.remove(hash)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::block::generate_random_block;
+    use crate::types::hash::generate_random_hash;
+    use crate::types::transaction::generate_random_transaction;
+
+    fn random_tx() -> SignedTransaction {
+        SignedTransaction {
+            transaction: generate_random_transaction(&Default::default()),
+            signature: vec![],
+            public_key: generate_random_hash().as_ref().to_vec(),
+        }
+    }
+
+    #[test]
+    fn reconstruct_from_mempool() {
+        let mut block = generate_random_block(&generate_random_hash());
+        block.data = (0..3).map(|_| random_tx()).collect();
+        let compact = CompactBlock::from_block(&block);
+        assert_eq!(compact.hash(), block.hash());
+        assert_eq!(compact.short_ids.len(), 3);
+
+        let mut mempool: HashMap<H256, SignedTransaction> = block.data.iter().map(|tx| (tx.hash(), tx.clone())).collect();
+        let other = random_tx();
+        mempool.insert(other.hash(), other);
+        let partial = compact.reconstruct(&mempool, Instant::now());
+        assert!(partial.is_complete());
+        let rebuilt = partial.block().unwrap();
+        let hashes: Vec<H256> = rebuilt.data.iter().map(|tx| tx.hash()).collect();
+        assert_eq!(hashes, block.data.iter().map(|tx| tx.hash()).collect::<Vec<H256>>());
+    }
+
+    #[test]
+    fn fill_missing_transactions() {
+        let mut block = generate_random_block(&generate_random_hash());
+        block.data = (0..3).map(|_| random_tx()).collect();
+        let compact = CompactBlock::from_block(&block);
+        let mempool: HashMap<H256, SignedTransaction> = std::iter::once((block.data[1].hash(), block.data[1].clone())).collect();
+        let mut partial = compact.reconstruct(&mempool, Instant::now());
+        assert_eq!(partial.missing(), vec![0, 2]);
+        assert!(partial.block().is_none());
+
+        assert!(partial.fill(vec![block.data[0].clone()]).is_err());
+        assert!(partial.fill(vec![block.data[0].clone(), random_tx()]).is_err());
+        assert!(partial.fill(vec![block.data[2].clone(), block.data[0].clone()]).is_err());
+        assert_eq!(partial.missing(), vec![0, 2]);
+        partial.fill(vec![block.data[0].clone(), block.data[2].clone()]).unwrap();
+        assert_eq!(partial.block().unwrap().data[2].hash(), block.data[2].hash());
+    }
+
+    #[test]
+    fn pending_blocks_cap_and_expiry() {
+        let mut pending = PendingBlocks::new(1, Duration::from_secs(30));
+        let now = Instant::now();
+        let first = CompactBlock::from_block(&generate_random_block(&generate_random_hash()));
+        let second = CompactBlock::from_block(&generate_random_block(&generate_random_hash()));
+        assert!(pending.insert(first.reconstruct(&HashMap::new(), now), now));
+        assert!(!pending.insert(second.reconstruct(&HashMap::new(), now), now));
+
+        let later = now + Duration::from_secs(31);
+        assert!(pending.insert(second.reconstruct(&HashMap::new(), later), later));
+        assert!(!pending.contains(&first.hash()));
+        assert!(pending.remove(&second.hash()).is_some());
+    }
+}
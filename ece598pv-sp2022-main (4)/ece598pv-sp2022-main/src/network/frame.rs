@@ -0,0 +1,207 @@
+use std::convert::TryInto;
+use std::io;
+
+use futures::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use ring::digest;
+
+use super::message::Message;
+use crate::types::hash::H256;
+
+/// Version of the frame layout below
+pub const FRAME_VERSION: u8 = 1;
+/// Size of the frame header: magic (4 bytes), frame version (1), message type (1), payload length
+/// (4, big endian) and checksum (4)
+pub const HEADER_SIZE: usize = 14;
+
+/// First bytes of every frame, different for every network so that nodes of different test
+/// networks never talk to each other
+pub type Magic = [u8; 4];
+
+/// Network magic of the network with the given genesis block
+pub fn network_magic(genesis: &H256) -> Magic {
+    genesis.as_ref()[..4].try_into().unwrap()
+}
+
+/// First four bytes of the SHA256 of the payload
+pub fn checksum(payload: &[u8]) -> [u8; 4] {
+    digest::digest(&digest::SHA256, payload).as_ref()[..4].try_into().unwrap()
+}
+
+/// Type tag of a message in the frame header, one per `Message` variant
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessageType {
+    Ping = 0,
+    Pong = 1,
+    NewBlockHashes = 2,
+    GetBlocks = 3,
+    Blocks = 4,
+    NewTransactionHashes = 5,
+    GetTransactions = 6,
+    Transactions = 7,
+    Version = 8,
+    VerAck = 9,
+    GetAddr = 10,
+    Addr = 11,
+    GetHeaders = 12,
+    Headers = 13,
+    CompactBlock = 14,
+    GetBlockTransactions = 15,
+    BlockTransactions = 16,
+}
+
+impl MessageType {
+    pub fn from_u8(tag: u8) -> Option<Self> {
+        use MessageType::*;
+        let kind = match tag {
+            0 => Ping,
+            1 => Pong,
+            2 => NewBlockHashes,
+            3 => GetBlocks,
+            4 => Blocks,
+            5 => NewTransactionHashes,
+            6 => GetTransactions,
+            7 => Transactions,
+            8 => Version,
+            9 => VerAck,
+            10 => GetAddr,
+            11 => Addr,
+            12 => GetHeaders,
+            13 => Headers,
+            14 => CompactBlock,
+            15 => GetBlockTransactions,
+            16 => BlockTransactions,
+            _ => return None,
+        };
+        Some(kind)
+    }
+
+    /// Largest payload accepted for a message of this type, in bytes
+    pub fn max_size(&self) -> u32 {
+        use MessageType::*;
+        match self {
+            VerAck | GetAddr => 64,
+            Ping | Pong => 1024,
+            Version => 4096,
+            Addr => 64 * 1024,
+            NewBlockHashes | GetBlocks | NewTransactionHashes | GetTransactions | GetHeaders | CompactBlock
+            | GetBlockTransactions => 2 * 1024 * 1024,
+            Headers => 8 * 1024 * 1024,
+            Blocks | Transactions | BlockTransactions => 32 * 1024 * 1024,
+        }
+    }
+}
+
+impl From<&Message> for MessageType {
+    fn from(msg: &Message) -> Self {
+        match msg {
+            Message::Ping(_) => MessageType::Ping,
+            Message::Pong(_) => MessageType::Pong,
+            Message::NewBlockHashes(_) => MessageType::NewBlockHashes,
+            Message::GetBlocks(_) => MessageType::GetBlocks,
+            Message::Blocks(_) => MessageType::Blocks,
+            Message::NewTransactionHashes(_) => MessageType::NewTransactionHashes,
+            Message::GetTransactions(_) => MessageType::GetTransactions,
+            Message::Transactions(_) => MessageType::Transactions,
+            Message::Version(_) => MessageType::Version,
+            Message::VerAck => MessageType::VerAck,
+            Message::GetAddr => MessageType::GetAddr,
+            Message::Addr(_) => MessageType::Addr,
+            Message::GetHeaders(_) => MessageType::GetHeaders,
+            Message::Headers(_) => MessageType::Headers,
+            Message::CompactBlock(_) => MessageType::CompactBlock,
+            Message::GetBlockTransactions(_) => MessageType::GetBlockTransactions,
+            Message::BlockTransactions(_) => MessageType::BlockTransactions,
+        }
+    }
+}
+
+/// Encode `msg` into a frame: the header followed by the bincode payload
+pub fn encode(magic: &Magic, msg: &Message) -> Vec<u8> {
+    let payload = bincode::serialize(msg).unwrap();
+    let mut frame = Vec::with_capacity(HEADER_SIZE + payload.len());
+    frame.extend_from_slice(magic);
+    frame.push(FRAME_VERSION);
+    frame.push(MessageType::from(msg) as u8);
+    frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    frame.extend_from_slice(&checksum(&payload));
+    frame.extend_from_slice(&payload);
+    frame
+}
+
+fn invalid_data(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Read a single frame and check its header, returning the message type and the payload. A frame
+/// of another network, an unknown version or type, a payload over the limit of its type or a bad
+/// checksum all fail with `InvalidData`, before the payload is read when possible.
+pub async fn read_frame<S: AsyncRead + Unpin>(stream: &mut S, magic: &Magic) -> io::Result<(MessageType, Vec<u8>)> {
+    let mut header = [0u8; HEADER_SIZE];
+    stream.read_exact(&mut header).await?;
+    if header[0..4] != magic[..] {
+        return Err(invalid_data(format!("wrong network magic {:02x?}", &header[0..4])));
+    }
+    if header[4] != FRAME_VERSION {
+        return Err(invalid_data(format!("unknown frame version {}", header[4])));
+    }
+    let kind = MessageType::from_u8(header[5]).ok_or_else(|| invalid_data(format!("unknown message type {}", header[5])))?;
+    let length = u32::from_be_bytes(header[6..10].try_into().unwrap());
+    if length > kind.max_size() {
+        return Err(invalid_data(format!("{:?} message of {} bytes is too large", kind, length)));
+    }
+
+    let mut payload = vec![0; length as usize];
+    stream.read_exact(&mut payload).await?;
+    if header[10..14] != checksum(&payload) {
+        return Err(invalid_data(format!("bad checksum of {:?} message", kind)));
+    }
+    Ok((kind, payload))
+}
+
+/// Write a single message as a frame
+pub async fn write_frame<S: AsyncWrite + Unpin>(stream: &mut S, magic: &Magic, msg: &Message) -> io::Result<()> {
+    stream.write_all(&encode(magic, msg)).await?;
+    stream.flush().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAGIC: Magic = [1, 2, 3, 4];
+
+    fn read(bytes: &[u8], magic: &Magic) -> io::Result<(MessageType, Vec<u8>)> {
+        smol::block_on(read_frame(&mut futures::io::Cursor::new(bytes), magic))
+    }
+
+    #[test]
+    fn round_trip() {
+        let frame = encode(&MAGIC, &Message::Ping(42));
+        let (kind, payload) = read(&frame, &MAGIC).unwrap();
+        assert_eq!(kind, MessageType::Ping);
+        match bincode::deserialize(&payload).unwrap() {
+            Message::Ping(nonce) => assert_eq!(nonce, 42),
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn reject_bad_frames() {
+        let frame = encode(&MAGIC, &Message::Ping(42));
+        assert!(read(&frame, &[4, 3, 2, 1]).is_err());
+
+        let mut bad_checksum = frame.clone();
+        *bad_checksum.last_mut().unwrap() ^= 1;
+        assert!(read(&bad_checksum, &MAGIC).is_err());
+
+        let mut bad_type = frame.clone();
+        bad_type[5] = 200;
+        assert!(read(&bad_type, &MAGIC).is_err());
+
+        // a huge length is refused without trying to read or allocate the payload
+        let mut oversized = frame[..HEADER_SIZE].to_vec();
+        oversized[6..10].copy_from_slice(&u32::MAX.to_be_bytes());
+        let err = read(&oversized, &MAGIC).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,104 @@
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use super::peer;
+
+/// Default most peers that connected to us
+pub const DEFAULT_MAX_INBOUND: usize = 32;
+/// Default most peers we connected to, persistent peers included
+pub const DEFAULT_MAX_OUTBOUND: usize = 16;
+/// Inbound peers with the lowest ping that are never evicted
+const PROTECTED_BY_PING: usize = 4;
+/// Inbound peers that most recently sent a new block that are never evicted
+const PROTECTED_BY_BLOCKS: usize = 4;
+
+/// How many connections the server keeps in each direction
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConnectionLimits {
+    pub max_inbound: usize,
+    pub max_outbound: usize,
+}
+
+impl Default for ConnectionLimits {
+    fn default() -> Self {
+        Self {
+            max_inbound: DEFAULT_MAX_INBOUND,
+            max_outbound: DEFAULT_MAX_OUTBOUND,
+        }
+    }
+}
+
+/// What the eviction looks at to tell how useful an inbound peer is
+#[derive(Debug, Clone)]
+pub struct EvictionCandidate {
+    pub addr: SocketAddr,
+    pub connected: Instant,
+    pub min_rtt: Option<Duration>,
+    pub last_block: Option<Instant>,
+}
+
+impl EvictionCandidate {
+    pub fn from_peer(peer: &peer::Handle) -> Self {
+        Self {
+            addr: *peer.addr(),
+            connected: peer.connected(),
+            min_rtt: peer.ping_stats().min_rtt_ms.map(|ms| Duration::from_secs_f64(ms / 1000.0)),
+            last_block: peer.last_block(),
+        }
+    }
+}
+
+/// Pick the inbound peer to drop to make room for a new one. The peers with the lowest ping and
+/// the ones that most recently sent us a new block are kept, as an attacker cannot easily fake
+/// either, and the youngest connection of the others goes. `None` if every peer is protected.
+pub fn select_eviction(mut candidates: Vec<EvictionCandidate>) -> Option<SocketAddr> {
+    // peers that never answered a ping or sent a block have earned no protection
+    candidates.sort_by_key(|candidate| candidate.min_rtt.unwrap_or(Duration::MAX));
+    let answered_pings = candidates.iter().filter(|candidate| candidate.min_rtt.is_some()).count();
+    candidates.drain(..PROTECTED_BY_PING.min(answered_pings));
+
+    candidates.sort_by_key(|candidate| std::cmp::Reverse(candidate.last_block));
+    let sent_blocks = candidates.iter().filter(|candidate| candidate.last_block.is_some()).count();
+    candidates.drain(..PROTECTED_BY_BLOCKS.min(sent_blocks));
+
+    candidates
+        .into_iter()
+        .max_by_key(|candidate| candidate.connected)
+        .map(|candidate| candidate.addr)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn candidate(port: u16, age_secs: u64, min_rtt_ms: Option<u64>, block_secs_ago: Option<u64>, now: Instant) -> EvictionCandidate {
+        EvictionCandidate {
+            addr: SocketAddr::from(([127, 0, 0, 1], port)),
+            connected: now - Duration::from_secs(age_secs),
+            min_rtt: min_rtt_ms.map(Duration::from_millis),
+            last_block: block_secs_ago.map(|secs| now - Duration::from_secs(secs)),
+        }
+    }
+
+    #[test]
+    fn evict_youngest_unprotected_peer() {
+        let now = Instant::now() + Duration::from_secs(1000);
+        let mut candidates = Vec::new();
+        // fast peers, protected whatever their age
+        for port in 0..4 {
+            candidates.push(candidate(6000 + port, 1, Some(1), None, now));
+        }
+        // peers that sent blocks, protected too
+        for port in 0..4 {
+            candidates.push(candidate(6100 + port, 2, Some(100), Some(10), now));
+        }
+        candidates.push(candidate(6200, 50, Some(100), None, now));
+        candidates.push(candidate(6201, 5, None, None, now));
+        assert_eq!(select_eviction(candidates.clone()), Some(SocketAddr::from(([127, 0, 0, 1], 6201))));
+
+        candidates.pop();
+        assert_eq!(select_eviction(candidates.clone()), Some(SocketAddr::from(([127, 0, 0, 1], 6200))));
+        candidates.pop();
+        assert_eq!(select_eviction(candidates), None);
+    }
+}
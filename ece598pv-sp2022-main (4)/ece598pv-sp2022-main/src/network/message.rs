@@ -1,15 +1,55 @@
 use serde::{Serialize, Deserialize};
 
-use crate::types::{hash::H256, block::Block, transaction::SignedTransaction};
+use super::compact::{BlockTransactions, BlockTransactionsRequest, CompactBlock};
+use crate::types::{hash::H256, block::{Block, Header}, transaction::SignedTransaction};
+
+/// Version of the P2P protocol this node speaks
+pub const PROTOCOL_VERSION: u32 = 1;
+/// Oldest protocol version this node can still talk to
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+/// Service bit of nodes that keep the full blockchain and can serve blocks
+pub const SERVICE_FULL_NODE: u64 = 1;
+/// Most addresses sent in one `Addr` message
+pub const MAX_ADDR_PER_MESSAGE: usize = 1000;
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum Message {
-    Ping(String),
-    Pong(String),
+    /// Keepalive carrying a nonce the peer sends back in its `Pong`
+    Ping(u64),
+    Pong(u64),
     NewBlockHashes(Vec<H256>),
     GetBlocks(Vec<H256>),
     Blocks(Vec<Block>),
     NewTransactionHashes(Vec<H256>),
     GetTransactions(Vec<H256>),
     Transactions(Vec<SignedTransaction>),
+    Version(Version),
+    VerAck,
+    GetAddr,
+    Addr(Vec<std::net::SocketAddr>),
+    /// Ask for the headers of the blocks after the first hash of the block locator the peer has
+    GetHeaders(Vec<H256>),
+    Headers(Vec<Header>),
+    /// A new block as its header and the short ids of its transactions
+    CompactBlock(CompactBlock),
+    /// Ask for the transactions of a compact block missing from the mempool
+    GetBlockTransactions(BlockTransactionsRequest),
+    BlockTransactions(BlockTransactions),
+}
+
+/// What a node tells a new peer about itself in the handshake
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Version {
+    pub version: u32,
+    pub genesis: H256,
+    pub tip: H256,
+    pub height: u32,
+    /// Random id of the node, to detect connections to itself
+    pub node_id: u64,
+    /// Bit set of `SERVICE_*` flags
+    pub services: u64,
+    /// Milliseconds since the UNIX epoch, when the message was made
+    pub timestamp: u128,
+    /// Address the node accepts connections at
+    pub listen_addr: Option<std::net::SocketAddr>,
 }
@@ -1,4 +1,20 @@
+pub mod addrman;
+pub mod allowlist;
+pub mod banman;
+pub mod compact;
+pub mod frame;
+pub mod limits;
 pub mod message;
+pub mod orphan;
+pub mod outbound;
 pub mod peer;
+pub mod ping;
+pub mod queue;
+pub mod relay;
+pub mod request;
+pub mod secure;
 pub mod server;
+pub mod sync;
+pub mod trace;
+pub mod transport;
 pub mod worker;
@@ -0,0 +1,204 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use log::debug;
+
+use crate::types::block::Block;
+use crate::types::hash::{H256, Hashable};
+
+/// Most blocks kept waiting for their parents
+pub const MAX_ORPHANS: usize = 1000;
+/// Most bytes of blocks kept waiting for their parents, two of the largest blocks a peer can send
+pub const MAX_ORPHAN_BYTES: usize = 64 * 1024 * 1024;
+/// How long a block waits for its parent before it is dropped
+pub const ORPHAN_EXPIRY: Duration = Duration::from_secs(20 * 60);
+
+struct Orphan {
+    block: Block,
+    added: Instant,
+    size: usize,
+}
+
+/// Blocks whose parent is not in the blockchain yet, indexed by the hash of the parent so that
+/// all the children of a block can be found when it connects. Bounded in blocks and in bytes, the
+/// oldest blocks are dropped when a new one does not fit, and blocks expire after a while.
+pub struct OrphanPool {
+    orphans: HashMap<H256, Orphan>,
+    // parent hash -> hashes of the orphans waiting for it
+    by_parent: HashMap<H256, Vec<H256>>,
+    capacity: usize,
+    max_bytes: usize,
+    // serialized size of the orphans in the pool
+    bytes: usize,
+    expiry: Duration,
+}
+
+impl OrphanPool {
+    pub fn new(capacity: usize, max_bytes: usize, expiry: Duration) -> Self {
+        Self {
+            orphans: HashMap::new(),
+            by_parent: HashMap::new(),
+            capacity,
+            max_bytes,
+            bytes: 0,
+            expiry,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.orphans.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.orphans.is_empty()
+    }
+
+    pub fn contains(&self, hash: &H256) -> bool {
+        self.orphans.contains_key(hash)
+    }
+
+    /// The first ancestor of `hash` that is not in the pool, what a chain of orphans waits for
+    pub fn missing_ancestor(&self, hash: &H256) -> H256 {
+        let mut ancestor = *hash;
+        while let Some(orphan) = self.orphans.get(&ancestor) {
+            ancestor = orphan.block.get_parent();
+        }
+        ancestor
+    }
+
+    /// Add a block waiting for its parent. Returns false if it is in the pool already, or too
+    /// large for it.
+    pub fn insert(&mut self, block: Block, now: Instant) -> bool {
+        let hash = block.hash();
+        if self.orphans.contains_key(&hash) {
+            return false;
+        }
+        let size = bincode::serialized_size(&block).unwrap() as usize;
+        if size > self.max_bytes {
+            debug!("Orphan block {:?} of {} bytes does not fit in the pool", hash, size);
+            return false;
+        }
+        self.expire(now);
+        while self.orphans.len() >= self.capacity || self.bytes + size > self.max_bytes {
+            let oldest = self.orphans.iter().min_by_key(|(_, orphan)| orphan.added).map(|(hash, _)| *hash);
+            match oldest {
+                Some(oldest) => {
+                    debug!("Orphan pool full, dropping block {:?}", oldest);
+                    self.remove(&oldest);
+                }
+                None => break,
+            }
+        }
+        self.by_parent.entry(block.get_parent()).or_default().push(hash);
+        self.orphans.insert(hash, Orphan { block, added: now, size });
+        self.bytes += size;
+        true
+    }
+
+    /// Remove and return the blocks waiting for `parent`
+    pub fn take_children(&mut self, parent: &H256) -> Vec<Block> {
+        let hashes = self.by_parent.remove(parent).unwrap_or_default();
+        let children: Vec<Orphan> = hashes.iter().filter_map(|hash| self.orphans.remove(hash)).collect();
+        self.bytes -= children.iter().map(|orphan| orphan.size).sum::<usize>();
+        children.into_iter().map(|orphan| orphan.block).collect()
+    }
+
+    /// Drop the blocks that waited for their parent for too long
+    pub fn expire(&mut self, now: Instant) {
+        let expiry = self.expiry;
+        let expired: Vec<H256> = self
+            .orphans
+            .iter()
+            .filter(|(_, orphan)| now.duration_since(orphan.added) >= expiry)
+            .map(|(hash, _)| *hash)
+            .collect();
+        for hash in expired {
+            debug!("Orphan block {:?} expired", hash);
+            self.remove(&hash);
+        }
+    }
+
+    fn remove(&mut self, hash: &H256) {
+        let orphan = match self.orphans.remove(hash) {
+            Some(orphan) => orphan,
+            None => return,
+        };
+        self.bytes -= orphan.size;
+        let parent = orphan.block.get_parent();
+        if let Some(siblings) = self.by_parent.get_mut(&parent) {
+            siblings.retain(|sibling| sibling != hash);
+            if siblings.is_empty() {
+                self.by_parent.remove(&parent);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::block::generate_random_block;
+
+    #[test]
+    fn children_by_parent() {
+        let mut pool = OrphanPool::new(10, MAX_ORPHAN_BYTES, ORPHAN_EXPIRY);
+        let parent: H256 = [1; 32].into();
+        let first = generate_random_block(&parent);
+        let second = generate_random_block(&parent);
+        let grandchild = generate_random_block(&first.hash());
+        let now = Instant::now();
+        assert!(pool.insert(first.clone(), now));
+        assert!(!pool.insert(first.clone(), now));
+        pool.insert(second.clone(), now);
+        pool.insert(grandchild.clone(), now);
+        assert_eq!(pool.len(), 3);
+        assert_eq!(pool.missing_ancestor(&grandchild.hash()), parent);
+
+        let mut children: Vec<H256> = pool.take_children(&parent).iter().map(|block| block.hash()).collect();
+        children.sort();
+        let mut expected = vec![first.hash(), second.hash()];
+        expected.sort();
+        assert_eq!(children, expected);
+        assert!(pool.take_children(&parent).is_empty());
+        assert_eq!(pool.take_children(&first.hash())[0].hash(), grandchild.hash());
+        assert!(pool.is_empty());
+    }
+
+    #[test]
+    fn cap_and_expiry() {
+        let mut pool = OrphanPool::new(2, MAX_ORPHAN_BYTES, Duration::from_secs(60));
+        let now = Instant::now();
+        let blocks: Vec<Block> = (0..3u8).map(|i| generate_random_block(&[i; 32].into())).collect();
+        pool.insert(blocks[0].clone(), now);
+        pool.insert(blocks[1].clone(), now + Duration::from_secs(1));
+        pool.insert(blocks[2].clone(), now + Duration::from_secs(2));
+        assert_eq!(pool.len(), 2);
+        assert!(!pool.contains(&blocks[0].hash()));
+        assert!(pool.take_children(&[0; 32].into()).is_empty());
+
+        pool.expire(now + Duration::from_secs(61));
+        assert!(!pool.contains(&blocks[1].hash()));
+        assert!(pool.contains(&blocks[2].hash()));
+    }
+
+    #[test]
+    fn cap_bytes() {
+        let size = bincode::serialized_size(&generate_random_block(&[0; 32].into())).unwrap() as usize;
+        let mut pool = OrphanPool::new(10, 2 * size, ORPHAN_EXPIRY);
+        let now = Instant::now();
+        let blocks: Vec<Block> = (0..3u8).map(|i| generate_random_block(&[i; 32].into())).collect();
+        pool.insert(blocks[0].clone(), now);
+        pool.insert(blocks[1].clone(), now + Duration::from_secs(1));
+        pool.insert(blocks[2].clone(), now + Duration::from_secs(2));
+        assert_eq!(pool.len(), 2);
+        assert!(!pool.contains(&blocks[0].hash()));
+
+        // a block larger than the whole pool never gets in
+        let mut large = generate_random_block(&[3; 32].into());
+        large.data = vec![Default::default(); 10];
+        assert!(!pool.insert(large, now));
+        assert_eq!(pool.len(), 2);
+        assert_eq!(pool.take_children(&[1; 32].into()).len(), 1);
+        assert_eq!(pool.bytes, size);
+    }
+}
@@ -0,0 +1,263 @@
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::{debug, info, warn};
+use rand::Rng;
+use serde::Serialize;
+
+use super::addrman::AddrManager;
+use super::server::Handle as ServerHandle;
+
+/// How often the outbound connections are checked
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+/// Wait before redialing a peer after its first failure, doubled for every further failure
+const BASE_BACKOFF: Duration = Duration::from_secs(1);
+/// Longest wait between two dials of the same peer
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// Connection state of an outbound peer, as reported by the API
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ConnectionState {
+    Connected,
+    Disconnected,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct PeerStatus {
+    pub addr: SocketAddr,
+    /// Whether the peer was given with `--connect` and is always redialed
+    pub persistent: bool,
+    pub state: ConnectionState,
+    /// Failed dials since the last successful one
+    pub failures: u32,
+    /// Time until the next dial, if disconnected
+    pub retry_in_ms: Option<u64>,
+}
+
+/// How long to wait before the next dial after `failures` failed ones, with up to 25% jitter
+/// either way so that nodes that lost a peer at the same time do not redial it in lockstep
+fn backoff_delay(failures: u32) -> Duration {
+    let exponent = failures.saturating_sub(1).min(16);
+    let delay = (BASE_BACKOFF * 2u32.pow(exponent)).min(MAX_BACKOFF);
+    delay.mul_f64(rand::thread_rng().gen_range(0.75..1.25))
+}
+
+struct Backoff {
+    failures: u32,
+    next_attempt: Instant,
+}
+
+/// Outbound peers and their redial schedules, shared with the API
+#[derive(Default)]
+struct Dialer {
+    persistent: HashSet<SocketAddr>,
+    // peers this manager connected to
+    outbound: HashSet<SocketAddr>,
+    // peers that failed to connect, and when to try them again
+    backoff: HashMap<SocketAddr, Backoff>,
+    // peers that are connected, by socket and listening address
+    connected: HashSet<SocketAddr>,
+}
+
+impl Dialer {
+    fn due(&self, addr: &SocketAddr, now: Instant) -> bool {
+        self.backoff.get(addr).is_none_or(|backoff| backoff.next_attempt <= now)
+    }
+
+    fn on_connected(&mut self, addr: SocketAddr) {
+        self.backoff.remove(&addr);
+        self.outbound.insert(addr);
+        self.connected.insert(addr);
+    }
+
+    fn on_failed(&mut self, addr: SocketAddr, now: Instant) -> Duration {
+        let backoff = self.backoff.entry(addr).or_insert(Backoff { failures: 0, next_attempt: now });
+        backoff.failures += 1;
+        let delay = backoff_delay(backoff.failures);
+        backoff.next_attempt = now + delay;
+        delay
+    }
+}
+
+/// Keeps a target number of outbound connections open, picking peers from the address manager,
+/// and redials the persistent peers whenever they drop, with exponential backoff. It also records
+/// the listening address of every connected peer in the address manager.
+pub struct Context {
+    server: ServerHandle,
+    addrman: Arc<Mutex<AddrManager>>,
+    target: usize,
+    // our own listening address, never to be dialed
+    local_addr: SocketAddr,
+    dialer: Arc<Mutex<Dialer>>,
+}
+
+#[derive(Clone)]
+pub struct Handle {
+    dialer: Arc<Mutex<Dialer>>,
+}
+
+pub fn new(
+    server: &ServerHandle,
+    addrman: &Arc<Mutex<AddrManager>>,
+    target: usize,
+    local_addr: SocketAddr,
+    persistent: Vec<SocketAddr>,
+) -> (Context, Handle) {
+    let dialer = Dialer {
+        persistent: persistent.into_iter().collect(),
+        ..Default::default()
+    };
+    let dialer = Arc::new(Mutex::new(dialer));
+    let ctx = Context {
+        server: server.clone(),
+        addrman: Arc::clone(addrman),
+        target,
+        local_addr,
+        dialer: Arc::clone(&dialer),
+    };
+    (ctx, Handle { dialer })
+}
+
+impl Handle {
+    /// Connection state of the persistent peers and the peers dialed to reach the target
+    pub fn status(&self) -> Vec<PeerStatus> {
+        let dialer = self.dialer.lock().unwrap();
+        let now = Instant::now();
+        let mut addrs: Vec<&SocketAddr> = dialer.persistent.union(&dialer.outbound).collect();
+        addrs.sort();
+        addrs
+            .into_iter()
+            .map(|addr| {
+                let connected = dialer.connected.contains(addr);
+                let backoff = dialer.backoff.get(addr);
+                PeerStatus {
+                    addr: *addr,
+                    persistent: dialer.persistent.contains(addr),
+                    state: if connected { ConnectionState::Connected } else { ConnectionState::Disconnected },
+                    failures: backoff.map_or(0, |backoff| backoff.failures),
+                    retry_in_ms: match backoff {
+                        Some(backoff) if !connected => {
+                            Some(backoff.next_attempt.saturating_duration_since(now).as_millis() as u64)
+                        }
+                        _ => None,
+                    },
+                }
+            })
+            .collect()
+    }
+}
+
+impl Context {
+    pub fn start(self) {
+        info!("Keeping {} outbound connections", self.target);
+        thread::Builder::new()
+            .name("outbound".to_string())
+            .spawn(move || loop {
+                self.check();
+                thread::sleep(CHECK_INTERVAL);
+            })
+            .unwrap();
+    }
+
+    fn check(&self) {
+        let peers = self.server.peers();
+        let mut connected: HashSet<SocketAddr> = peers.iter().map(|peer| *peer.addr()).collect();
+        connected.extend(peers.iter().filter_map(|peer| peer.listen_addr()));
+        let now = Instant::now();
+
+        let mut addrman = self.addrman.lock().unwrap();
+        for peer in peers.iter() {
+            if let Some(addr) = peer.listen_addr() {
+                addrman.mark_seen(addr);
+            }
+        }
+        if let Err(e) = addrman.save() {
+            warn!("Error saving peer addresses: {}", e);
+        }
+
+        let to_dial = {
+            let mut dialer = self.dialer.lock().unwrap();
+            let dropped: Vec<SocketAddr> = dialer.outbound.iter().filter(|addr| !connected.contains(addr)).cloned().collect();
+            for addr in dropped {
+                dialer.outbound.remove(&addr);
+                if dialer.persistent.contains(&addr) {
+                    info!("Lost connection to persistent peer {}, redialing", addr);
+                }
+            }
+            dialer.connected = connected.clone();
+
+            let mut to_dial: Vec<SocketAddr> = dialer
+                .persistent
+                .iter()
+                .filter(|addr| !connected.contains(addr) && dialer.due(addr, now))
+                .cloned()
+                .collect();
+
+            let outbound = dialer.outbound.iter().filter(|addr| !dialer.persistent.contains(addr)).count();
+            let mut exclude = connected.clone();
+            exclude.insert(self.local_addr);
+            exclude.extend(dialer.persistent.iter().cloned());
+            exclude.extend(dialer.backoff.keys().filter(|addr| !dialer.due(addr, now)).cloned());
+            to_dial.extend(addrman.candidates(&exclude, self.target.saturating_sub(outbound)));
+            to_dial
+        };
+        drop(addrman);
+
+        for addr in to_dial {
+            match self.server.connect(addr) {
+                Ok(_) => {
+                    info!("Connected to outgoing peer {}", addr);
+                    self.dialer.lock().unwrap().on_connected(addr);
+                    self.addrman.lock().unwrap().mark_seen(addr);
+                }
+                Err(e) => {
+                    let mut dialer = self.dialer.lock().unwrap();
+                    let delay = dialer.on_failed(addr, Instant::now());
+                    if dialer.persistent.contains(&addr) {
+                        warn!("Error connecting to peer {}, retrying in {:?}: {}", addr, delay, e);
+                    } else {
+                        debug!("Error connecting to peer {}: {}", addr, e);
+                    }
+                    drop(dialer);
+                    self.addrman.lock().unwrap().mark_failed(addr);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exponential_backoff_with_jitter() {
+        for failures in 1..10 {
+            let delay = backoff_delay(failures);
+            let expected = (BASE_BACKOFF * 2u32.pow(failures - 1)).min(MAX_BACKOFF);
+            assert!(delay >= expected.mul_f64(0.75) && delay <= expected.mul_f64(1.25));
+        }
+        assert!(backoff_delay(100) <= MAX_BACKOFF.mul_f64(1.25));
+    }
+
+    #[test]
+    fn dial_again_after_backoff() {
+        let mut dialer = Dialer::default();
+        let addr = SocketAddr::from(([127, 0, 0, 1], 6001));
+        let now = Instant::now();
+        assert!(dialer.due(&addr, now));
+
+        let first = dialer.on_failed(addr, now);
+        assert!(!dialer.due(&addr, now));
+        assert!(dialer.due(&addr, now + first));
+        let second = dialer.on_failed(addr, now);
+        assert!(second > first);
+
+        dialer.on_connected(addr);
+        assert!(dialer.due(&addr, now));
+    }
+}
@@ -1,21 +1,88 @@
-use super::message::Message;
-use futures::{channel::mpsc, sink::SinkExt};
-use log::trace;
-use smol::Async;
+use super::banman::BanKey;
+use super::message::{Message, Version};
+use super::ping::{PingAction, PingState, PingStats};
+use super::secure::Identity;
+use super::queue::{Push, QueueStats, WriteQueue, WRITE_QUEUE_CAPACITY};
+use crate::types::hash::{H256, Hashable};
+use log::{trace, warn};
+use serde::Serialize;
+use std::collections::{HashSet, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
+/// Most block and transaction hashes remembered per peer, the oldest are forgotten first
+pub const MAX_KNOWN_INVENTORY: usize = 10000;
+
+/// Create the handle of the peer at `addr` that completed the handshake and sent `version`, with
+/// the identity it proved if the connection is encrypted
 pub fn new(
-    stream: &Async<std::net::TcpStream>,
-) -> std::io::Result<(mpsc::UnboundedReceiver<Vec<u8>>, Handle)> {
-    let (write_sender, write_receiver) = mpsc::unbounded();
-    let addr = stream.get_ref().peer_addr()?;
+    addr: std::net::SocketAddr,
+    version: Version,
+    identity: Option<Identity>,
+    direction: Direction,
+) -> (Arc<WriteQueue>, Handle) {
+    let write_queue = Arc::new(WriteQueue::new(WRITE_QUEUE_CAPACITY));
     let handle = Handle {
-        write_queue: write_sender,
+        write_queue: Arc::clone(&write_queue),
         addr,
+        version: Arc::new(version),
+        known_inventory: Arc::new(Mutex::new(KnownInventory::new(MAX_KNOWN_INVENTORY))),
+        ping: Arc::new(Mutex::new(PingState::new())),
+        identity,
+        direction,
+        connected: Instant::now(),
+        last_block: Arc::new(Mutex::new(None)),
     };
-    Ok((write_receiver, handle))
+    (write_queue, handle)
+}
+
+/// Hashes of the blocks and transactions a peer is known to have, because it sent them, announced
+/// them, or we did. Bounded, the oldest hash is forgotten when a new one does not fit.
+#[derive(Debug)]
+pub struct KnownInventory {
+    hashes: HashSet<H256>,
+    order: VecDeque<H256>,
+    capacity: usize,
+}
+
+impl KnownInventory {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            hashes: HashSet::new(),
+            order: VecDeque::new(),
+            capacity,
+        }
+    }
+
+    /// Remember `hash`, returns false if it was known already
+    pub fn insert(&mut self, hash: H256) -> bool {
+        if !self.hashes.insert(hash) {
+            return false;
+        }
+        self.order.push_back(hash);
+        if self.order.len() > self.capacity {
+            let oldest = self.order.pop_front().unwrap();
+            self.hashes.remove(&oldest);
+        }
+        true
+    }
+
+    pub fn contains(&self, hash: &H256) -> bool {
+        self.hashes.contains(hash)
+    }
+
+    pub fn len(&self) -> usize {
+        self.hashes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.hashes.is_empty()
+    }
 }
 
-#[derive(Copy, Clone)]
+/// Who opened the connection: the peer (`Incoming`) or we (`Outgoing`)
+#[derive(Serialize, Debug, Copy, Clone, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
 pub enum Direction {
     Incoming,
     Outgoing,
@@ -24,46 +91,264 @@ pub enum Direction {
 #[derive(Clone, Debug)]
 pub struct Handle {
     addr: std::net::SocketAddr,
-    write_queue: mpsc::UnboundedSender<Vec<u8>>,
+    write_queue: Arc<WriteQueue>,
+    version: Arc<Version>,
+    known_inventory: Arc<Mutex<KnownInventory>>,
+    ping: Arc<Mutex<PingState>>,
+    identity: Option<Identity>,
+    direction: Direction,
+    connected: Instant,
+    // when the peer last sent a block that extended our blockchain
+    last_block: Arc<Mutex<Option<Instant>>>,
 }
 
 #[cfg(any(test,test_utilities))]
 pub struct TestReceiver {
-    r: mpsc::UnboundedReceiver<Vec<u8>>
+    r: Arc<WriteQueue>
 }
 
 impl Handle {
+    /// Queue a message to write to the peer, without blocking. When the queue is full,
+    /// transaction messages are dropped, and a peer that does not catch up is disconnected.
     pub fn write(&mut self, msg: Message) {
-        let buffer = bincode::serialize(&msg).unwrap();
-        smol::block_on(async move {
-            if self.write_queue.send(buffer).await.is_err() {
-                trace!("Trying to send to disconnected peer");
+        match self.write_queue.push(msg) {
+            Push::Queued => {}
+            Push::Dropped => trace!("Write queue of {} is full, dropped a transaction message", self.addr),
+            Push::Closed => trace!("Trying to send to disconnected peer"),
+            Push::Overflow => warn!("Peer {} does not keep up with its write queue, disconnecting", self.addr),
+        }
+    }
+
+    /// Write an inventory message to the peer, leaving out the blocks or transactions it is known
+    /// to have and remembering that it has the rest. Nothing is written if nothing is left, and
+    /// other messages are written as they are.
+    pub fn relay(&mut self, msg: Message) {
+        let msg = {
+            let mut known = self.known_inventory.lock().unwrap();
+            match msg {
+                Message::NewBlockHashes(hashes) => {
+                    Message::NewBlockHashes(hashes.into_iter().filter(|hash| known.insert(*hash)).collect())
+                }
+                Message::NewTransactionHashes(hashes) => {
+                    Message::NewTransactionHashes(hashes.into_iter().filter(|hash| known.insert(*hash)).collect())
+                }
+                Message::Blocks(blocks) => {
+                    Message::Blocks(blocks.into_iter().filter(|block| known.insert(block.hash())).collect())
+                }
+                Message::Transactions(txs) => {
+                    Message::Transactions(txs.into_iter().filter(|tx| known.insert(tx.hash())).collect())
+                }
+                Message::CompactBlock(compact) => {
+                    if !known.insert(compact.hash()) {
+                        return;
+                    }
+                    Message::CompactBlock(compact)
+                }
+                msg => msg,
             }
-        });
+        };
+        let empty = match &msg {
+            Message::NewBlockHashes(hashes) | Message::NewTransactionHashes(hashes) => hashes.is_empty(),
+            Message::Blocks(blocks) => blocks.is_empty(),
+            Message::Transactions(txs) => txs.is_empty(),
+            _ => false,
+        };
+        if !empty {
+            self.write(msg);
+        }
+    }
+
+    /// Remember that the peer has the block or transaction with this hash
+    pub fn mark_known(&self, hash: H256) {
+        self.known_inventory.lock().unwrap().insert(hash);
+    }
+
+    /// Whether the peer is known to have the block or transaction with this hash
+    pub fn knows(&self, hash: &H256) -> bool {
+        self.known_inventory.lock().unwrap().contains(hash)
     }
 
     pub fn addr(&self) -> &std::net::SocketAddr {
         &self.addr
     }
 
+    /// What the peer told about itself in the handshake: protocol version, genesis, best tip and
+    /// height at the time, node id, services and listening address
+    pub fn version(&self) -> &Version {
+        &self.version
+    }
+
+    /// Public key the peer proved to hold in the encrypted handshake, `None` on a plaintext
+    /// connection
+    pub fn identity(&self) -> Option<&Identity> {
+        self.identity.as_ref()
+    }
+
+    /// What the misbehavior of the peer is scored and banned by
+    pub fn ban_key(&self) -> BanKey {
+        BanKey::of(&self.addr, self.identity.as_ref())
+    }
+
+    pub fn direction(&self) -> Direction {
+        self.direction
+    }
+
+    /// Whether we opened the connection. Outbound peers were picked by us, so they are harder
+    /// for an attacker to control than the ones that connected to us.
+    pub fn is_outbound(&self) -> bool {
+        self.direction == Direction::Outgoing
+    }
+
+    /// When the handshake with the peer completed
+    pub fn connected(&self) -> Instant {
+        self.connected
+    }
+
+    /// Remember that the peer sent a block that extended our blockchain
+    pub fn mark_block_received(&self, now: Instant) {
+        *self.last_block.lock().unwrap() = Some(now);
+    }
+
+    /// When the peer last sent a block that extended our blockchain
+    pub fn last_block(&self) -> Option<Instant> {
+        *self.last_block.lock().unwrap()
+    }
+
+    /// Address the peer accepts connections at, as told in the handshake. An unspecified IP is
+    /// replaced by the IP the peer connected from.
+    pub fn listen_addr(&self) -> Option<std::net::SocketAddr> {
+        let mut listen_addr = self.version.listen_addr?;
+        if listen_addr.ip().is_unspecified() {
+            listen_addr.set_ip(self.addr.ip());
+        }
+        Some(listen_addr)
+    }
+
+    /// Close the write queue, so that the writer of this peer stops and reports the peer as dropped
+    pub fn close(&self) {
+        self.write_queue.close();
+    }
+
+    /// Depth and drop counters of the write queue
+    pub fn queue_stats(&self) -> QueueStats {
+        self.write_queue.stats()
+    }
+
+    /// Start a new keepalive ping, counting the previous one as missed if it was not answered
+    pub fn start_ping(&self, now: Instant) -> PingAction {
+        self.ping.lock().unwrap().tick(now)
+    }
+
+    /// Match a pong of the peer received at `now` against the last ping, returns the round-trip
+    /// time if it answers it
+    pub fn pong(&self, nonce: u64, now: Instant) -> Option<Duration> {
+        self.ping.lock().unwrap().pong(nonce, now)
+    }
+
+    /// Round-trip times measured with the keepalive pings
+    pub fn ping_stats(&self) -> PingStats {
+        self.ping.lock().unwrap().stats()
+    }
+
+    /// Whether the writer of this peer has stopped, i.e. the connection is gone
+    pub fn is_disconnected(&self) -> bool {
+        self.write_queue.is_closed()
+    }
+
     #[cfg(any(test,test_utilities))]
     pub fn test_handle() -> (Handle, TestReceiver) {
-        let (s,r) = mpsc::unbounded();
+        let r = Arc::new(WriteQueue::new(WRITE_QUEUE_CAPACITY));
         (Handle {
             addr: std::net::SocketAddr::new(std::net::IpAddr::V4(std::net::Ipv4Addr::new(127, 0, 0, 1)), 12321),
-            write_queue: s,
+            write_queue: Arc::clone(&r),
+            version: Arc::new(Version::default()),
+            known_inventory: Arc::new(Mutex::new(KnownInventory::new(MAX_KNOWN_INVENTORY))),
+            ping: Arc::new(Mutex::new(PingState::new())),
+            identity: None,
+            direction: Direction::Incoming,
+            connected: Instant::now(),
+            last_block: Arc::new(Mutex::new(None)),
         },
         TestReceiver {
             r
         })
     }
+
+    /// A handle for tests of a peer at `addr`, that claimed the given height in the handshake
+    #[cfg(test)]
+    pub fn test_handle_at(addr: std::net::SocketAddr, height: u32) -> (Handle, TestReceiver) {
+        let r = Arc::new(WriteQueue::new(WRITE_QUEUE_CAPACITY));
+        let version = Version {
+            height,
+            ..Default::default()
+        };
+        let known_inventory = Arc::new(Mutex::new(KnownInventory::new(MAX_KNOWN_INVENTORY)));
+        let ping = Arc::new(Mutex::new(PingState::new()));
+        let handle = Handle {
+            addr,
+            write_queue: Arc::clone(&r),
+            version: Arc::new(version),
+            known_inventory,
+            ping,
+            identity: None,
+            direction: Direction::Incoming,
+            connected: Instant::now(),
+            last_block: Arc::new(Mutex::new(None)),
+        };
+        (handle, TestReceiver { r })
+    }
+
+    /// The same test handle, as if the connection was opened the other way
+    #[cfg(test)]
+    pub fn with_direction(self, direction: Direction) -> Self {
+        Handle { direction, ..self }
+    }
 }
 
 #[cfg(any(test,test_utilities))]
 impl TestReceiver {
     pub fn recv(&mut self) -> Message {
-        let bytes = smol::block_on(futures::stream::StreamExt::next(&mut self.r)).unwrap();
-        let msg: Message = bincode::deserialize(&bytes).unwrap();
-        msg
+        smol::block_on(self.r.pop()).unwrap()
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::block::generate_random_block;
+
+    #[test]
+    fn rolling_known_inventory() {
+        let mut known = KnownInventory::new(2);
+        let hashes: Vec<H256> = (0..3u8).map(|i| [i; 32].into()).collect();
+        assert!(known.insert(hashes[0]));
+        assert!(!known.insert(hashes[0]));
+        known.insert(hashes[1]);
+        known.insert(hashes[2]);
+        assert_eq!(known.len(), 2);
+        assert!(!known.contains(&hashes[0]));
+        assert!(known.contains(&hashes[2]));
+    }
+
+    #[test]
+    fn relay_skips_known_inventory() {
+        let (mut handle, mut receiver) = Handle::test_handle();
+        let known = generate_random_block(&[0; 32].into());
+        let unknown = generate_random_block(&known.hash());
+        handle.mark_known(known.hash());
+
+        handle.relay(Message::NewBlockHashes(vec![known.hash(), unknown.hash()]));
+        match receiver.recv() {
+            Message::NewBlockHashes(hashes) => assert_eq!(hashes, vec![unknown.hash()]),
+            _ => panic!(),
+        }
+        assert!(handle.knows(&unknown.hash()));
+
+        // nothing left to announce, so nothing is written
+        handle.relay(Message::Blocks(vec![known, unknown]));
+        handle.relay(Message::Ping(0));
+        match receiver.recv() {
+            Message::Ping(_) => {}
+            _ => panic!(),
+        }
+    }
+}
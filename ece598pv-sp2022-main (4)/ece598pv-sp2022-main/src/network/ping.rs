@@ -0,0 +1,126 @@
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+
+/// How often every peer is pinged
+pub const PING_INTERVAL: Duration = Duration::from_secs(10);
+/// Peers that leave this many pings in a row unanswered are disconnected
+pub const MAX_MISSED_PONGS: u32 = 3;
+
+/// What to do with a peer when it is time to ping it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PingAction {
+    /// Send a ping with this nonce
+    Send(u64),
+    /// The peer missed too many pongs, drop it
+    Disconnect,
+}
+
+/// Round-trip times measured with the pings of a peer
+#[derive(Serialize, Debug, Clone, Default, PartialEq)]
+pub struct PingStats {
+    /// Round-trip time of the last ping answered, in milliseconds
+    pub rtt_ms: Option<f64>,
+    /// Lowest round-trip time so far, in milliseconds
+    pub min_rtt_ms: Option<f64>,
+    /// Pings left unanswered since the last pong
+    pub missed: u32,
+}
+
+/// The ping sent to a peer and not answered yet, and the round-trip times of the answered ones
+#[derive(Debug, Default)]
+pub struct PingState {
+    outstanding: Option<(u64, Instant)>,
+    missed: u32,
+    rtt: Option<Duration>,
+    min_rtt: Option<Duration>,
+}
+
+impl PingState {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Start a new ping. A ping still unanswered counts as missed and is replaced, a late pong
+    /// for it is ignored.
+    pub fn tick(&mut self, now: Instant) -> PingAction {
+        if self.outstanding.is_some() {
+            self.missed += 1;
+        }
+        if self.missed >= MAX_MISSED_PONGS {
+            self.outstanding = None;
+            return PingAction::Disconnect;
+        }
+        let nonce = rand::random();
+        self.outstanding = Some((nonce, now));
+        PingAction::Send(nonce)
+    }
+
+    /// Match a pong against the outstanding ping, and return the round-trip time if it answers it
+    pub fn pong(&mut self, nonce: u64, now: Instant) -> Option<Duration> {
+        match self.outstanding {
+            Some((sent_nonce, sent)) if sent_nonce == nonce => {
+                let rtt = now.duration_since(sent);
+                self.outstanding = None;
+                self.missed = 0;
+                self.rtt = Some(rtt);
+                self.min_rtt = Some(self.min_rtt.map_or(rtt, |min_rtt| min_rtt.min(rtt)));
+                Some(rtt)
+            }
+            _ => None,
+        }
+    }
+
+    pub fn stats(&self) -> PingStats {
+        let millis = |rtt: Duration| rtt.as_secs_f64() * 1000.0;
+        PingStats {
+            rtt_ms: self.rtt.map(millis),
+            min_rtt_ms: self.min_rtt.map(millis),
+            missed: self.missed,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn nonce(action: PingAction) -> u64 {
+        match action {
+            PingAction::Send(nonce) => nonce,
+            PingAction::Disconnect => panic!(),
+        }
+    }
+
+    #[test]
+    fn measure_round_trip() {
+        let mut ping = PingState::new();
+        let now = Instant::now();
+        let first = nonce(ping.tick(now));
+        assert_eq!(ping.pong(first.wrapping_add(1), now), None);
+        assert_eq!(ping.pong(first, now + Duration::from_millis(30)), Some(Duration::from_millis(30)));
+        // answered once only
+        assert_eq!(ping.pong(first, now + Duration::from_millis(40)), None);
+
+        let second = nonce(ping.tick(now + PING_INTERVAL));
+        ping.pong(second, now + PING_INTERVAL + Duration::from_millis(50));
+        let stats = ping.stats();
+        assert_eq!(stats.rtt_ms, Some(50.0));
+        assert_eq!(stats.min_rtt_ms, Some(30.0));
+        assert_eq!(stats.missed, 0);
+    }
+
+    #[test]
+    fn disconnect_after_missed_pongs() {
+        let mut ping = PingState::new();
+        let now = Instant::now();
+        let first = nonce(ping.tick(now));
+        for _ in 1..MAX_MISSED_PONGS {
+            nonce(ping.tick(now));
+        }
+        assert_eq!(ping.stats().missed, MAX_MISSED_PONGS - 1);
+        // a late pong of a replaced ping does not count
+        assert_eq!(ping.pong(first, now), None);
+        assert_eq!(ping.tick(now), PingAction::Disconnect);
+    }
+}
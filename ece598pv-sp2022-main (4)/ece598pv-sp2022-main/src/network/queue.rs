@@ -0,0 +1,223 @@
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+
+use super::message::Message;
+
+/// Messages a peer write queue holds before it counts as full
+pub const WRITE_QUEUE_CAPACITY: usize = 1000;
+/// A peer whose queue stays full this long is disconnected
+pub const STALL_TIMEOUT: Duration = Duration::from_secs(30);
+/// A queue this many times over its capacity gets its peer disconnected right away
+const HARD_LIMIT_FACTOR: usize = 4;
+
+/// How a message is treated when the queue of a peer is full
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessageClass {
+    /// Blocks, headers and their announcements and requests, never dropped
+    Block,
+    /// Transactions and their announcements and requests, dropped when the queue is full since
+    /// the peer can get them again from others
+    Transaction,
+    /// Handshake, pings and addresses, small and rare, never dropped
+    Control,
+}
+
+impl From<&Message> for MessageClass {
+    fn from(msg: &Message) -> Self {
+        match msg {
+            Message::NewBlockHashes(_)
+            | Message::GetBlocks(_)
+            | Message::Blocks(_)
+            | Message::GetHeaders(_)
+            | Message::Headers(_)
+            | Message::CompactBlock(_)
+            | Message::GetBlockTransactions(_)
+            | Message::BlockTransactions(_) => MessageClass::Block,
+            Message::NewTransactionHashes(_) | Message::GetTransactions(_) | Message::Transactions(_) => {
+                MessageClass::Transaction
+            }
+            Message::Ping(_)
+            | Message::Pong(_)
+            | Message::Version(_)
+            | Message::VerAck
+            | Message::GetAddr
+            | Message::Addr(_) => MessageClass::Control,
+        }
+    }
+}
+
+/// What happened to a message pushed to a write queue
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Push {
+    Queued,
+    /// The queue is full and the message could be dropped
+    Dropped,
+    /// The queue is closed
+    Closed,
+    /// The queue stayed full for too long or grew way over its capacity, and is now closed: the
+    /// peer cannot keep up and has to be disconnected
+    Overflow,
+}
+
+/// Depth and drop counters of a write queue
+#[derive(Serialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct QueueStats {
+    pub depth: usize,
+    pub capacity: usize,
+    /// Largest depth so far
+    pub high_water: usize,
+    /// Messages dropped because the queue was full
+    pub dropped: u64,
+}
+
+#[derive(Debug)]
+struct QueueState {
+    messages: VecDeque<Message>,
+    // since when the queue is at or over its capacity
+    full_since: Option<Instant>,
+    high_water: usize,
+    dropped: u64,
+    closed: bool,
+}
+
+/// Bounded queue of the messages to write to a peer. Pushing never blocks: when the queue is full,
+/// transaction messages are dropped and other messages are queued anyway, until the queue stays
+/// full for `STALL_TIMEOUT` or grows past its hard limit, when it is closed.
+#[derive(Debug)]
+pub struct WriteQueue {
+    state: Mutex<QueueState>,
+    capacity: usize,
+    // holds a token while there may be messages to pop, wakes up the writer
+    doorbell_tx: smol::channel::Sender<()>,
+    doorbell_rx: smol::channel::Receiver<()>,
+}
+
+impl WriteQueue {
+    pub fn new(capacity: usize) -> Self {
+        let (doorbell_tx, doorbell_rx) = smol::channel::bounded(1);
+        Self {
+            state: Mutex::new(QueueState {
+                messages: VecDeque::new(),
+                full_since: None,
+                high_water: 0,
+                dropped: 0,
+                closed: false,
+            }),
+            capacity,
+            doorbell_tx,
+            doorbell_rx,
+        }
+    }
+
+    pub fn push(&self, msg: Message) -> Push {
+        let mut state = self.state.lock().unwrap();
+        if state.closed {
+            return Push::Closed;
+        }
+        if state.messages.len() >= self.capacity {
+            let full_since = *state.full_since.get_or_insert_with(Instant::now);
+            if full_since.elapsed() > STALL_TIMEOUT || state.messages.len() >= self.capacity * HARD_LIMIT_FACTOR {
+                drop(state);
+                self.close();
+                return Push::Overflow;
+            }
+            if MessageClass::from(&msg) == MessageClass::Transaction {
+                state.dropped += 1;
+                return Push::Dropped;
+            }
+        }
+        state.messages.push_back(msg);
+        state.high_water = state.high_water.max(state.messages.len());
+        drop(state);
+        // a token may be there already, one is enough
+        let _ = self.doorbell_tx.try_send(());
+        Push::Queued
+    }
+
+    /// Wait for the next message to write, `None` once the queue is closed
+    pub async fn pop(&self) -> Option<Message> {
+        loop {
+            if let Some(msg) = self.try_pop() {
+                return Some(msg);
+            }
+            if self.is_closed() || self.doorbell_rx.recv().await.is_err() {
+                return None;
+            }
+        }
+    }
+
+    /// The next message to write if there is one, without waiting. `None` once the queue is closed.
+    pub fn try_pop(&self) -> Option<Message> {
+        let mut state = self.state.lock().unwrap();
+        if state.closed {
+            return None;
+        }
+        let msg = state.messages.pop_front()?;
+        if state.messages.len() < self.capacity {
+            state.full_since = None;
+        }
+        Some(msg)
+    }
+
+    /// Close the queue, dropping the messages in it. The writer stops at its next `pop`.
+    pub fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        self.doorbell_tx.close();
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.state.lock().unwrap().closed
+    }
+
+    pub fn stats(&self) -> QueueStats {
+        let state = self.state.lock().unwrap();
+        QueueStats {
+            depth: state.messages.len(),
+            capacity: self.capacity,
+            high_water: state.high_water,
+            dropped: state.dropped,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn drop_transactions_when_full() {
+        let queue = WriteQueue::new(2);
+        assert_eq!(queue.push(Message::NewTransactionHashes(vec![])), Push::Queued);
+        assert_eq!(queue.push(Message::NewTransactionHashes(vec![])), Push::Queued);
+        assert_eq!(queue.push(Message::NewTransactionHashes(vec![])), Push::Dropped);
+        // block messages are never dropped
+        assert_eq!(queue.push(Message::NewBlockHashes(vec![])), Push::Queued);
+        assert_eq!(
+            queue.stats(),
+            QueueStats {
+                depth: 3,
+                capacity: 2,
+                high_water: 3,
+                dropped: 1
+            }
+        );
+
+        assert!(matches!(smol::block_on(queue.pop()), Some(Message::NewTransactionHashes(_))));
+        assert_eq!(queue.stats().depth, 2);
+    }
+
+    #[test]
+    fn close_on_overflow() {
+        let queue = WriteQueue::new(2);
+        for _ in 0..2 * HARD_LIMIT_FACTOR {
+            assert_eq!(queue.push(Message::NewBlockHashes(vec![])), Push::Queued);
+        }
+        assert_eq!(queue.push(Message::NewBlockHashes(vec![])), Push::Overflow);
+        assert!(queue.is_closed());
+        assert_eq!(queue.push(Message::GetAddr), Push::Closed);
+        assert!(smol::block_on(queue.pop()).is_none());
+    }
+}
@@ -0,0 +1,83 @@
+use std::collections::HashSet;
+use std::net::SocketAddr;
+
+use rand::seq::IteratorRandom;
+
+use super::message::Message;
+use crate::types::hash::Hashable;
+
+/// How a broadcast message is spread over the connected peers
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RelayPolicy {
+    /// Every peer gets the message as it is
+    #[default]
+    Flood,
+    /// Blocks and transactions go in full to this many random peers, the other peers only get
+    /// their hashes and ask for the data they miss
+    Fanout(usize),
+}
+
+impl RelayPolicy {
+    /// Peers out of `peers` that get `msg` in full. All of them with `Flood`, or when the message
+    /// carries no data that could be announced by hash instead.
+    pub fn full_data_peers<'a, I>(&self, msg: &Message, peers: I) -> HashSet<SocketAddr>
+    where
+        I: Iterator<Item = &'a SocketAddr>,
+    {
+        match self {
+            RelayPolicy::Fanout(k) if announcement(msg).is_some() => {
+                peers.cloned().choose_multiple(&mut rand::thread_rng(), *k).into_iter().collect()
+            }
+            _ => peers.cloned().collect(),
+        }
+    }
+}
+
+/// The hashes announcing the blocks or transactions of `msg`, `None` for messages without such
+/// data
+pub fn announcement(msg: &Message) -> Option<Message> {
+    match msg {
+        Message::Blocks(blocks) => Some(Message::NewBlockHashes(blocks.iter().map(|block| block.hash()).collect())),
+        Message::CompactBlock(compact) => Some(Message::NewBlockHashes(vec![compact.hash()])),
+        Message::Transactions(txs) => Some(Message::NewTransactionHashes(txs.iter().map(|tx| tx.hash()).collect())),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::block::generate_random_block;
+    use crate::types::hash::generate_random_hash;
+
+    fn peers(n: u16) -> Vec<SocketAddr> {
+        (0..n).map(|i| SocketAddr::from(([127, 0, 0, 1], 6000 + i))).collect()
+    }
+
+    #[test]
+    fn announce_by_hash() {
+        let block = generate_random_block(&generate_random_hash());
+        match announcement(&Message::Blocks(vec![block.clone()])) {
+            Some(Message::NewBlockHashes(hashes)) => assert_eq!(hashes, vec![block.hash()]),
+            _ => panic!(),
+        }
+        assert!(announcement(&Message::NewBlockHashes(vec![block.hash()])).is_none());
+        assert!(announcement(&Message::GetAddr).is_none());
+    }
+
+    #[test]
+    fn fanout_to_random_subset() {
+        let peers = peers(10);
+        let block = Message::Blocks(vec![generate_random_block(&generate_random_hash())]);
+        assert_eq!(RelayPolicy::Flood.full_data_peers(&block, peers.iter()).len(), 10);
+
+        let chosen = RelayPolicy::Fanout(3).full_data_peers(&block, peers.iter());
+        assert_eq!(chosen.len(), 3);
+        assert!(chosen.iter().all(|addr| peers.contains(addr)));
+        assert_eq!(RelayPolicy::Fanout(20).full_data_peers(&block, peers.iter()).len(), 10);
+
+        // announcements themselves still reach everyone
+        let hashes = Message::NewBlockHashes(vec![generate_random_hash()]);
+        assert_eq!(RelayPolicy::Fanout(3).full_data_peers(&hashes, peers.iter()).len(), 10);
+    }
+}
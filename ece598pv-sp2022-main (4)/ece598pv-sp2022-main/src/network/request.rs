@@ -0,0 +1,220 @@
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use log::debug;
+
+use crate::types::hash::H256;
+
+#[derive(Debug)]
+struct Request {
+    // peer the block is requested from now, and when
+    peer: SocketAddr,
+    sent: Instant,
+    // peers known to have the block, e.g. because they announced it
+    sources: HashSet<SocketAddr>,
+    // peers the block was requested from so far, a response from any of them is accepted
+    tried: HashSet<SocketAddr>,
+}
+
+/// Outstanding block requests: which hash was asked from which peer, and when. A hash is only
+/// requested from one peer at a time. When the peer does not answer in time, the request moves
+/// to another peer that has the block.
+#[derive(Debug)]
+pub struct RequestManager {
+    requests: HashMap<H256, Request>,
+    timeout: Duration,
+}
+
+impl RequestManager {
+    pub fn new(timeout: Duration) -> Self {
+        Self {
+            requests: HashMap::new(),
+            timeout,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.requests.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.requests.is_empty()
+    }
+
+    pub fn contains(&self, hash: &H256) -> bool {
+        self.requests.contains_key(hash)
+    }
+
+    /// Number of requests outstanding at `peer`
+    pub fn load(&self, peer: &SocketAddr) -> usize {
+        self.requests.values().filter(|request| request.peer == *peer).count()
+    }
+
+    /// Record that `hash` is being requested from `peer`. Returns false if it is requested from
+    /// some peer already, and `peer` is only remembered as a fallback.
+    pub fn request(&mut self, hash: H256, peer: SocketAddr, now: Instant) -> bool {
+        if let Some(request) = self.requests.get_mut(&hash) {
+            request.sources.insert(peer);
+            return false;
+        }
+        self.requests.insert(
+            hash,
+            Request {
+                peer,
+                sent: now,
+                sources: std::iter::once(peer).collect(),
+                tried: std::iter::once(peer).collect(),
+            },
+        );
+        true
+    }
+
+    /// Match a block `peer` sent against the requests. Returns false if the block was not
+    /// requested from that peer, in which case the response does not count.
+    pub fn received(&mut self, hash: &H256, peer: &SocketAddr) -> bool {
+        match self.requests.get(hash) {
+            Some(request) if request.tried.contains(peer) => {
+                self.requests.remove(hash);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Keep only the requests for which `f` returns true
+    pub fn retain<F: FnMut(&H256) -> bool>(&mut self, mut f: F) {
+        self.requests.retain(|hash, _| f(hash));
+    }
+
+    /// Move the requests that timed out, or whose peer disconnected, to another connected peer
+    /// that has the block: one of its sources or of `extra_sources`, preferring peers not tried
+    /// yet. Requests with nowhere left to go are dropped. Returns the requests to send.
+    pub fn retry<F>(&mut self, now: Instant, connected: &HashSet<SocketAddr>, extra_sources: F) -> Vec<(SocketAddr, H256)>
+    where
+        F: Fn(&H256) -> Vec<SocketAddr>,
+    {
+        let mut load: HashMap<SocketAddr, usize> = HashMap::new();
+        for request in self.requests.values() {
+            *load.entry(request.peer).or_insert(0) += 1;
+        }
+
+        let mut retries = Vec::new();
+        let mut dropped = Vec::new();
+        // in hash order, and ties to the lowest address, so that the peers picked do not depend
+        // on the hash map order
+        let mut hashes: Vec<H256> = self.requests.keys().copied().collect();
+        hashes.sort();
+        for hash in hashes.iter() {
+            let request = self.requests.get_mut(hash).unwrap();
+            let alive = connected.contains(&request.peer);
+            if alive && now.duration_since(request.sent) < self.timeout {
+                continue;
+            }
+            let candidates: Vec<SocketAddr> = request
+                .sources
+                .iter()
+                .cloned()
+                .chain(extra_sources(hash))
+                .filter(|addr| connected.contains(addr) && *addr != request.peer)
+                .collect();
+            let untried = candidates.iter().filter(|addr| !request.tried.contains(addr));
+            let next = untried
+                .min_by_key(|addr| (load.get(addr).copied().unwrap_or(0), **addr))
+                .or_else(|| candidates.iter().min_by_key(|addr| (load.get(addr).copied().unwrap_or(0), **addr)))
+                .cloned()
+                .or(if alive { Some(request.peer) } else { None });
+            match next {
+                Some(peer) => {
+                    debug!("Request for block {:?} to {} timed out, retrying with {}", hash, request.peer, peer);
+                    *load.entry(request.peer).or_insert(1) -= 1;
+                    *load.entry(peer).or_insert(0) += 1;
+                    request.peer = peer;
+                    request.sent = now;
+                    request.sources.insert(peer);
+                    request.tried.insert(peer);
+                    retries.push((peer, *hash));
+                }
+                None => dropped.push(*hash),
+            }
+        }
+        for hash in dropped {
+            debug!("No peer left to request block {:?} from", hash);
+            self.requests.remove(&hash);
+        }
+        retries
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    #[test]
+    fn deduplicate_requests() {
+        let mut requests = RequestManager::new(Duration::from_secs(10));
+        let hash: H256 = [1; 32].into();
+        let now = Instant::now();
+        assert!(requests.request(hash, addr(6001), now));
+        assert!(!requests.request(hash, addr(6002), now));
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests.load(&addr(6001)), 1);
+
+        // only the peer it was asked from can answer
+        assert!(!requests.received(&hash, &addr(6002)));
+        assert!(requests.received(&hash, &addr(6001)));
+        assert!(requests.is_empty());
+        assert!(!requests.received(&hash, &addr(6001)));
+    }
+
+    #[test]
+    fn retry_with_another_peer() {
+        let mut requests = RequestManager::new(Duration::from_secs(10));
+        let hash: H256 = [1; 32].into();
+        let now = Instant::now();
+        requests.request(hash, addr(6001), now);
+        requests.request(hash, addr(6002), now);
+        let connected: HashSet<SocketAddr> = vec![addr(6001), addr(6002), addr(6003)].into_iter().collect();
+
+        assert!(requests.retry(now, &connected, |_| vec![]).is_empty());
+        let later = now + Duration::from_secs(11);
+        assert_eq!(requests.retry(later, &connected, |_| vec![]), vec![(addr(6002), hash)]);
+        // the first peer may still answer late
+        assert!(requests.received(&hash, &addr(6001)));
+
+        // with no other peer left, the request goes back to one tried before
+        requests.request(hash, addr(6001), now);
+        assert_eq!(requests.retry(later, &connected, |_| vec![addr(6003)]), vec![(addr(6003), hash)]);
+        let much_later = later + Duration::from_secs(11);
+        assert_eq!(requests.retry(much_later, &connected, |_| vec![]), vec![(addr(6001), hash)]);
+
+        // and is dropped once no peer that has the block is connected
+        let nobody = HashSet::new();
+        assert!(requests.retry(much_later, &nobody, |_| vec![]).is_empty());
+        assert!(requests.is_empty());
+    }
+
+    #[test]
+    fn retry_in_a_fixed_order() {
+        let mut requests = RequestManager::new(Duration::from_secs(10));
+        let now = Instant::now();
+        for i in (1..5u8).rev() {
+            requests.request([i; 32].into(), addr(6001), now);
+        }
+        let connected: HashSet<SocketAddr> = vec![addr(6002), addr(6003), addr(6004)].into_iter().collect();
+
+        // by hash, each to the least loaded peer, the lowest address among those
+        let retries = requests.retry(now, &connected, |_| vec![addr(6004), addr(6003), addr(6002)]);
+        let expected = vec![
+            (addr(6002), [1; 32].into()),
+            (addr(6003), [2; 32].into()),
+            (addr(6004), [3; 32].into()),
+            (addr(6002), [4; 32].into()),
+        ];
+        assert_eq!(retries, expected);
+    }
+}
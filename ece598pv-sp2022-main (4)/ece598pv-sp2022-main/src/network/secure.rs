@@ -0,0 +1,343 @@
+use std::convert::TryInto;
+use std::io;
+
+use futures::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use ring::rand::SystemRandom;
+use ring::signature::{self, Ed25519KeyPair, KeyPair};
+use ring::{aead, agreement, digest, hkdf, hmac};
+
+use super::frame::{self, Magic, MessageType};
+use super::message::Message;
+
+/// Byte after the network magic that opens the encrypted handshake, where a plaintext frame has
+/// its frame version, so that a node with the other setting fails the handshake right away
+pub const SECURE_MARKER: u8 = 0xe1;
+/// Size of the first message of the handshake: magic, marker and ephemeral X25519 public key
+const HELLO_SIZE: usize = 4 + 1 + 32;
+/// Size of the second message of the handshake before encryption: identity and signature, followed
+/// by a proof per network secret
+const AUTH_SIZE: usize = 32 + 64;
+/// Size of the proof that a node knows a network secret
+const SECRET_PROOF_SIZE: usize = 32;
+/// Most network secrets a node can prove it knows in one handshake
+pub const MAX_SECRET_PROOFS: usize = 16;
+/// Largest encrypted record accepted: the largest frame with its tag
+const MAX_RECORD_SIZE: u32 = frame::HEADER_SIZE as u32 + 32 * 1024 * 1024 + 16;
+
+/// Identity of a node on the encrypted transport, its ed25519 public key
+pub type Identity = [u8; 32];
+
+fn invalid_data<E: std::fmt::Display>(msg: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+/// Nonce of the record with the given sequence number, every key is used in one direction only
+fn nonce(counter: u64) -> aead::Nonce {
+    let mut nonce = [0u8; aead::NONCE_LEN];
+    nonce[4..].copy_from_slice(&counter.to_be_bytes());
+    aead::Nonce::assume_unique_for_key(nonce)
+}
+
+/// Encrypts the records sent to a peer
+pub struct Sealer {
+    key: aead::LessSafeKey,
+    counter: u64,
+}
+
+impl Sealer {
+    /// Encrypt `plaintext` into a record: its length (4 bytes, big endian) and the ciphertext with
+    /// its tag
+    fn seal(&mut self, mut plaintext: Vec<u8>) -> Vec<u8> {
+        self.key
+            .seal_in_place_append_tag(nonce(self.counter), aead::Aad::empty(), &mut plaintext)
+            .unwrap();
+        self.counter += 1;
+        let mut record = (plaintext.len() as u32).to_be_bytes().to_vec();
+        record.extend_from_slice(&plaintext);
+        record
+    }
+
+    async fn write_record<S: AsyncWrite + Unpin>(&mut self, stream: &mut S, plaintext: Vec<u8>) -> io::Result<()> {
+        stream.write_all(&self.seal(plaintext)).await?;
+        stream.flush().await
+    }
+
+    /// Write a single message as a frame inside an encrypted record
+    pub async fn write_frame<S: AsyncWrite + Unpin>(&mut self, stream: &mut S, magic: &Magic, msg: &Message) -> io::Result<()> {
+        self.write_record(stream, frame::encode(magic, msg)).await
+    }
+}
+
+/// Decrypts and authenticates the records received from a peer
+pub struct Opener {
+    key: aead::LessSafeKey,
+    counter: u64,
+}
+
+impl Opener {
+    async fn read_record<S: AsyncRead + Unpin>(&mut self, stream: &mut S) -> io::Result<Vec<u8>> {
+        let mut length = [0u8; 4];
+        stream.read_exact(&mut length).await?;
+        let length = u32::from_be_bytes(length);
+        if length > MAX_RECORD_SIZE {
+            return Err(invalid_data(format!("encrypted record of {} bytes is too large", length)));
+        }
+        let mut record = vec![0; length as usize];
+        stream.read_exact(&mut record).await?;
+        let plaintext_len = self
+            .key
+            .open_in_place(nonce(self.counter), aead::Aad::empty(), &mut record)
+            .map_err(|_| invalid_data("encrypted record does not authenticate"))?
+            .len();
+        self.counter += 1;
+        record.truncate(plaintext_len);
+        Ok(record)
+    }
+
+    /// Read a single encrypted record and check the frame inside, like `frame::read_frame`
+    pub async fn read_frame<S: AsyncRead + Unpin>(&mut self, stream: &mut S, magic: &Magic) -> io::Result<(MessageType, Vec<u8>)> {
+        let record = self.read_record(stream).await?;
+        let mut cursor = futures::io::Cursor::new(&record);
+        let frame = frame::read_frame(&mut cursor, magic).await?;
+        if cursor.position() as usize != record.len() {
+            return Err(invalid_data("trailing bytes after the frame of an encrypted record"));
+        }
+        Ok(frame)
+    }
+}
+
+/// Read a frame, from inside an encrypted record if the connection is encrypted
+pub async fn read_frame<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    magic: &Magic,
+    opener: Option<&mut Opener>,
+) -> io::Result<(MessageType, Vec<u8>)> {
+    match opener {
+        Some(opener) => opener.read_frame(stream, magic).await,
+        None => frame::read_frame(stream, magic).await,
+    }
+}
+
+/// Write a frame, inside an encrypted record if the connection is encrypted
+pub async fn write_frame<S: AsyncWrite + Unpin>(
+    stream: &mut S,
+    magic: &Magic,
+    sealer: Option<&mut Sealer>,
+    msg: &Message,
+) -> io::Result<()> {
+    match sealer {
+        Some(sealer) => sealer.write_frame(stream, magic, msg).await,
+        None => frame::write_frame(stream, magic, msg).await,
+    }
+}
+
+/// The keys of an encrypted connection, and the identity of the peer at the other end
+pub struct Session {
+    pub sealer: Sealer,
+    pub opener: Opener,
+    pub remote: Identity,
+    // what the peer signed, and the proofs it sent of the network secrets it knows
+    remote_payload: Vec<u8>,
+    secret_proofs: Vec<Vec<u8>>,
+}
+
+impl Session {
+    /// Whether the peer proved it knows the network secret `secret`
+    pub fn proves_secret(&self, secret: &[u8]) -> bool {
+        let key = hmac::Key::new(hmac::HMAC_SHA256, secret);
+        self.secret_proofs
+            .iter()
+            .any(|proof| hmac::verify(&key, &self.remote_payload, proof).is_ok())
+    }
+}
+
+/// Key of one direction of the connection, derived from the shared secret
+fn direction_key(prk: &hkdf::Prk, info: &[u8]) -> aead::LessSafeKey {
+    let info = [info];
+    let okm = prk.expand(&info, &aead::CHACHA20_POLY1305).unwrap();
+    aead::LessSafeKey::new(aead::UnboundKey::from(okm))
+}
+
+/// What a node signs to prove its identity: the hash of the handshake and its role in it
+fn auth_payload(transcript: &digest::Digest, initiator: bool) -> Vec<u8> {
+    let mut payload = transcript.as_ref().to_vec();
+    payload.push(if initiator { b'I' } else { b'R' });
+    payload
+}
+
+/// Run the encrypted handshake on a new connection. Both nodes send an ephemeral X25519 key, and
+/// derive a key per direction from the shared secret and both ephemeral keys. Then each node
+/// sends its identity and its signature over the hash of the ephemeral keys, encrypted, which
+/// proves it holds the identity key and binds the identity to this connection. The node that
+/// opened the connection is the initiator. A MAC of the same hash with every secret of `secrets`
+/// goes with the signature, to prove the node knows them without telling them.
+pub async fn handshake<S>(
+    stream: &mut S,
+    magic: &Magic,
+    identity: &Ed25519KeyPair,
+    secrets: &[Vec<u8>],
+    initiator: bool,
+) -> io::Result<Session>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let rng = SystemRandom::new();
+    let ephemeral = agreement::EphemeralPrivateKey::generate(&agreement::X25519, &rng)
+        .map_err(|_| io::Error::other("cannot generate an ephemeral key"))?;
+    let local_public = ephemeral
+        .compute_public_key()
+        .map_err(|_| io::Error::other("cannot compute an ephemeral public key"))?;
+
+    let mut hello = magic.to_vec();
+    hello.push(SECURE_MARKER);
+    hello.extend_from_slice(local_public.as_ref());
+    stream.write_all(&hello).await?;
+    stream.flush().await?;
+
+    let mut remote_hello = [0u8; HELLO_SIZE];
+    stream.read_exact(&mut remote_hello).await?;
+    if remote_hello[0..4] != magic[..] {
+        return Err(invalid_data(format!("wrong network magic {:02x?}", &remote_hello[0..4])));
+    }
+    if remote_hello[4] != SECURE_MARKER {
+        return Err(invalid_data("peer does not use the encrypted transport"));
+    }
+    let remote_public = &remote_hello[5..];
+
+    // the hash of the handshake binds the keys and the signatures to this connection
+    let (initiator_public, responder_public) = if initiator {
+        (local_public.as_ref(), remote_public)
+    } else {
+        (remote_public, local_public.as_ref())
+    };
+    let mut ctx = digest::Context::new(&digest::SHA256);
+    ctx.update(magic);
+    ctx.update(initiator_public);
+    ctx.update(responder_public);
+    let transcript = ctx.finish();
+
+    let (initiator_key, responder_key) = agreement::agree_ephemeral(
+        ephemeral,
+        &agreement::UnparsedPublicKey::new(&agreement::X25519, remote_public),
+        invalid_data("invalid ephemeral key"),
+        |shared_secret| {
+            let prk = hkdf::Salt::new(hkdf::HKDF_SHA256, transcript.as_ref()).extract(shared_secret);
+            Ok((direction_key(&prk, b"initiator to responder"), direction_key(&prk, b"responder to initiator")))
+        },
+    )?;
+    let (sealing_key, opening_key) = if initiator {
+        (initiator_key, responder_key)
+    } else {
+        (responder_key, initiator_key)
+    };
+    let mut sealer = Sealer { key: sealing_key, counter: 0 };
+    let mut opener = Opener { key: opening_key, counter: 0 };
+
+    let payload = auth_payload(&transcript, initiator);
+    let mut auth = identity.public_key().as_ref().to_vec();
+    auth.extend_from_slice(identity.sign(&payload).as_ref());
+    for secret in secrets.iter().take(MAX_SECRET_PROOFS) {
+        auth.extend_from_slice(hmac::sign(&hmac::Key::new(hmac::HMAC_SHA256, secret), &payload).as_ref());
+    }
+    sealer.write_record(stream, auth).await?;
+
+    let remote_auth = opener.read_record(stream).await?;
+    let proofs_size = remote_auth.len().saturating_sub(AUTH_SIZE);
+    if remote_auth.len() < AUTH_SIZE
+        || proofs_size % SECRET_PROOF_SIZE != 0
+        || proofs_size / SECRET_PROOF_SIZE > MAX_SECRET_PROOFS
+    {
+        return Err(invalid_data("malformed identity"));
+    }
+    let remote: Identity = remote_auth[..32].try_into().unwrap();
+    let remote_payload = auth_payload(&transcript, !initiator);
+    signature::UnparsedPublicKey::new(&signature::ED25519, &remote)
+        .verify(&remote_payload, &remote_auth[32..AUTH_SIZE])
+        .map_err(|_| invalid_data("peer failed to prove its identity"))?;
+    let secret_proofs = remote_auth[AUTH_SIZE..].chunks(SECRET_PROOF_SIZE).map(|proof| proof.to_vec()).collect();
+
+    Ok(Session { sealer, opener, remote, remote_payload, secret_proofs })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::key_pair;
+    use smol::Async;
+    use std::net::{TcpListener, TcpStream};
+
+    const MAGIC: Magic = [1, 2, 3, 4];
+
+    fn connected_pair() -> (Async<TcpStream>, Async<TcpStream>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        (Async::new(client).unwrap(), Async::new(server).unwrap())
+    }
+
+    #[test]
+    fn exchange_encrypted_frames() {
+        let (mut client, mut server) = connected_pair();
+        let client_key = key_pair::random();
+        let server_key = key_pair::random();
+        let (client_session, server_session) = smol::block_on(futures::future::try_join(
+            handshake(&mut client, &MAGIC, &client_key, &[], true),
+            handshake(&mut server, &MAGIC, &server_key, &[], false),
+        ))
+        .unwrap();
+        assert_eq!(&client_session.remote[..], server_key.public_key().as_ref());
+        assert_eq!(&server_session.remote[..], client_key.public_key().as_ref());
+
+        let (mut sealer, mut opener) = (client_session.sealer, server_session.opener);
+        smol::block_on(async {
+            for nonce in 0..3 {
+                write_frame(&mut client, &MAGIC, Some(&mut sealer), &Message::Ping(nonce)).await.unwrap();
+                let (kind, payload) = read_frame(&mut server, &MAGIC, Some(&mut opener)).await.unwrap();
+                assert_eq!(kind, MessageType::Ping);
+                assert!(matches!(bincode::deserialize(&payload).unwrap(), Message::Ping(n) if n == nonce));
+            }
+        });
+    }
+
+    #[test]
+    fn prove_network_secret() {
+        let (mut client, mut server) = connected_pair();
+        let (client_key, server_key) = (key_pair::random(), key_pair::random());
+        let client_secrets = vec![b"alpha".to_vec(), b"beta".to_vec()];
+        let (_, server_session) = smol::block_on(futures::future::try_join(
+            handshake(&mut client, &MAGIC, &client_key, &client_secrets, true),
+            handshake(&mut server, &MAGIC, &server_key, &[], false),
+        ))
+        .unwrap();
+        assert!(server_session.proves_secret(b"beta"));
+        assert!(!server_session.proves_secret(b"gamma"));
+    }
+
+    #[test]
+    fn reject_tampered_record() {
+        let (mut client, mut server) = connected_pair();
+        let (client_key, server_key) = (key_pair::random(), key_pair::random());
+        let (mut client_session, mut server_session) = smol::block_on(futures::future::try_join(
+            handshake(&mut client, &MAGIC, &client_key, &[], true),
+            handshake(&mut server, &MAGIC, &server_key, &[], false),
+        ))
+        .unwrap();
+
+        let mut record = client_session.sealer.seal(frame::encode(&MAGIC, &Message::Ping(1)));
+        *record.last_mut().unwrap() ^= 1;
+        let err = smol::block_on(server_session.opener.read_record(&mut futures::io::Cursor::new(record))).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn reject_plaintext_peer() {
+        let (mut client, mut server) = connected_pair();
+        let key = key_pair::random();
+        let (secure, plain) = smol::block_on(futures::future::join(
+            handshake(&mut client, &MAGIC, &key, &[], true),
+            frame::write_frame(&mut server, &MAGIC, &Message::Version(Default::default())),
+        ));
+        plain.unwrap();
+        assert_eq!(secure.err().unwrap().kind(), io::ErrorKind::InvalidData);
+    }
+}
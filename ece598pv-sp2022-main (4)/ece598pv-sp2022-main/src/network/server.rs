@@ -1,21 +1,43 @@
-use crate::types::address::Address;
+use super::allowlist::Allowlist;
+use super::banman::{BanKey, BanManager, Misbehavior};
+use super::frame;
+use super::limits::{self, ConnectionLimits, EvictionCandidate};
 use super::peer;
+use super::ping::{PingAction, PING_INTERVAL};
 use super::message;
+use super::relay::{self, RelayPolicy};
+use super::secure;
+use super::trace::TraceWriter;
+use super::transport::{BoxStream, Listener, TcpTransport, Transport};
+use crate::blockchain::Blockchain;
 
-use async_dup::Arc as AsyncArc;
-use futures::io::{AsyncReadExt, AsyncWriteExt};
+use futures::io::{AsyncRead, AsyncReadExt, AsyncWrite};
 use futures::io::{BufReader, BufWriter};
-use futures::{channel::oneshot, stream::StreamExt};
-use smol::{Async, Executor};
-use log::{debug, info, trace};
-use std::net;
-use std::sync::Arc;
+use futures::channel::oneshot;
+use smol::{Executor, Timer};
+use log::{debug, info, trace, warn};
+use ring::signature::{Ed25519KeyPair, KeyPair};
+use std::io;
+use std::sync::{Arc, Mutex};
 use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
+/// How long a new peer has to complete the handshake
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
 
+/// How long to wait for a peer to accept a connection
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[allow(clippy::too_many_arguments)]
 pub fn new(
     addr: std::net::SocketAddr,
     msg_sink: smol::channel::Sender<(Vec<u8>, peer::Handle)>,
+    blockchain: &Arc<Mutex<Blockchain>>,
+    banman: &Arc<Mutex<BanManager>>,
+    relay_policy: RelayPolicy,
+    identity: Option<Arc<Ed25519KeyPair>>,
+    allowlist: Option<Arc<Mutex<Allowlist>>>,
+    limits: ConnectionLimits,
 ) -> std::io::Result<(Context, Handle)> {
     let (control_signal_sender, control_signal_receiver) = smol::channel::bounded(10000);
     let handle = Handle {
@@ -27,6 +49,17 @@ pub fn new(
         control_chan: control_signal_receiver,
         control_sender: control_signal_sender,
         new_msg_chan: msg_sink,
+        magic: frame::network_magic(&blockchain.lock().unwrap().genesis_hash()),
+        blockchain: Arc::clone(blockchain),
+        banman: Arc::clone(banman),
+        node_id: rand::random(),
+        relay_policy,
+        identity,
+        allowlist,
+        limits,
+        transport: Arc::new(TcpTransport),
+        trace: None,
+        pings: true,
     };
     Ok((ctx, handle))
 }
@@ -37,18 +70,63 @@ pub struct Context {
     control_chan: smol::channel::Receiver<ControlSignal>,
     control_sender: smol::channel::Sender<ControlSignal>,
     new_msg_chan: smol::channel::Sender<(Vec<u8>, peer::Handle)>,
+    blockchain: Arc<Mutex<Blockchain>>,
+    banman: Arc<Mutex<BanManager>>,
+    node_id: u64,
+    // network magic every frame starts with
+    magic: frame::Magic,
+    relay_policy: RelayPolicy,
+    // key of this node on the encrypted transport, connections are plaintext without one
+    identity: Option<Arc<Ed25519KeyPair>>,
+    // peers allowed on a private network, everyone is allowed without one
+    allowlist: Option<Arc<Mutex<Allowlist>>>,
+    limits: ConnectionLimits,
+    transport: Arc<dyn Transport>,
+    // where to record the frames received, for replaying them later
+    trace: Option<Arc<TraceWriter>>,
+    // whether to ping the peers every `PING_INTERVAL`
+    pings: bool,
 }
 
 impl Context {
+    /// Reach the peers over `transport` instead of TCP
+    pub fn with_transport(mut self, transport: Arc<dyn Transport>) -> Self {
+        self.transport = transport;
+        self
+    }
+
+    /// Record every frame received from peers to `trace`
+    pub fn with_trace(mut self, trace: Arc<TraceWriter>) -> Self {
+        self.trace = Some(trace);
+        self
+    }
+
+    /// Never ping the peers, for when the wall clock means nothing, like in a simulation
+    pub fn without_pings(mut self) -> Self {
+        self.pings = false;
+        self
+    }
+
     /// Start a new server context.
     pub fn start(self) -> std::io::Result<()> {
+        let ex = Arc::new(Executor::new());
+        self.start_on(&ex)?;
+        thread::spawn(move || smol::block_on(ex.run(futures::future::pending::<()>())));
+        return Ok(());
+    }
+
+    /// Start the server context on `ex`, which the caller has to run
+    pub fn start_on(self, ex: &Arc<Executor<'static>>) -> std::io::Result<()> {
         // initialize the server socket
-        let listener = Async::<net::TcpListener>::bind(self.addr)?;
+        let listener = self.transport.bind(self.addr)?;
         info!("P2P server listening at {}", self.addr);
+        if let Some(identity) = &self.identity {
+            info!("P2P connections are encrypted, node identity {}", hex::encode(identity.public_key().as_ref()));
+        }
         let control_chan = self.control_sender.clone();
-        let ex = Executor::new();
-        let ex = Arc::new(ex);
-        let ex_clone = ex.clone();
+        let ping_chan = control_chan.clone();
+        let pings = self.pings;
+        let ex_clone = Arc::clone(ex);
         ex.spawn(async move {
             self.dispatch_control(ex_clone).await.unwrap();
         })
@@ -57,17 +135,23 @@ impl Context {
             Self::listener_loop(listener, control_chan).await.unwrap();
         })
             .detach();
-        thread::spawn(move || smol::block_on(ex.run(futures::future::pending::<()>())));
-        return Ok(());
+        if pings {
+            ex.spawn(async move {
+                Self::ping_loop(ping_chan).await;
+            })
+                .detach();
+        }
+        Ok(())
     }
 
     /// the loop that endlessly accept incoming peers
     async fn listener_loop(
-        listener: Async<net::TcpListener>,
+        listener: Box<dyn Listener>,
         control_chan: smol::channel::Sender<ControlSignal>,
     ) -> std::io::Result<()> {
         loop {
-            let (stream, addr) = listener.accept().await?;
+            let stream = listener.accept().await?;
+            let addr = stream.peer_addr()?;
             control_chan
                 .send(ControlSignal::GetNewPeer(stream))
                 .await
@@ -76,145 +160,302 @@ impl Context {
         }
     }
 
+    /// the loop that keeps asking for the peers to be pinged
+    async fn ping_loop(control_chan: smol::channel::Sender<ControlSignal>) {
+        loop {
+            Timer::after(PING_INTERVAL).await;
+            if control_chan.send(ControlSignal::PingPeers).await.is_err() {
+                break;
+            }
+        }
+    }
+
     async fn dispatch_control(mut self, ex: Arc<Executor<'_>>) -> std::io::Result<()> {
         // read the next control signal
         while let Ok(ctrl) = self.control_chan.recv().await {
             match ctrl {
                 ControlSignal::ConnectNewPeer(addr, result_chan) => {
                     trace!("Processing ConnectNewPeer command");
-                    let handle = self.connect(&addr, ex.clone()).await;
-                    result_chan.send(handle).unwrap();
+                    self.connect(addr, result_chan, &ex);
+                }
+                ControlSignal::PeerConnected(stream, result_chan) => {
+                    trace!("Processing PeerConnected command");
+                    self.start_handshake(stream, peer::Direction::Outgoing, Some(result_chan), &ex);
                 }
                 ControlSignal::BroadcastMessage(msg) => {
                     trace!("Processing BroadcastMessage command");
-                    for (_, hd) in self.peers.iter_mut() {
-                        hd.write(msg.clone());
+                    // peers left out of the fanout get the hashes, and ask for the data they miss
+                    let full_data_peers = self.relay_policy.full_data_peers(&msg, self.peers.keys());
+                    let announcement = relay::announcement(&msg);
+                    for (addr, hd) in self.peers.iter_mut() {
+                        match &announcement {
+                            Some(announcement) if !full_data_peers.contains(addr) => hd.relay(announcement.clone()),
+                            _ => hd.relay(msg.clone()),
+                        }
                     }
                 }
                 ControlSignal::GetNewPeer(stream) => {
                     trace!("Processing GetNewPeer command");
-                    self.accept(stream, ex.clone()).await?;
+                    self.start_handshake(stream, peer::Direction::Incoming, None, &ex);
+                }
+                ControlSignal::PeerReady(new_peer, result_chan) => {
+                    trace!("Processing PeerReady command");
+                    let result = self.add_peer(*new_peer, &ex);
+                    match result_chan {
+                        // the caller may have given up waiting, nothing to do then
+                        Some(result_chan) => {
+                            let _ = result_chan.send(result);
+                        }
+                        None => {
+                            if let Err(e) = result {
+                                warn!("Dropped incoming peer: {}", e);
+                            }
+                        }
+                    }
+                }
+                ControlSignal::GetPeers(result_chan) => {
+                    trace!("Processing GetPeers command");
+                    let _ = result_chan.send(self.peers.values().cloned().collect());
+                }
+                ControlSignal::PingPeers => {
+                    trace!("Processing PingPeers command");
+                    let now = std::time::Instant::now();
+                    for (addr, hd) in self.peers.iter_mut() {
+                        match hd.start_ping(now) {
+                            PingAction::Send(nonce) => hd.write(message::Message::Ping(nonce)),
+                            PingAction::Disconnect => {
+                                warn!("Peer {} stopped answering pings, disconnecting", addr);
+                                hd.close();
+                            }
+                        }
+                    }
                 }
                 ControlSignal::DroppedPeer(addr) => {
                     trace!("Processing DroppedPeer({})", addr);
                     self.peers.remove(&addr);
                     info!("Peer {} disconnected", addr);
                 }
-                ControlSignal::SendToPeer((_receiver, _msg)) => {
-                    unimplemented!()
+                ControlSignal::SendToPeer(addr, msg, result_chan) => {
+                    trace!("Processing SendToPeer({})", addr);
+                    let result = match self.peers.get_mut(&addr) {
+                        Some(hd) if hd.is_disconnected() => Err(io::Error::new(
+                            io::ErrorKind::NotConnected,
+                            format!("peer {} is disconnected", addr),
+                        )),
+                        Some(hd) => {
+                            hd.write(msg);
+                            Ok(())
+                        }
+                        None => Err(io::Error::new(
+                            io::ErrorKind::NotFound,
+                            format!("unknown peer {}", addr),
+                        )),
+                    };
+                    // the caller may have given up waiting, nothing to do then
+                    let _ = result_chan.send(result);
                 }
             }
         }
         return Ok(());
     }
 
-    /// Connect to a peer, and register this peer
-    async fn connect(
-        &mut self,
-        addr: &std::net::SocketAddr,
-        ex: Arc<Executor<'_>>,
-    ) -> std::io::Result<peer::Handle> {
+    /// Dial a peer in a task of its own, giving up after `CONNECT_TIMEOUT`. The stream comes
+    /// back as `PeerConnected` for the handshake, and errors go to `result_chan`.
+    fn connect(
+        &self,
+        addr: std::net::SocketAddr,
+        result_chan: oneshot::Sender<std::io::Result<peer::Handle>>,
+        ex: &Executor<'_>,
+    ) {
+        let outbound = self.count(peer::Direction::Outgoing);
+        if outbound >= self.limits.max_outbound {
+            let _ = result_chan.send(Err(io::Error::other(format!("{} outbound connections already, the limit", outbound))));
+            return;
+        }
         debug!("Establishing connection to peer {}", addr);
-        let stream = Async::<std::net::TcpStream>::connect(addr.clone()).await?;
+        let dial = self.transport.connect(addr);
+        let control_chan = self.control_sender.clone();
+        ex.spawn(async move {
+            let timeout = async {
+                Timer::after(CONNECT_TIMEOUT).await;
+                Err(io::Error::new(io::ErrorKind::TimedOut, format!("connecting to {} timed out", addr)))
+            };
+            match smol::future::or(dial, timeout).await {
+                Ok(stream) => {
+                    let _ = control_chan.send(ControlSignal::PeerConnected(stream, result_chan)).await;
+                }
+                Err(e) => {
+                    let _ = result_chan.send(Err(e));
+                }
+            }
+        })
+            .detach();
+    }
 
-        // register the new peer
-        self.register(stream, peer::Direction::Outgoing, ex).await
+    /// Number of connected peers in the given direction
+    fn count(&self, direction: peer::Direction) -> usize {
+        self.peers.values().filter(|peer| peer.direction() == direction).count()
     }
 
-    async fn accept(
-        &mut self,
-        stream: Async<net::TcpStream>,
-        ex: Arc<Executor<'_>>,
-    ) -> std::io::Result<()> {
-        self.register(stream, peer::Direction::Incoming, ex).await?;
+    /// Drop the least useful inbound peer to make room for a new one, fails if they are all
+    /// worth keeping
+    fn evict_inbound(&mut self) -> std::io::Result<()> {
+        let candidates = self
+            .peers
+            .values()
+            .filter(|peer| !peer.is_outbound())
+            .map(EvictionCandidate::from_peer)
+            .collect();
+        let addr = limits::select_eviction(candidates)
+            .ok_or_else(|| io::Error::other("inbound connections are full"))?;
+        info!("Evicting inbound peer {} to make room for a new one", addr);
+        // the writer reports it as dropped later, it no longer counts from now on
+        if let Some(peer) = self.peers.remove(&addr) {
+            peer.close();
+        }
         Ok(())
     }
 
-    async fn register(
-        &mut self,
-        stream: Async<net::TcpStream>,
-        _direction: peer::Direction,
-        ex: Arc<Executor<'_>>,
-    ) -> std::io::Result<peer::Handle> {
-        let (mut write_queue, handle) = peer::new(&stream)?;
+    /// What this node tells new peers about itself in the handshake
+    fn local_version(&self) -> message::Version {
+        let blockchain = self.blockchain.lock().unwrap();
+        let tip = blockchain.tip();
+        message::Version {
+            version: message::PROTOCOL_VERSION,
+            genesis: blockchain.genesis_hash(),
+            tip,
+            height: blockchain.get_block(&tip).unwrap().length,
+            node_id: self.node_id,
+            services: message::SERVICE_FULL_NODE,
+            timestamp: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis(),
+            listen_addr: Some(self.addr),
+        }
+    }
 
-        let stream = AsyncArc::new(stream);
+    /// Run the handshake with a new peer in a task of its own, so that a slow or silent peer does
+    /// not hold up the control signals. The peer comes back as `PeerReady` once it is done, and
+    /// the outcome of an outgoing connection goes to `result_chan`.
+    fn start_handshake(
+        &self,
+        stream: BoxStream,
+        direction: peer::Direction,
+        result_chan: Option<oneshot::Sender<std::io::Result<peer::Handle>>>,
+        ex: &Executor<'_>,
+    ) {
+        let magic = self.magic;
+        let local = self.local_version();
+        let identity = self.identity.clone();
+        let allowlist = self.allowlist.as_ref().map(|allowlist| allowlist.lock().unwrap().clone());
+        let banman = Arc::clone(&self.banman);
+        let control_chan = self.control_sender.clone();
+        ex.spawn(async move {
+            let result = handshake_peer(stream, direction, &magic, local, identity.as_deref(), allowlist.as_ref(), &banman).await;
+            match (result, result_chan) {
+                (Ok(new_peer), result_chan) => {
+                    let _ = control_chan.send(ControlSignal::PeerReady(Box::new(new_peer), result_chan)).await;
+                }
+                (Err(e), Some(result_chan)) => {
+                    let _ = result_chan.send(Err(e));
+                }
+                (Err(e), None) => warn!("Dropped incoming peer: {}", e),
+            }
+        })
+            .detach();
+    }
+
+    /// Start reading from and writing to a peer that passed the handshake, and register it
+    fn add_peer(&mut self, new_peer: NewPeer, ex: &Executor<'_>) -> std::io::Result<peer::Handle> {
+        let NewPeer { stream, direction, version, session } = new_peer;
+        let peer_addr = stream.peer_addr()?;
+        let (mut sealer, mut opener, remote_identity) = match session {
+            Some(session) => {
+                info!("Peer {} has identity {}", peer_addr, hex::encode(session.remote));
+                (Some(session.sealer), Some(session.opener), Some(session.remote))
+            }
+            None => (None, None, None),
+        };
+
+        let (write_queue, mut handle) = peer::new(peer_addr, version, remote_identity, direction);
+        // the identity of the peer is only known now, and it may have been banned during the
+        // handshake
+        let ban_key = handle.ban_key();
+        if self.banman.lock().unwrap().is_banned(&ban_key) {
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied, format!("peer {} ({}) is banned", peer_addr, ban_key)));
+        }
+        // only a peer that passed the handshake takes the place of another
+        if handle.is_outbound() {
+            let outbound = self.count(peer::Direction::Outgoing);
+            if outbound >= self.limits.max_outbound {
+                return Err(io::Error::other(format!("{} outbound connections already, the limit", outbound)));
+            }
+        } else if self.count(peer::Direction::Incoming) >= self.limits.max_inbound {
+            self.evict_inbound()?;
+        }
+        if handle.is_outbound() {
+            // learn about more peers from the ones we choose to connect to
+            handle.write(message::Message::GetAddr);
+        }
         let new_msg_chan = self.new_msg_chan.clone();
         let handle_copy = handle.clone();
         let control_chan = self.control_sender.clone();
-        let addr = stream.get_ref().peer_addr()?;
+        let addr = peer_addr;
 
         // start the reactor for this peer
         // first, start a task that keeps reading from this guy
-        let mut reader = BufReader::new(stream.clone());
+        let closer = stream.closer()?;
+        let (reader, writer) = stream.split();
+        let mut reader = BufReader::new(reader);
+        let magic = self.magic;
+        let banman = Arc::clone(&self.banman);
+        let trace = self.trace.clone();
         ex.spawn(async move {
-            // the buffer to store the frame header, which contains the length of the frame
-            let mut size_buffer: [u8; 4] = [0; 4];
-            // the buffer to store the message content
-            let mut msg_buffer: Vec<u8> = vec![];
             loop {
-                // first, read exactly 4 bytes to get the frame header
-                let msg_size = match reader.read_exact(&mut size_buffer).await {
-                    Ok(_) => u32::from_be_bytes(size_buffer),
-                    Err(_) => {
-                        break;
-                    }
-                };
-                // then, read exactly msg_size bytes to get the whole message
-                if msg_buffer.len() < msg_size as usize {
-                    msg_buffer.resize(msg_size as usize, 0);
-                }
-                match reader
-                    .read_exact(&mut msg_buffer[0..msg_size as usize])
-                    .await
-                {
-                    Ok(_) => {
-                        let new_payload: Vec<u8> = msg_buffer[0..msg_size as usize].to_vec();
+                match secure::read_frame(&mut reader, &magic, opener.as_mut()).await {
+                    Ok((_, payload)) => {
+                        if let Some(trace) = &trace {
+                            if let Err(e) = trace.record(addr, &payload) {
+                                warn!("Error recording frame from {}: {}", addr, e);
+                            }
+                        }
                         new_msg_chan
-                            .send((new_payload, handle_copy.clone()))
+                            .send((payload, handle_copy.clone()))
                             .await
                             .unwrap();
                     }
+                    Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                        // a peer that breaks the framing cannot be read any further
+                        warn!("Invalid frame from {}: {}", addr, e);
+                        banman.lock().unwrap().misbehaving(handle_copy.ban_key(), Misbehavior::InvalidFrame);
+                        break;
+                    }
                     Err(_) => {
                         break;
                     }
                 }
             }
-            // the peer is disconnected
+            // the peer is disconnected, let the writer clean up
+            handle_copy.close();
         })
             .detach();
 
         // second, start a task that keeps writing to this guy
-        let mut writer = BufWriter::new(stream.clone());
+        let mut writer = BufWriter::new(writer);
         ex.spawn(async move {
             loop {
                 // first, get a message to write from the queue
-                let new_msg = write_queue.next().await.unwrap();
-
-                // second, encode the length of the message
-                let size_buffer = (new_msg.len() as u32).to_be_bytes();
+                let new_msg = match write_queue.pop().await {
+                    Some(msg) => msg,
+                    None => break,
+                };
 
-                // third, write the frame header and the payload
-                match writer.write_all(&size_buffer).await {
-                    Ok(_) => {}
-                    Err(_) => {
-                        break;
-                    }
-                }
-                match writer.write_all(&new_msg).await {
-                    Ok(_) => {}
-                    Err(_) => {
-                        break;
-                    }
-                }
-                match writer.flush().await {
-                    Ok(_) => {}
-                    Err(_) => {
-                        break;
-                    }
+                // then, write it as a frame
+                if secure::write_frame(&mut writer, &magic, sealer.as_mut(), &new_msg).await.is_err() {
+                    break;
                 }
             }
-            // the peer is disconnected
+            // the peer is disconnected, or we closed the queue to drop it: close the socket so
+            // that the reader stops too
+            closer();
             control_chan
                 .send(ControlSignal::DroppedPeer(addr))
                 .await
@@ -228,6 +469,127 @@ impl Context {
     }
 }
 
+/// A peer that passed the handshake, for the control loop to take in
+struct NewPeer {
+    stream: BoxStream,
+    direction: peer::Direction,
+    version: message::Version,
+    session: Option<secure::Session>,
+}
+
+/// Check the IP of a new peer against the bans and exchange versions with it, giving up after
+/// `HANDSHAKE_TIMEOUT`. Bans of identities are checked once the peer is added.
+async fn handshake_peer(
+    mut stream: BoxStream,
+    direction: peer::Direction,
+    magic: &frame::Magic,
+    local: message::Version,
+    identity: Option<&Ed25519KeyPair>,
+    allowlist: Option<&Allowlist>,
+    banman: &Mutex<BanManager>,
+) -> io::Result<NewPeer> {
+    let peer_addr = stream.peer_addr()?;
+    if banman.lock().unwrap().is_banned(&BanKey::Ip(peer_addr.ip())) {
+        return Err(io::Error::new(io::ErrorKind::PermissionDenied, format!("peer {} is banned", peer_addr)));
+    }
+
+    // exchange versions before anything else, and drop the peer if it is not on our network
+    let initiator = matches!(direction, peer::Direction::Outgoing);
+    let handshake = handshake(&mut stream, magic, local, identity, allowlist, initiator);
+    let timeout = async {
+        Timer::after(HANDSHAKE_TIMEOUT).await;
+        Err(io::Error::new(io::ErrorKind::TimedOut, "handshake timed out"))
+    };
+    let (version, session) = smol::future::or(handshake, timeout)
+        .await
+        .map_err(|e| io::Error::new(e.kind(), format!("handshake with {} failed: {}", peer_addr, e)))?;
+    info!(
+        "Handshake with {} done, protocol version {}, height {}",
+        peer_addr, version.version, version.height
+    );
+    Ok(NewPeer { stream, direction, version, session })
+}
+
+/// Exchange `Version` and `VerAck` with a new peer, and return the version of the peer if it is
+/// compatible with ours. With an `identity`, the connection is encrypted first, and the session
+/// with the identity of the peer is returned too. With an `allowlist`, peers that are not on it
+/// are refused before they learn anything about this node.
+async fn handshake<S>(
+    mut stream: S,
+    magic: &frame::Magic,
+    local: message::Version,
+    identity: Option<&Ed25519KeyPair>,
+    allowlist: Option<&Allowlist>,
+    initiator: bool,
+) -> io::Result<(message::Version, Option<secure::Session>)>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let secrets = allowlist.map(|allowlist| allowlist.secrets()).unwrap_or_default();
+    let mut session = match identity {
+        Some(identity) => Some(secure::handshake(&mut stream, magic, identity, secrets, initiator).await?),
+        None => None,
+    };
+    if let Some(allowlist) = allowlist {
+        match &session {
+            Some(session) if allowlist.admits(session) => {}
+            Some(session) => {
+                warn!("Rejecting peer with identity {}, it is not on the allowlist", hex::encode(session.remote));
+                return Err(io::Error::new(io::ErrorKind::PermissionDenied, "peer is not on the allowlist"));
+            }
+            None => {
+                return Err(io::Error::new(io::ErrorKind::PermissionDenied, "private network needs encryption"));
+            }
+        }
+    }
+
+    let version = message::Message::Version(local.clone());
+    secure::write_frame(&mut stream, magic, session.as_mut().map(|s| &mut s.sealer), &version).await?;
+    let remote = match read_message(&mut stream, magic, session.as_mut()).await? {
+        message::Message::Version(version) => version,
+        _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "expected a Version message")),
+    };
+    check_version(&local, &remote)?;
+
+    let verack = message::Message::VerAck;
+    secure::write_frame(&mut stream, magic, session.as_mut().map(|s| &mut s.sealer), &verack).await?;
+    match read_message(&mut stream, magic, session.as_mut()).await? {
+        message::Message::VerAck => Ok((remote, session)),
+        _ => Err(io::Error::new(io::ErrorKind::InvalidData, "expected a VerAck message")),
+    }
+}
+
+/// Check that a peer with version `remote` can talk to us
+fn check_version(local: &message::Version, remote: &message::Version) -> io::Result<()> {
+    if remote.version < message::MIN_PROTOCOL_VERSION {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("incompatible protocol version {}", remote.version),
+        ));
+    }
+    if remote.genesis != local.genesis {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("different genesis block {}", remote.genesis),
+        ));
+    }
+    if remote.node_id == local.node_id {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "connected to ourselves"));
+    }
+
+    Ok(())
+}
+
+/// Read and decode a single message, encrypted if there is a session
+async fn read_message<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    magic: &frame::Magic,
+    session: Option<&mut secure::Session>,
+) -> io::Result<message::Message> {
+    let (_, payload) = secure::read_frame(stream, magic, session.map(|s| &mut s.opener)).await?;
+    bincode::deserialize(&payload).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
 #[derive(Clone)]
 pub struct Handle {
     control_chan: smol::channel::Sender<ControlSignal>,
@@ -263,8 +625,36 @@ impl Handle {
         smol::block_on(self.control_chan.send(ControlSignal::BroadcastMessage(msg))).unwrap();
     }
 
-    pub fn send(&self, receiver: Address, msg: message::Message) {
-        smol::block_on(self.control_chan.send(ControlSignal::SendToPeer((receiver, msg)))).unwrap();
+    /// Get the handles of all connected peers
+    pub fn peers(&self) -> Vec<peer::Handle> {
+        let (sender, receiver) = oneshot::channel();
+        smol::block_on(self.control_chan.send(ControlSignal::GetPeers(sender))).unwrap();
+        smol::block_on(receiver).unwrap_or_default()
+    }
+
+    /// Send a message to a single peer, given by its address. Fails if the peer is unknown or has
+    /// disconnected.
+    pub fn send(&self, peer: std::net::SocketAddr, msg: message::Message) -> std::io::Result<()> {
+        let (sender, receiver) = oneshot::channel();
+        smol::block_on(self.control_chan.send(ControlSignal::SendToPeer(peer, msg, sender))).unwrap();
+        match smol::block_on(receiver) {
+            Ok(result) => result,
+            Err(_) => Err(io::Error::other("P2P server dropped the request")),
+        }
+    }
+
+    /// A handle to no running server, for driving a worker offline. Messages sent through it are
+    /// dropped.
+    pub fn offline() -> Handle {
+        let (sender, receiver) = smol::channel::unbounded();
+        thread::spawn(move || {
+            while let Ok(signal) = smol::block_on(receiver.recv()) {
+                if let ControlSignal::BroadcastMessage(msg) = signal {
+                    trace!("Offline server drops broadcast {:?}", msg);
+                }
+            }
+        });
+        Handle { control_chan: sender }
     }
 
     #[cfg(any(test,test_utilities))]
@@ -282,7 +672,363 @@ enum ControlSignal {
         oneshot::Sender<std::io::Result<peer::Handle>>,
     ),
     BroadcastMessage(message::Message),
-    GetNewPeer(Async<net::TcpStream>),
+    GetNewPeer(BoxStream),
+    PeerConnected(BoxStream, oneshot::Sender<std::io::Result<peer::Handle>>),
+    PeerReady(Box<NewPeer>, Option<oneshot::Sender<std::io::Result<peer::Handle>>>),
     DroppedPeer(std::net::SocketAddr),
-    SendToPeer((Address,message::Message)),
+    PingPeers,
+    GetPeers(oneshot::Sender<Vec<peer::Handle>>),
+    SendToPeer(
+        std::net::SocketAddr,
+        message::Message,
+        oneshot::Sender<std::io::Result<()>>,
+    ),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::consensus::genesis::GenesisSpec;
+    use crate::consensus::pow::ProofOfWork;
+    use crate::network::banman::Misbehavior;
+    use crate::network::transport::MemoryTransport;
+    use crate::types::key_pair;
+    use ntest::timeout;
+    use std::net;
+
+    fn free_addr() -> net::SocketAddr {
+        net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap()
+    }
+
+    type MessageReceiver = smol::channel::Receiver<(Vec<u8>, peer::Handle)>;
+
+    /// The peers of `server` once `done` says so. The end that accepted a connection registers
+    /// the peer a little after the end that dialed it.
+    fn wait_for_peers(server: &Handle, done: impl Fn(&[peer::Handle]) -> bool) -> Vec<peer::Handle> {
+        loop {
+            let peers = server.peers();
+            if done(&peers) {
+                return peers;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    /// A server to test against, with the defaults unless told otherwise
+    struct TestServer {
+        addr: net::SocketAddr,
+        spec: GenesisSpec,
+        banman: Arc<Mutex<BanManager>>,
+        identity: Option<Ed25519KeyPair>,
+        allowlist: Option<Allowlist>,
+        limits: ConnectionLimits,
+        transport: Option<Arc<dyn Transport>>,
+    }
+
+    impl TestServer {
+        fn new(addr: net::SocketAddr) -> Self {
+            Self {
+                addr,
+                spec: GenesisSpec::default(),
+                banman: Arc::new(Mutex::new(BanManager::new(Duration::from_secs(60)))),
+                identity: None,
+                allowlist: None,
+                limits: ConnectionLimits::default(),
+                transport: None,
+            }
+        }
+
+        fn with_spec(mut self, spec: GenesisSpec) -> Self {
+            self.spec = spec;
+            self
+        }
+
+        fn with_banman(mut self, banman: &Arc<Mutex<BanManager>>) -> Self {
+            self.banman = Arc::clone(banman);
+            self
+        }
+
+        fn with_identity(mut self, identity: Ed25519KeyPair) -> Self {
+            self.identity = Some(identity);
+            self
+        }
+
+        fn with_allowlist(mut self, allowlist: Allowlist) -> Self {
+            self.allowlist = Some(allowlist);
+            self
+        }
+
+        fn with_limits(mut self, limits: ConnectionLimits) -> Self {
+            self.limits = limits;
+            self
+        }
+
+        fn with_transport(mut self, transport: Arc<dyn Transport>) -> Self {
+            self.transport = Some(transport);
+            self
+        }
+
+        fn start(self) -> (Handle, MessageReceiver) {
+            let (msg_tx, msg_rx) = smol::channel::unbounded();
+            let blockchain = Arc::new(Mutex::new(Blockchain::with_consensus(Arc::new(ProofOfWork::new(&self.spec).unwrap()), &self.spec)));
+            let identity = self.identity.map(Arc::new);
+            let allowlist = self.allowlist.map(|allowlist| Arc::new(Mutex::new(allowlist)));
+            let (mut ctx, server) =
+                new(self.addr, msg_tx, &blockchain, &self.banman, RelayPolicy::Flood, identity, allowlist, self.limits).unwrap();
+            if let Some(transport) = self.transport {
+                ctx = ctx.with_transport(transport);
+            }
+            ctx.start().unwrap();
+            (server, msg_rx)
+        }
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn send_to_one_peer() {
+        let (server, _) = TestServer::new(free_addr()).start();
+        let peer_addr = free_addr();
+        let (_peer_server, peer_msg_rx) = TestServer::new(peer_addr).start();
+
+        let peer = server.connect(peer_addr).unwrap();
+        assert_eq!(peer.version().height, 1);
+        assert_eq!(peer.version().listen_addr, Some(peer_addr));
+        server.send(*peer.addr(), message::Message::Ping(42)).unwrap();
+        // the peer first gets the GetAddr that every outgoing connection starts with
+        let (bytes, _) = smol::block_on(peer_msg_rx.recv()).unwrap();
+        assert!(matches!(bincode::deserialize(&bytes).unwrap(), message::Message::GetAddr));
+        let (bytes, _) = smol::block_on(peer_msg_rx.recv()).unwrap();
+        match bincode::deserialize(&bytes).unwrap() {
+            message::Message::Ping(nonce) => assert_eq!(nonce, 42),
+            _ => panic!(),
+        }
+
+        let err = server.send(free_addr(), message::Message::Ping(42)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn silent_peer_does_not_stall() {
+        let addr = free_addr();
+        let (server, _) = TestServer::new(addr).start();
+        let peer_addr = free_addr();
+        let (_peer_server, _) = TestServer::new(peer_addr).start();
+
+        // connects and never says a word
+        let _silent = net::TcpStream::connect(addr).unwrap();
+        thread::sleep(Duration::from_millis(100));
+        let start = std::time::Instant::now();
+        assert!(server.peers().is_empty());
+        server.connect(peer_addr).unwrap();
+        assert!(start.elapsed() < HANDSHAKE_TIMEOUT);
+    }
+
+    /// Takes connections over memory, but dials into the void
+    struct BlackHole(MemoryTransport);
+
+    impl Transport for BlackHole {
+        fn bind(&self, addr: net::SocketAddr) -> io::Result<Box<dyn Listener>> {
+            self.0.bind(addr)
+        }
+
+        fn connect(&self, _addr: net::SocketAddr) -> futures::future::BoxFuture<'static, io::Result<BoxStream>> {
+            Box::pin(futures::future::pending())
+        }
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn connect_times_out() {
+        let addr = net::SocketAddr::from(([10, 0, 0, 1], 6000));
+        let (server, _msg_rx) = TestServer::new(addr).with_transport(Arc::new(BlackHole(MemoryTransport::new()))).start();
+
+        let dialer = server.clone();
+        let dial = thread::spawn(move || dialer.connect(net::SocketAddr::from(([10, 0, 0, 2], 6000))));
+        thread::sleep(Duration::from_millis(100));
+        // the server keeps answering while the dial hangs
+        assert!(server.peers().is_empty());
+        assert!(!dial.is_finished());
+        let err = dial.join().unwrap().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn reject_other_genesis() {
+        let (server, _) = TestServer::new(free_addr()).start();
+        let peer_addr = free_addr();
+        let other_spec = GenesisSpec { block_reward: 1, ..Default::default() };
+        let (_peer_server, _) = TestServer::new(peer_addr).with_spec(other_spec).start();
+
+        let err = server.connect(peer_addr).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn refuse_banned_peer() {
+        let banman = Arc::new(Mutex::new(BanManager::new(Duration::from_secs(60))));
+        let (server, _) = TestServer::new(free_addr()).with_banman(&banman).start();
+        let peer_addr = free_addr();
+        let (_peer_server, _) = TestServer::new(peer_addr).start();
+
+        let peer = server.connect(peer_addr).unwrap();
+        let mut banman = banman.lock().unwrap();
+        assert!(!banman.misbehaving(peer.ban_key(), Misbehavior::InvalidBlock));
+        assert!(banman.misbehaving(peer.ban_key(), Misbehavior::InvalidBlock));
+        drop(banman);
+
+        let err = server.connect(peer_addr).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn refuse_banned_peer_from_another_port() {
+        let banman = Arc::new(Mutex::new(BanManager::new(Duration::from_secs(60))));
+        let addr = free_addr();
+        let (server, _msg_rx) = TestServer::new(addr).with_banman(&banman).start();
+        let (peer_server, _peer_msg_rx) = TestServer::new(free_addr()).start();
+
+        peer_server.connect(addr).unwrap();
+        let peer = wait_for_peers(&server, |peers| peers.len() == 1).remove(0);
+        let mut banman_guard = banman.lock().unwrap();
+        assert!(!banman_guard.misbehaving(peer.ban_key(), Misbehavior::InvalidBlock));
+        assert!(banman_guard.misbehaving(peer.ban_key(), Misbehavior::InvalidBlock));
+        drop(banman_guard);
+        peer.close();
+        wait_for_peers(&server, |peers| peers.is_empty());
+        wait_for_peers(&peer_server, |peers| peers.is_empty());
+
+        // the new connection comes from another port, the ban is on the IP
+        assert!(peer_server.connect(addr).is_err());
+        assert!(server.peers().is_empty());
+        assert!(banman.lock().unwrap().is_banned(&peer.ban_key()));
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn encrypted_connection() {
+        let banman = Arc::new(Mutex::new(BanManager::new(Duration::from_secs(60))));
+        let (server, _) = TestServer::new(free_addr()).with_banman(&banman).with_identity(key_pair::random()).start();
+        let peer_addr = free_addr();
+        let peer_key = key_pair::random();
+        let peer_identity = peer_key.public_key().as_ref().to_vec();
+        let (_peer_server, peer_msg_rx) = TestServer::new(peer_addr).with_banman(&banman).with_identity(peer_key).start();
+
+        let peer = server.connect(peer_addr).unwrap();
+        assert_eq!(&peer.identity().unwrap()[..], &peer_identity[..]);
+        server.send(*peer.addr(), message::Message::Ping(42)).unwrap();
+        let (bytes, _) = smol::block_on(peer_msg_rx.recv()).unwrap();
+        assert!(matches!(bincode::deserialize(&bytes).unwrap(), message::Message::GetAddr));
+        let (bytes, from) = smol::block_on(peer_msg_rx.recv()).unwrap();
+        assert!(matches!(bincode::deserialize(&bytes).unwrap(), message::Message::Ping(42)));
+        assert!(from.identity().is_some());
+
+        // a node without encryption cannot connect
+        let plain_addr = free_addr();
+        let (_plain_server, _) = TestServer::new(plain_addr).start();
+        let err = server.connect(plain_addr).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn private_network() {
+        let path = std::env::temp_dir().join(format!("allowlist-{}", rand::random::<u64>()));
+        let member_key = key_pair::random();
+        std::fs::write(&path, format!("{}\nsecret:members only\n", hex::encode(member_key.public_key().as_ref()))).unwrap();
+        let banman = Arc::new(Mutex::new(BanManager::new(Duration::from_secs(60))));
+        let private_addr = free_addr();
+        let allowlist = Allowlist::load(&path).unwrap();
+        let (_private_server, _private_msg_rx) =
+            TestServer::new(private_addr).with_banman(&banman).with_identity(key_pair::random()).with_allowlist(allowlist).start();
+
+        // in by identity
+        let (member, _) = TestServer::new(free_addr()).with_banman(&banman).with_identity(member_key).start();
+        assert!(member.connect(private_addr).is_ok());
+
+        // in by secret
+        let secret_holder = Allowlist::load(&path).unwrap();
+        let (other_member, _) =
+            TestServer::new(free_addr()).with_banman(&banman).with_identity(key_pair::random()).with_allowlist(secret_holder).start();
+        assert!(other_member.connect(private_addr).is_ok());
+
+        // everyone else is out
+        let (stranger, _) = TestServer::new(free_addr()).with_banman(&banman).with_identity(key_pair::random()).start();
+        assert!(stranger.connect(private_addr).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn connection_limits() {
+        let banman = Arc::new(Mutex::new(BanManager::new(Duration::from_secs(60))));
+        let addr = free_addr();
+        let limits = ConnectionLimits { max_inbound: 1, max_outbound: 1 };
+        let (server, _msg_rx) = TestServer::new(addr).with_banman(&banman).with_limits(limits).start();
+
+        // a new inbound peer takes the place of the one that has done nothing for us yet
+        let (first, _first_rx) = TestServer::new(free_addr()).start();
+        let second_addr = free_addr();
+        let (second, _second_rx) = TestServer::new(second_addr).start();
+        first.connect(addr).unwrap();
+        wait_for_peers(&server, |peers| peers.len() == 1);
+        second.connect(addr).unwrap();
+        let peers = wait_for_peers(&server, |peers| !peers.is_empty() && peers.iter().all(|peer| peer.listen_addr() == Some(second_addr)));
+        assert_eq!(peers.len(), 1);
+        assert_eq!(peers[0].direction(), peer::Direction::Incoming);
+        assert_eq!(peers[0].listen_addr(), Some(second_addr));
+        // one that fails the handshake takes nobody's place
+        let other_spec = GenesisSpec { block_reward: 1, ..Default::default() };
+        let (stranger, _stranger_rx) = TestServer::new(free_addr()).with_spec(other_spec).start();
+        assert!(stranger.connect(addr).is_err());
+        let peers = server.peers();
+        assert_eq!(peers.len(), 1);
+        assert_eq!(peers[0].listen_addr(), Some(second_addr));
+
+        // outbound connections stop at the limit, whatever the inbound ones
+        let outbound_addr = free_addr();
+        let (_outbound, _outbound_rx) = TestServer::new(outbound_addr).start();
+        let outbound_peer = server.connect(outbound_addr).unwrap();
+        assert!(outbound_peer.is_outbound());
+        assert!(server.connect(free_addr()).is_err());
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn memory_transport() {
+        let transport: Arc<dyn Transport> = Arc::new(MemoryTransport::new());
+        let start = |addr: net::SocketAddr| TestServer::new(addr).with_transport(Arc::clone(&transport)).start();
+        // no sockets, so the addresses need not be free
+        let (server, _msg_rx) = start(net::SocketAddr::from(([10, 0, 0, 1], 6000)));
+        let peer_addr = net::SocketAddr::from(([10, 0, 0, 2], 6000));
+        let (peer_server, peer_msg_rx) = start(peer_addr);
+
+        let peer = server.connect(peer_addr).unwrap();
+        assert_eq!(*peer.addr(), peer_addr);
+        server.send(peer_addr, message::Message::Ping(42)).unwrap();
+        let (bytes, _) = smol::block_on(peer_msg_rx.recv()).unwrap();
+        assert!(matches!(bincode::deserialize(&bytes).unwrap(), message::Message::GetAddr));
+        let (bytes, from) = smol::block_on(peer_msg_rx.recv()).unwrap();
+        assert!(matches!(bincode::deserialize(&bytes).unwrap(), message::Message::Ping(42)));
+        assert_eq!(from.listen_addr(), Some(net::SocketAddr::from(([10, 0, 0, 1], 6000))));
+        assert_eq!(peer_server.peers().len(), 1);
+        assert!(server.connect(net::SocketAddr::from(([10, 0, 0, 3], 6000))).is_err());
+    }
+
+    #[test]
+    fn check_versions() {
+        let local = message::Version {
+            version: message::PROTOCOL_VERSION,
+            node_id: 1,
+            ..Default::default()
+        };
+        let remote = message::Version { node_id: 2, ..local.clone() };
+        assert!(check_version(&local, &remote).is_ok());
+        assert!(check_version(&local, &message::Version { version: 0, ..remote.clone() }).is_err());
+        assert!(check_version(&local, &message::Version { genesis: [1; 32].into(), ..remote.clone() }).is_err());
+        assert!(check_version(&local, &local).is_err());
+    }
 }
@@ -0,0 +1,452 @@
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::{debug, info, warn};
+use serde::Serialize;
+
+use super::message::Message;
+use super::peer;
+use super::request::RequestManager;
+use super::server::Handle as ServerHandle;
+use crate::blockchain::Blockchain;
+use crate::types::block::Header;
+use crate::types::hash::{H256, Hashable};
+
+/// Most headers sent in one `Headers` message. A full message means the peer has more.
+pub const MAX_HEADERS_PER_MESSAGE: usize = 2000;
+/// Most headers kept whose blocks are not in the blockchain yet. Peers with more headers are asked
+/// for the rest once enough of the blocks came in.
+const MAX_PENDING_HEADERS: usize = 10 * MAX_HEADERS_PER_MESSAGE;
+/// Most block bodies requested from one peer at a time
+const MAX_BLOCKS_IN_FLIGHT_PER_PEER: usize = 16;
+/// How long a peer has to answer a request before it is sent to another peer
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+/// How often the sync driver checks for work
+pub const TICK_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Sync progress, as reported by the API
+#[derive(Serialize, Debug, Clone)]
+pub struct SyncStatus {
+    /// Whether headers or blocks are still being downloaded
+    pub syncing: bool,
+    /// Length of the best validated header chain
+    pub header_height: u32,
+    /// Length of the best chain of full blocks
+    pub block_height: u32,
+    /// Headers whose blocks are not in the blockchain yet
+    pub blocks_missing: usize,
+    pub blocks_in_flight: usize,
+    /// Peer the headers are being downloaded from
+    pub headers_peer: Option<SocketAddr>,
+}
+
+/// Headers-first block download. The header chain is downloaded from one peer with `GetHeaders`
+/// and checked first, then the block bodies are fetched from all peers that have them, a few
+/// at a time from each.
+pub struct SyncManager {
+    // validated headers whose blocks are not in the blockchain yet, with their lengths
+    headers: HashMap<H256, (Header, u32)>,
+    best_header: Option<(H256, u32)>,
+    headers_request: Option<(SocketAddr, Instant)>,
+    // block bodies requested from peers, by the sync or after announcements
+    requests: RequestManager,
+    // blocks that arrived but wait for their parent to be connected
+    received: HashSet<H256>,
+    // best chain length each peer is known to have
+    peer_heights: HashMap<SocketAddr, u32>,
+}
+
+impl SyncManager {
+    pub fn new() -> Self {
+        Self {
+            headers: HashMap::new(),
+            best_header: None,
+            headers_request: None,
+            requests: RequestManager::new(REQUEST_TIMEOUT),
+            received: HashSet::new(),
+            peer_heights: HashMap::new(),
+        }
+    }
+
+    /// Whether `hash` is a downloaded header whose block is still to come
+    pub fn has_header(&self, hash: &H256) -> bool {
+        self.headers.contains_key(hash)
+    }
+
+    /// Block locator to ask for headers with, starting from the best downloaded header
+    pub fn locator(&self, blockchain: &Blockchain) -> Vec<H256> {
+        let mut locator = blockchain.block_locator();
+        if let Some((best_header, _)) = self.best_header {
+            if self.headers.contains_key(&best_header) {
+                locator.insert(0, best_header);
+            }
+        }
+        locator
+    }
+
+    fn header_height(&self, blockchain: &Blockchain) -> u32 {
+        let block_height = tip_length(blockchain);
+        match self.best_header {
+            Some((_, length)) if length > block_height => length,
+            _ => block_height,
+        }
+    }
+
+    /// Validate and store headers sent by `peer`. Returns whether the peer may have more headers.
+    pub fn on_headers(&mut self, peer: SocketAddr, headers: Vec<Header>, blockchain: &Blockchain) -> Result<bool, String> {
+        if let Some((requested_from, _)) = self.headers_request {
+            if requested_from == peer {
+                self.headers_request = None;
+            }
+        }
+        let more = headers.len() == MAX_HEADERS_PER_MESSAGE;
+        if headers.is_empty() {
+            // the peer has nothing we do not have, whatever height it claimed
+            let header_height = self.header_height(blockchain);
+            if let Some(height) = self.peer_heights.get_mut(&peer) {
+                *height = (*height).min(header_height);
+            }
+        }
+
+        let consensus = blockchain.consensus();
+        for header in headers {
+            let hash = header.hash();
+            if blockchain.get_block(&hash).is_some() || self.headers.contains_key(&hash) {
+                continue;
+            }
+            let (parent, parent_length) = match blockchain.get_block(&header.parent) {
+                Some(block) => (&block.header, block.length),
+                None => match self.headers.get(&header.parent) {
+                    Some((header, length)) => (header, *length),
+                    None => return Err(format!("header {:?} does not connect to known headers", hash)),
+                },
+            };
+            if !consensus.verify_header(&header, parent) {
+                return Err(format!("header {:?} has an invalid seal", hash));
+            }
+            if self.headers.len() >= MAX_PENDING_HEADERS {
+                debug!("Too many pending headers, ignoring the rest from {}", peer);
+                return Ok(false);
+            }
+
+            let length = parent_length + 1;
+            self.headers.insert(hash, (header, length));
+            let height = self.peer_heights.entry(peer).or_insert(0);
+            *height = (*height).max(length);
+            if self.best_header.is_none_or(|(_, best_length)| length > best_length) {
+                self.best_header = Some((hash, length));
+            }
+        }
+
+        Ok(more)
+    }
+
+    /// Whether the block with this hash is requested from some peer
+    pub fn is_requested(&self, hash: &H256) -> bool {
+        self.requests.contains(hash)
+    }
+
+    /// Request blocks `peer` announced. Returns the hashes to ask the peer for, leaving out those
+    /// requested from another peer already, for which `peer` becomes a fallback.
+    pub fn request_blocks(&mut self, peer: SocketAddr, hashes: &[H256], now: Instant) -> Vec<H256> {
+        hashes.iter().filter(|hash| self.requests.request(**hash, peer, now)).cloned().collect()
+    }
+
+    /// Match a block `peer` sent against the requests. Returns false if the block was not
+    /// requested from that peer. Requested blocks are not requested again while they wait for
+    /// their parents.
+    pub fn on_block(&mut self, peer: &SocketAddr, hash: &H256) -> bool {
+        let solicited = self.requests.received(hash, peer);
+        if solicited && self.headers.contains_key(hash) {
+            self.received.insert(*hash);
+        }
+        solicited
+    }
+
+    /// Drop the headers that are not on a fork longer than `tip_length`
+    fn forget_lost_forks(&mut self, tip_length: u32) {
+        let mut alive = HashSet::new();
+        for (hash, (_, length)) in self.headers.iter() {
+            if *length <= tip_length {
+                continue;
+            }
+            let mut hash = *hash;
+            while alive.insert(hash) {
+                match self.headers.get(&hash) {
+                    Some((header, _)) => hash = header.parent,
+                    None => break,
+                }
+            }
+        }
+        self.headers.retain(|hash, _| alive.contains(hash));
+    }
+
+    /// Update the view of the connected peers and return the requests to send out
+    pub fn tick(&mut self, blockchain: &Blockchain, peers: &[peer::Handle], now: Instant) -> Vec<(SocketAddr, Message)> {
+        let connected: HashSet<SocketAddr> = peers.iter().map(|peer| *peer.addr()).collect();
+        self.peer_heights.retain(|addr, _| connected.contains(addr));
+        for peer in peers {
+            self.peer_heights.entry(*peer.addr()).or_insert(peer.version().height);
+        }
+
+        // forget the blocks that made it into the blockchain, and the forks that are no longer than
+        // our chain, which lost
+        self.headers.retain(|hash, _| blockchain.get_block(hash).is_none());
+        self.forget_lost_forks(tip_length(blockchain));
+        let headers = &self.headers;
+        self.received.retain(|hash| headers.contains_key(hash));
+        self.requests.retain(|hash| blockchain.get_block(hash).is_none());
+        if let Some((addr, requested)) = self.headers_request {
+            if !connected.contains(&addr) || now.duration_since(requested) >= REQUEST_TIMEOUT {
+                debug!("Headers request to {} timed out", addr);
+                self.headers_request = None;
+            }
+        }
+
+        let mut requests = Vec::new();
+
+        // ask the best peer for headers if it is ahead of us, an outbound one among those at the
+        // same height since an attacker has a harder time getting picked by us. The remaining ties
+        // go to the lowest address, so that the choice does not depend on the hash map order.
+        if self.headers_request.is_none() {
+            let outbound: HashSet<SocketAddr> = peers.iter().filter(|peer| peer.is_outbound()).map(|peer| *peer.addr()).collect();
+            let best_peer = self
+                .peer_heights
+                .iter()
+                .max_by_key(|(addr, height)| (**height, outbound.contains(addr), std::cmp::Reverse(**addr)));
+            if let Some((addr, height)) = best_peer {
+                if *height > self.header_height(blockchain) {
+                    info!("Downloading headers from {} at height {}", addr, height);
+                    self.headers_request = Some((*addr, now));
+                    requests.push((*addr, Message::GetHeaders(self.locator(blockchain))));
+                }
+            }
+        }
+
+        // send the requests that timed out to other peers that have the blocks
+        let peer_heights = &self.peer_heights;
+        let retries = self.requests.retry(now, &connected, |hash| match headers.get(hash) {
+            Some((_, length)) => peer_heights.iter().filter(|(_, height)| **height >= *length).map(|(addr, _)| *addr).collect(),
+            None => vec![],
+        });
+        let mut batches: HashMap<SocketAddr, Vec<H256>> = HashMap::new();
+        for (addr, hash) in retries {
+            batches.entry(addr).or_default().push(hash);
+        }
+
+        // spread the missing bodies over the peers that have them, the oldest first
+        let mut missing: Vec<(&H256, u32)> = self
+            .headers
+            .iter()
+            .filter(|(hash, _)| !self.requests.contains(hash) && !self.received.contains(hash))
+            .map(|(hash, (_, length))| (hash, *length))
+            .collect();
+        missing.sort_by_key(|(hash, length)| (*length, **hash));
+        let mut load: HashMap<SocketAddr, usize> = self.peer_heights.keys().map(|addr| (*addr, self.requests.load(addr))).collect();
+        for (hash, length) in missing {
+            let peer = self
+                .peer_heights
+                .iter()
+                .filter(|(addr, height)| **height >= length && load.get(addr).copied().unwrap_or(0) < MAX_BLOCKS_IN_FLIGHT_PER_PEER)
+                .min_by_key(|(addr, _)| (load.get(addr).copied().unwrap_or(0), **addr))
+                .map(|(addr, _)| *addr);
+            let peer = match peer {
+                Some(peer) => peer,
+                None => continue,
+            };
+            *load.entry(peer).or_insert(0) += 1;
+            batches.entry(peer).or_default().push(*hash);
+        }
+        for (addr, hashes) in batches {
+            for hash in hashes.iter() {
+                self.requests.request(*hash, addr, now);
+            }
+            requests.push((addr, Message::GetBlocks(hashes)));
+        }
+
+        requests
+    }
+
+    pub fn status(&self, blockchain: &Blockchain) -> SyncStatus {
+        SyncStatus {
+            syncing: self.headers_request.is_some() || !self.headers.is_empty(),
+            header_height: self.header_height(blockchain),
+            block_height: tip_length(blockchain),
+            blocks_missing: self.headers.len(),
+            blocks_in_flight: self.requests.len(),
+            headers_peer: self.headers_request.map(|(addr, _)| addr),
+        }
+    }
+}
+
+impl Default for SyncManager {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn tip_length(blockchain: &Blockchain) -> u32 {
+    blockchain.get_block(&blockchain.tip()).unwrap().length
+}
+
+/// The thread that drives the sync, sending out the requests of the sync manager
+pub struct Context {
+    server: ServerHandle,
+    blockchain: Arc<Mutex<Blockchain>>,
+    sync: Arc<Mutex<SyncManager>>,
+}
+
+pub fn new(server: &ServerHandle, blockchain: &Arc<Mutex<Blockchain>>, sync: &Arc<Mutex<SyncManager>>) -> Context {
+    Context {
+        server: server.clone(),
+        blockchain: Arc::clone(blockchain),
+        sync: Arc::clone(sync),
+    }
+}
+
+impl Context {
+    pub fn start(self) {
+        thread::Builder::new()
+            .name("sync".to_string())
+            .spawn(move || loop {
+                self.tick();
+                thread::sleep(TICK_INTERVAL);
+            })
+            .unwrap();
+    }
+
+    fn tick(&self) {
+        let peers = self.server.peers();
+        let requests = {
+            let blockchain = self.blockchain.lock().unwrap();
+            self.sync.lock().unwrap().tick(&blockchain, &peers, Instant::now())
+        };
+        for (addr, msg) in requests {
+            if let Err(e) = self.server.send(addr, msg) {
+                warn!("Error sending sync request to {}: {}", addr, e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::consensus::AcceptAll;
+    use crate::consensus::genesis::GenesisSpec;
+    use crate::types::block::generate_random_block;
+
+    fn peer_at(height: u32, port: u16) -> peer::Handle {
+        let (handle, _) = peer::Handle::test_handle_at(SocketAddr::from(([127, 0, 0, 1], port)), height);
+        handle
+    }
+
+    #[test]
+    fn headers_then_bodies_from_several_peers() {
+        let mut source = Blockchain::with_consensus(Arc::new(AcceptAll), &GenesisSpec::default());
+        for _ in 0..40 {
+            let block = generate_random_block(&source.tip());
+            source.insert(&block);
+        }
+        let blockchain = Blockchain::with_consensus(Arc::new(AcceptAll), &GenesisSpec::default());
+        let mut sync = SyncManager::new();
+        let peers = vec![peer_at(41, 6001), peer_at(41, 6002), peer_at(10, 6003)];
+
+        // first the headers, from one of the peers at the best height
+        let requests = sync.tick(&blockchain, &peers, Instant::now());
+        assert_eq!(requests.len(), 1);
+        let (addr, locator) = match &requests[0] {
+            (addr, Message::GetHeaders(locator)) => (*addr, locator.clone()),
+            _ => panic!(),
+        };
+        assert_ne!(addr.port(), 6003);
+        let headers = source.headers_after(&locator, MAX_HEADERS_PER_MESSAGE);
+        assert_eq!(headers.len(), 40);
+        assert_eq!(sync.on_headers(addr, headers, &blockchain), Ok(false));
+        assert_eq!(sync.status(&blockchain).header_height, 41);
+
+        // then the bodies, spread over the peers that have them
+        let requests = sync.tick(&blockchain, &peers, Instant::now());
+        assert_eq!(requests.len(), 3);
+        for (addr, msg) in requests.iter() {
+            match msg {
+                Message::GetBlocks(hashes) if addr.port() == 6003 => {
+                    assert!(hashes.iter().all(|hash| sync.headers[hash].1 <= 10));
+                }
+                Message::GetBlocks(hashes) => assert_eq!(hashes.len(), MAX_BLOCKS_IN_FLIGHT_PER_PEER),
+                _ => panic!(),
+            }
+        }
+        assert!(sync.tick(&blockchain, &peers, Instant::now()).is_empty());
+    }
+
+    #[test]
+    fn forget_headers_of_lost_forks() {
+        let mut source = Blockchain::with_consensus(Arc::new(AcceptAll), &GenesisSpec::default());
+        for _ in 0..10 {
+            let block = generate_random_block(&source.tip());
+            source.insert(&block);
+        }
+        let mut blockchain = Blockchain::with_consensus(Arc::new(AcceptAll), &GenesisSpec::default());
+        for _ in 0..5 {
+            let block = generate_random_block(&blockchain.tip());
+            blockchain.insert(&block);
+        }
+        let mut sync = SyncManager::new();
+        let peers = vec![peer_at(11, 6001)];
+        let addr = *peers[0].addr();
+        let headers = source.headers_after(&blockchain.block_locator(), MAX_HEADERS_PER_MESSAGE);
+        assert_eq!(sync.on_headers(addr, headers, &blockchain), Ok(false));
+
+        // the fork of the peer is longer, even the headers below our height are needed
+        sync.tick(&blockchain, &peers, Instant::now());
+        assert_eq!(sync.headers.len(), 10);
+
+        // our chain overtook it, so the fork lost
+        for _ in 0..6 {
+            let block = generate_random_block(&blockchain.tip());
+            blockchain.insert(&block);
+        }
+        sync.tick(&blockchain, &peers, Instant::now());
+        assert!(sync.headers.is_empty());
+        assert!(!sync.status(&blockchain).syncing);
+    }
+
+    #[test]
+    fn prefer_outbound_peers_for_headers() {
+        let blockchain = Blockchain::with_consensus(Arc::new(AcceptAll), &GenesisSpec::default());
+        let mut sync = SyncManager::new();
+        let mut peers: Vec<peer::Handle> = (6001..6010).map(|port| peer_at(20, port)).collect();
+        peers.push(peer_at(20, 6010).with_direction(peer::Direction::Outgoing));
+        peers.push(peer_at(30, 6011));
+
+        // height first, the direction only breaks ties
+        let requests = sync.tick(&blockchain, &peers, Instant::now());
+        assert_eq!(requests[0].0.port(), 6011);
+
+        peers.pop();
+        let mut sync = SyncManager::new();
+        let requests = sync.tick(&blockchain, &peers, Instant::now());
+        assert_eq!(requests[0].0.port(), 6010);
+
+        // the lowest address breaks the remaining ties, whatever the order of the peers
+        peers.pop();
+        peers.reverse();
+        let mut sync = SyncManager::new();
+        let requests = sync.tick(&blockchain, &peers, Instant::now());
+        assert_eq!(requests[0].0.port(), 6001);
+    }
+
+    #[test]
+    fn reject_unconnected_headers() {
+        let blockchain = Blockchain::with_consensus(Arc::new(AcceptAll), &GenesisSpec::default());
+        let mut sync = SyncManager::new();
+        let orphan = generate_random_block(&H256::from([1; 32]));
+        let addr = SocketAddr::from(([127, 0, 0, 1], 6001));
+        assert!(sync.on_headers(addr, vec![orphan.header], &blockchain).is_err());
+        assert!(!sync.status(&blockchain).syncing);
+    }
+}
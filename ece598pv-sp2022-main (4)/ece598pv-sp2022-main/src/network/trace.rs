@@ -0,0 +1,200 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use log::{debug, trace, warn};
+use serde::{Serialize, Deserialize};
+
+use super::addrman::AddrManager;
+use super::banman::BanManager;
+use super::message::Version;
+use super::peer::{self, Direction};
+use super::queue::WriteQueue;
+use super::server::Handle as ServerHandle;
+use super::sync::{SyncManager, TICK_INTERVAL};
+use super::worker::Worker;
+use crate::blockchain::Blockchain;
+use crate::types::hash::H256;
+
+/// How long peers that misbehave during a replay stay banned, longer than any trace
+const REPLAY_BAN_DURATION: Duration = Duration::from_secs(365 * 24 * 3600);
+
+/// A frame received from a peer
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct TraceRecord {
+    /// Microseconds since the Unix epoch when the frame was read
+    pub timestamp: u128,
+    pub peer: SocketAddr,
+    /// Payload of the frame, a bincode-encoded message
+    pub frame: Vec<u8>,
+}
+
+/// Appends the frames received from peers to a trace file, each as its length in 4 big-endian
+/// bytes followed by the bincode-encoded record
+pub struct TraceWriter {
+    file: Mutex<BufWriter<File>>,
+}
+
+impl TraceWriter {
+    /// Start a new trace at `path`, replacing the file if there is one
+    pub fn create(path: &Path) -> io::Result<Self> {
+        let file = File::create(path)?;
+        Ok(Self {
+            file: Mutex::new(BufWriter::new(file)),
+        })
+    }
+
+    pub fn record(&self, peer: SocketAddr, frame: &[u8]) -> io::Result<()> {
+        let record = TraceRecord {
+            timestamp: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_micros(),
+            peer,
+            frame: frame.to_vec(),
+        };
+        let bytes = bincode::serialize(&record).map_err(io::Error::other)?;
+        let mut file = self.file.lock().unwrap();
+        file.write_all(&(bytes.len() as u32).to_be_bytes())?;
+        file.write_all(&bytes)?;
+        // the trace is most useful when the node crashed, so nothing waits in the buffer
+        file.flush()
+    }
+}
+
+/// Read the records of a trace file. A record cut short at the end, as a crash can leave it, is
+/// left out.
+pub fn read_trace(path: &Path) -> io::Result<Vec<TraceRecord>> {
+    let mut file = BufReader::new(File::open(path)?);
+    let mut records = Vec::new();
+    loop {
+        let mut len = [0u8; 4];
+        match file.read_exact(&mut len) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e),
+        }
+        let mut bytes = vec![0u8; u32::from_be_bytes(len) as usize];
+        match file.read_exact(&mut bytes) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                warn!("Trace {} ends in a partial record, ignoring it", path.display());
+                break;
+            }
+            Err(e) => return Err(e),
+        }
+        let record = bincode::deserialize(&bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        records.push(record);
+    }
+    Ok(records)
+}
+
+/// What the blockchain looked like at the end of a replay
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReplaySummary {
+    pub frames: usize,
+    pub peers: usize,
+    pub tip: H256,
+    pub height: u32,
+}
+
+/// Feed the frames of a trace to a network worker against `blockchain`, in order, as if the
+/// peers sent them again. The worker sees the time pass as it did between the frames, and its
+/// sync manager ticks on that clock too, so that requests and timeouts play out as recorded.
+/// What the worker sends back goes nowhere; it is logged at trace level.
+pub fn replay(records: &[TraceRecord], blockchain: &Arc<Mutex<Blockchain>>) -> ReplaySummary {
+    let server = ServerHandle::offline();
+    let mempool = Arc::new(Mutex::new(HashMap::new()));
+    let addrman = Arc::new(Mutex::new(AddrManager::new()));
+    let sync = Arc::new(Mutex::new(SyncManager::new()));
+    let banman = Arc::new(Mutex::new(BanManager::new(REPLAY_BAN_DURATION)));
+    // frames are handed to the worker directly, nothing comes through the channel
+    let (_, msg_rx) = smol::channel::unbounded();
+    let worker = Worker::new(1, msg_rx, &server, blockchain, &mempool, &addrman, &sync, &banman);
+
+    let start = Instant::now();
+    let first = records.first().map(|record| record.timestamp).unwrap_or(0);
+    let mut next_tick = start;
+    let mut peers: HashMap<SocketAddr, (Arc<WriteQueue>, peer::Handle)> = HashMap::new();
+    for (index, record) in records.iter().enumerate() {
+        let now = start + Duration::from_micros(record.timestamp.saturating_sub(first) as u64);
+        while next_tick <= now {
+            let handles: Vec<peer::Handle> = peers.values().map(|(_, handle)| handle.clone()).collect();
+            let requests = sync.lock().unwrap().tick(&blockchain.lock().unwrap(), &handles, next_tick);
+            for (addr, msg) in requests {
+                trace!("Replay: sync requests {:?} from {}", msg, addr);
+            }
+            next_tick += TICK_INTERVAL;
+        }
+
+        let (queue, handle) = peers
+            .entry(record.peer)
+            .or_insert_with(|| peer::new(record.peer, Version::default(), None, Direction::Incoming));
+        debug!("Replay: frame {} from {}", index, record.peer);
+        worker.handle(record.frame.clone(), handle.clone(), now);
+        while let Some(msg) = queue.try_pop() {
+            trace!("Replay: reply to {}: {:?}", record.peer, msg);
+        }
+    }
+
+    let blockchain = blockchain.lock().unwrap();
+    let tip = blockchain.tip();
+    ReplaySummary {
+        frames: records.len(),
+        peers: peers.len(),
+        tip,
+        height: blockchain.get_block(&tip).unwrap().length,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::message::Message;
+    use crate::types::block::generate_random_block;
+    use crate::types::hash::Hashable;
+
+    fn fresh_blockchain() -> Arc<Mutex<Blockchain>> {
+        let blockchain = Blockchain::with_consensus(Arc::new(crate::consensus::AcceptAll), &Default::default());
+        Arc::new(Mutex::new(blockchain))
+    }
+
+    #[test]
+    fn record_and_replay() {
+        let genesis = fresh_blockchain().lock().unwrap().tip();
+        let mut parent = genesis;
+        let mut hashes = Vec::new();
+        let path = std::env::temp_dir().join(format!("trace-{}", rand::random::<u64>()));
+        let writer = TraceWriter::create(&path).unwrap();
+        for port in 0..3 {
+            let block = generate_random_block(&parent);
+            parent = block.hash();
+            hashes.push(parent);
+            // announced first, pushed blocks are not taken
+            let peer = SocketAddr::from(([127, 0, 0, 1], 6000 + port));
+            writer.record(peer, &bincode::serialize(&Message::NewBlockHashes(vec![parent])).unwrap()).unwrap();
+            writer.record(peer, &bincode::serialize(&Message::Blocks(vec![block])).unwrap()).unwrap();
+        }
+        drop(writer);
+        // a crash in the middle of a record
+        let mut file = std::fs::OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&[0, 0, 1, 0, 42]).unwrap();
+
+        let records = read_trace(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(records.len(), 6);
+        assert!(records.windows(2).all(|pair| pair[0].timestamp <= pair[1].timestamp));
+
+        let summary = replay(&records, &fresh_blockchain());
+        assert_eq!(summary.frames, 6);
+        assert_eq!(summary.peers, 3);
+        assert_eq!(summary.tip, hashes[2]);
+        // on top of the genesis block
+        assert_eq!(summary.height, 4);
+
+        // the first frames only, as when bisecting
+        let summary = replay(&records[..4], &fresh_blockchain());
+        assert_eq!(summary.tip, hashes[1]);
+    }
+}
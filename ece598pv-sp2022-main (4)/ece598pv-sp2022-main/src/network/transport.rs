@@ -0,0 +1,427 @@
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::net::{Ipv6Addr, SocketAddr, TcpListener, TcpStream};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+
+use futures::future::BoxFuture;
+use futures::io::{AsyncRead, AsyncWrite};
+use smol::Async;
+
+/// Most bytes an in-memory connection buffers in each direction before writes wait for reads
+pub const MEMORY_PIPE_CAPACITY: usize = 64 * 1024;
+
+/// A connection to a peer over some transport
+pub trait Stream: AsyncRead + AsyncWrite + Unpin + Send {
+    /// Address the peer is known by: the address we dialed, or the one it connected from
+    fn peer_addr(&self) -> io::Result<SocketAddr>;
+
+    /// Something that shuts the connection down in both directions, to stop the reader and the
+    /// writer of a peer once the stream is split between them
+    fn closer(&self) -> io::Result<Closer>;
+}
+
+pub type BoxStream = Box<dyn Stream>;
+pub type Closer = Box<dyn Fn() + Send>;
+
+/// Accepts the connections of new peers
+pub trait Listener: Send {
+    fn accept(&self) -> BoxFuture<'_, io::Result<BoxStream>>;
+}
+
+/// How the P2P server reaches its peers. Nodes are always named by a socket address, which a
+/// transport other than TCP maps to its own kind of endpoint.
+pub trait Transport: Send + Sync {
+    fn bind(&self, addr: SocketAddr) -> io::Result<Box<dyn Listener>>;
+    fn connect(&self, addr: SocketAddr) -> BoxFuture<'static, io::Result<BoxStream>>;
+}
+
+/// Made-up address of a peer that connected over a transport without addresses. They come from
+/// the IPv6 unique local range, so that they cannot clash with the address of a node.
+fn anonymous_addr() -> SocketAddr {
+    static NEXT: AtomicU64 = AtomicU64::new(1);
+    let n = NEXT.fetch_add(1, Ordering::Relaxed);
+    let ip = Ipv6Addr::new(0xfd00, 0, 0, 0, (n >> 48) as u16, (n >> 32) as u16, (n >> 16) as u16, n as u16);
+    SocketAddr::new(ip.into(), 0)
+}
+
+/// Plain TCP, the addresses are the ones of the sockets
+pub struct TcpTransport;
+
+impl Transport for TcpTransport {
+    fn bind(&self, addr: SocketAddr) -> io::Result<Box<dyn Listener>> {
+        Ok(Box::new(Async::<TcpListener>::bind(addr)?))
+    }
+
+    fn connect(&self, addr: SocketAddr) -> BoxFuture<'static, io::Result<BoxStream>> {
+        Box::pin(async move { Ok(Box::new(Async::<TcpStream>::connect(addr).await?) as BoxStream) })
+    }
+}
+
+impl Listener for Async<TcpListener> {
+    fn accept(&self) -> BoxFuture<'_, io::Result<BoxStream>> {
+        Box::pin(async move {
+            let (stream, _) = Async::<TcpListener>::accept(self).await?;
+            Ok(Box::new(stream) as BoxStream)
+        })
+    }
+}
+
+impl Stream for Async<TcpStream> {
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.get_ref().peer_addr()
+    }
+
+    fn closer(&self) -> io::Result<Closer> {
+        let socket = self.get_ref().try_clone()?;
+        Ok(Box::new(move || {
+            let _ = socket.shutdown(std::net::Shutdown::Both);
+        }))
+    }
+}
+
+#[cfg(unix)]
+pub use self::unix::UnixTransport;
+
+#[cfg(unix)]
+mod unix {
+    use super::*;
+    use std::os::unix::net::{UnixListener, UnixStream};
+    use std::path::PathBuf;
+
+    /// Unix domain sockets in one directory, named after the address of the node listening on
+    /// them, for many nodes on one host without picking free ports
+    pub struct UnixTransport {
+        dir: PathBuf,
+    }
+
+    impl UnixTransport {
+        pub fn new(dir: PathBuf) -> Self {
+            Self { dir }
+        }
+
+        fn path(&self, addr: &SocketAddr) -> PathBuf {
+            self.dir.join(format!("{}.sock", addr))
+        }
+    }
+
+    impl Transport for UnixTransport {
+        fn bind(&self, addr: SocketAddr) -> io::Result<Box<dyn Listener>> {
+            let path = self.path(&addr);
+            // a socket left behind by a node that did not shut down cleanly
+            if path.exists() {
+                std::fs::remove_file(&path)?;
+            }
+            let listener = Async::<UnixListener>::bind(&path)?;
+            Ok(Box::new(UnixSocketListener { listener, path }))
+        }
+
+        fn connect(&self, addr: SocketAddr) -> BoxFuture<'static, io::Result<BoxStream>> {
+            let path = self.path(&addr);
+            Box::pin(async move {
+                let stream = Async::<UnixStream>::connect(&path).await?;
+                Ok(Box::new(UnixSocket { stream, peer_addr: addr }) as BoxStream)
+            })
+        }
+    }
+
+    struct UnixSocketListener {
+        listener: Async<UnixListener>,
+        path: PathBuf,
+    }
+
+    impl Listener for UnixSocketListener {
+        fn accept(&self) -> BoxFuture<'_, io::Result<BoxStream>> {
+            Box::pin(async move {
+                let (stream, _) = self.listener.accept().await?;
+                Ok(Box::new(UnixSocket { stream, peer_addr: anonymous_addr() }) as BoxStream)
+            })
+        }
+    }
+
+    impl Drop for UnixSocketListener {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+
+    struct UnixSocket {
+        stream: Async<UnixStream>,
+        peer_addr: SocketAddr,
+    }
+
+    impl AsyncRead for UnixSocket {
+        fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+            Pin::new(&mut self.stream).poll_read(cx, buf)
+        }
+    }
+
+    impl AsyncWrite for UnixSocket {
+        fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+            Pin::new(&mut self.stream).poll_write(cx, buf)
+        }
+
+        fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.stream).poll_flush(cx)
+        }
+
+        fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.stream).poll_close(cx)
+        }
+    }
+
+    impl Stream for UnixSocket {
+        fn peer_addr(&self) -> io::Result<SocketAddr> {
+            Ok(self.peer_addr)
+        }
+
+        fn closer(&self) -> io::Result<Closer> {
+            let socket = self.stream.get_ref().try_clone()?;
+            Ok(Box::new(move || {
+                let _ = socket.shutdown(std::net::Shutdown::Both);
+            }))
+        }
+    }
+}
+
+/// Bytes in flight one way over an in-memory connection
+#[derive(Default)]
+struct Pipe {
+    buffer: VecDeque<u8>,
+    closed: bool,
+    reader: Option<Waker>,
+    writer: Option<Waker>,
+}
+
+impl Pipe {
+    fn close(&mut self) {
+        self.closed = true;
+        wake(&mut self.reader);
+        wake(&mut self.writer);
+    }
+}
+
+fn wake(waker: &mut Option<Waker>) {
+    if let Some(waker) = waker.take() {
+        waker.wake();
+    }
+}
+
+/// One end of an in-memory connection
+struct MemoryStream {
+    incoming: Arc<Mutex<Pipe>>,
+    outgoing: Arc<Mutex<Pipe>>,
+    peer_addr: SocketAddr,
+}
+
+/// Both ends of a new in-memory connection, the first one is the dialer's
+fn memory_pair(listener_addr: SocketAddr) -> (MemoryStream, MemoryStream) {
+    let there = Arc::new(Mutex::new(Pipe::default()));
+    let back = Arc::new(Mutex::new(Pipe::default()));
+    let dialer = MemoryStream {
+        incoming: Arc::clone(&back),
+        outgoing: Arc::clone(&there),
+        peer_addr: listener_addr,
+    };
+    let listener = MemoryStream {
+        incoming: there,
+        outgoing: back,
+        peer_addr: anonymous_addr(),
+    };
+    (dialer, listener)
+}
+
+impl AsyncRead for MemoryStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        let mut pipe = self.incoming.lock().unwrap();
+        if pipe.buffer.is_empty() {
+            if pipe.closed {
+                return Poll::Ready(Ok(0));
+            }
+            pipe.reader = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+        let n = buf.len().min(pipe.buffer.len());
+        for (byte, slot) in pipe.buffer.drain(..n).zip(buf.iter_mut()) {
+            *slot = byte;
+        }
+        wake(&mut pipe.writer);
+        Poll::Ready(Ok(n))
+    }
+}
+
+impl AsyncWrite for MemoryStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let mut pipe = self.outgoing.lock().unwrap();
+        if pipe.closed {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+        let room = MEMORY_PIPE_CAPACITY - pipe.buffer.len();
+        if room == 0 {
+            pipe.writer = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+        let n = buf.len().min(room);
+        pipe.buffer.extend(&buf[..n]);
+        wake(&mut pipe.reader);
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.outgoing.lock().unwrap().close();
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Stream for MemoryStream {
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.peer_addr)
+    }
+
+    fn closer(&self) -> io::Result<Closer> {
+        let incoming = Arc::clone(&self.incoming);
+        let outgoing = Arc::clone(&self.outgoing);
+        Ok(Box::new(move || {
+            incoming.lock().unwrap().close();
+            outgoing.lock().unwrap().close();
+        }))
+    }
+}
+
+impl Drop for MemoryStream {
+    fn drop(&mut self) {
+        self.incoming.lock().unwrap().close();
+        self.outgoing.lock().unwrap().close();
+    }
+}
+
+type MemoryListeners = Arc<Mutex<HashMap<SocketAddr, smol::channel::Sender<BoxStream>>>>;
+
+/// Connections between nodes in the same process, through in-memory pipes. Every node of one
+/// network shares a clone of the same transport.
+#[derive(Clone, Default)]
+pub struct MemoryTransport {
+    listeners: MemoryListeners,
+}
+
+impl MemoryTransport {
+    pub fn new() -> Self {
+        Default::default()
+    }
+}
+
+impl Transport for MemoryTransport {
+    fn bind(&self, addr: SocketAddr) -> io::Result<Box<dyn Listener>> {
+        let mut listeners = self.listeners.lock().unwrap();
+        if listeners.get(&addr).is_some_and(|incoming| !incoming.is_closed()) {
+            return Err(io::Error::new(io::ErrorKind::AddrInUse, format!("{} is taken", addr)));
+        }
+        let (sender, incoming) = smol::channel::unbounded();
+        listeners.insert(addr, sender);
+        Ok(Box::new(MemoryListener {
+            addr,
+            incoming,
+            listeners: Arc::clone(&self.listeners),
+        }))
+    }
+
+    fn connect(&self, addr: SocketAddr) -> BoxFuture<'static, io::Result<BoxStream>> {
+        let listener = self.listeners.lock().unwrap().get(&addr).cloned();
+        Box::pin(async move {
+            let refused = || io::Error::new(io::ErrorKind::ConnectionRefused, format!("nobody listens at {}", addr));
+            let listener = listener.ok_or_else(refused)?;
+            let (dialer, accepted) = memory_pair(addr);
+            listener.send(Box::new(accepted)).await.map_err(|_| refused())?;
+            Ok(Box::new(dialer) as BoxStream)
+        })
+    }
+}
+
+struct MemoryListener {
+    addr: SocketAddr,
+    incoming: smol::channel::Receiver<BoxStream>,
+    listeners: MemoryListeners,
+}
+
+impl Listener for MemoryListener {
+    fn accept(&self) -> BoxFuture<'_, io::Result<BoxStream>> {
+        Box::pin(async move {
+            self.incoming
+                .recv()
+                .await
+                .map_err(|_| io::Error::new(io::ErrorKind::NotConnected, "listener closed"))
+        })
+    }
+}
+
+impl Drop for MemoryListener {
+    fn drop(&mut self) {
+        self.listeners.lock().unwrap().remove(&self.addr);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::io::{AsyncReadExt, AsyncWriteExt};
+
+    fn round_trip(transport: &dyn Transport, addr: SocketAddr) {
+        let listener = transport.bind(addr).unwrap();
+        smol::block_on(async {
+            let mut dialer = transport.connect(addr).await.unwrap();
+            let mut accepted = listener.accept().await.unwrap();
+            assert_eq!(dialer.peer_addr().unwrap(), addr);
+
+            dialer.write_all(b"ping").await.unwrap();
+            let mut buf = [0u8; 4];
+            accepted.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"ping");
+
+            // shutting down stops the other end too
+            accepted.closer().unwrap()();
+            assert_eq!(dialer.read(&mut buf).await.unwrap(), 0);
+        });
+    }
+
+    #[test]
+    fn memory_round_trip() {
+        let transport = MemoryTransport::new();
+        let addr = SocketAddr::from(([127, 0, 0, 1], 6000));
+        round_trip(&transport, addr);
+        assert!(smol::block_on(transport.connect(SocketAddr::from(([127, 0, 0, 1], 6001)))).is_err());
+    }
+
+    #[test]
+    fn memory_backpressure() {
+        let (mut dialer, mut accepted) = memory_pair(SocketAddr::from(([127, 0, 0, 1], 6000)));
+        let data = vec![7u8; MEMORY_PIPE_CAPACITY + 10];
+        smol::block_on(async {
+            let writer = async {
+                dialer.write_all(&data).await.unwrap();
+                dialer.close().await.unwrap();
+            };
+            let reader = async {
+                let mut received = Vec::new();
+                accepted.read_to_end(&mut received).await.unwrap();
+                received
+            };
+            let ((), received) = futures::join!(writer, reader);
+            assert_eq!(received, data);
+        });
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn unix_round_trip() {
+        let dir = std::env::temp_dir().join(format!("unix-transport-{}", rand::random::<u64>()));
+        std::fs::create_dir(&dir).unwrap();
+        round_trip(&UnixTransport::new(dir.clone()), SocketAddr::from(([127, 0, 0, 1], 6000)));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
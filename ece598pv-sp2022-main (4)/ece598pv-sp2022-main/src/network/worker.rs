@@ -1,19 +1,25 @@
-use super::message::Message;
+use super::addrman::AddrManager;
+use super::banman::{BanManager, Misbehavior};
+use super::compact::{BlockTransactions, BlockTransactionsRequest, PendingBlocks, MAX_PENDING_BLOCKS, PENDING_BLOCK_EXPIRY};
+use super::frame::MessageType;
+use super::orphan::{OrphanPool, MAX_ORPHANS, MAX_ORPHAN_BYTES, ORPHAN_EXPIRY};
+use super::message::{Message, MAX_ADDR_PER_MESSAGE};
 use super::peer;
 use super::server::Handle as ServerHandle;
+use super::sync::{SyncManager, MAX_HEADERS_PER_MESSAGE};
 
 use crate::types::hash::H256;
 use crate::blockchain::Blockchain;
 use crate::types::hash::Hashable;
 use crate::types::block::Block;
-use crate::types::address::Address;
-use crate::types::transaction::{SignedTransaction, delete_tx_from_mempool, verify, execute_tx, State};
+use crate::types::transaction::{SignedTransaction, delete_tx_from_mempool, verify};
 
 use log::{debug, warn, error};
 
 use std::thread;
 use std::collections::HashMap;
 use std::sync::{Arc, Mutex};
+use std::time::Instant;
 
 #[cfg(any(test,test_utilities))]
 use super::peer::TestReceiver as PeerTestReceiver;
@@ -25,25 +31,37 @@ pub struct Worker {
     num_worker: usize,
     server: ServerHandle,
     blockchain: Arc<Mutex<Blockchain>>,
-    orphan_buffer: Arc<Mutex<Vec<Block>>>,
+    orphans: Arc<Mutex<OrphanPool>>,
+    pending_blocks: Arc<Mutex<PendingBlocks>>,
     mempool: Arc<Mutex<HashMap<H256, SignedTransaction>>>,
+    addrman: Arc<Mutex<AddrManager>>,
+    sync: Arc<Mutex<SyncManager>>,
+    banman: Arc<Mutex<BanManager>>,
 }
 
 impl Worker {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         num_worker: usize,
         msg_src: smol::channel::Receiver<(Vec<u8>, peer::Handle)>,
         server: &ServerHandle,
         blockchain: &Arc<Mutex<Blockchain>>,
         mempool: &Arc<Mutex<HashMap<H256, SignedTransaction>>>,
+        addrman: &Arc<Mutex<AddrManager>>,
+        sync: &Arc<Mutex<SyncManager>>,
+        banman: &Arc<Mutex<BanManager>>,
     ) -> Self {
         Self {
             msg_chan: msg_src,
             num_worker,
             server: server.clone(),
             blockchain: Arc::clone(blockchain),
-            orphan_buffer: Arc::new(Mutex::new(vec![])),
+            orphans: Arc::new(Mutex::new(OrphanPool::new(MAX_ORPHANS, MAX_ORPHAN_BYTES, ORPHAN_EXPIRY))),
+            pending_blocks: Arc::new(Mutex::new(PendingBlocks::new(MAX_PENDING_BLOCKS, PENDING_BLOCK_EXPIRY))),
             mempool: Arc::clone(mempool),
+            addrman: Arc::clone(addrman),
+            sync: Arc::clone(sync),
+            banman: Arc::clone(banman),
         }
     }
 
@@ -58,8 +76,17 @@ impl Worker {
         }
     }
 
-    // returns hashes of blocks not seen before
-    fn handle_new_block(&self, block: &Block, blockchain: &mut Blockchain) {
+    /// Score a protocol violation of `peer`, and disconnect it if that gets it banned
+    fn misbehaving(&self, peer: &peer::Handle, misbehavior: Misbehavior) {
+        let banned = self.banman.lock().unwrap().misbehaving(peer.ban_key(), misbehavior);
+        if banned {
+            peer.close();
+        }
+    }
+
+    // returns hashes of the blocks added to the blockchain, the new block and the orphans that
+    // connect through it
+    fn handle_new_block(&self, block: &Block, blockchain: &mut Blockchain, peer: &peer::Handle, now: Instant) -> Vec<H256> {
         debug!("Received block hash {:?} with parent hash {:?}",block.hash(), block.get_parent());
 
         // remove tx in block from mempool
@@ -72,72 +99,55 @@ impl Worker {
         let parent_block_option = blockchain.get_parent_block(&block);
         match parent_block_option {
             None => {
-                if block.length == 1 { // new block is a genesis block, insert it
-                    blockchain.insert(&block.clone());
-
-                    // generate the initial ICO state
-                    let mut ico_state = HashMap::new();
-                    let ico_acc: u32 = 0;
-                    let ico_addr = Address::from_public_key_bytes(&ico_acc.to_be_bytes());
-                    ico_state.insert(ico_addr, (0, 100)); // initial ico account starts with 100 coins
-                    blockchain.block_states.insert(block.hash(), ico_state);
-                } else { // no parent block found
-                    // add to orphan buffer
-                    let mut orphan_buffer = self.orphan_buffer.lock().unwrap();
-                    orphan_buffer.push(block.clone());
-
-                    // broadcast the GetBlocks to get missing parent block
-                    self.server.broadcast(Message::GetBlocks(vec![block.get_parent()]));
+                // no parent block found, the genesis block is never received since every node
+                // builds it from the genesis spec. Only keep it if its seal holds up on its own.
+                if !blockchain.consensus().verify_standalone(&block.header) {
+                    warn!("Orphan block {:?} has an invalid seal", block.hash());
+                    self.misbehaving(peer, Misbehavior::InvalidBlock);
+                    return vec![];
                 }
+                let mut orphans = self.orphans.lock().unwrap();
+                orphans.insert(block.clone(), now);
+
+                // unless the block the orphans wait for is being downloaded already, ask the peer
+                // that sent the block for the headers we miss, and let the sync fetch the blocks.
+                // A request lost before is asked again with the next orphan of the chain.
+                let missing = orphans.missing_ancestor(&block.hash());
+                let sync = self.sync.lock().unwrap();
+                if !sync.has_header(&missing) && !sync.is_requested(&missing) {
+                    let mut peer = peer.clone();
+                    peer.write(Message::GetHeaders(sync.locator(blockchain)));
+                }
+                vec![]
             }
-            Some (parent_block) => {
-                let mut orphan_buffer = self.orphan_buffer.lock().unwrap();
-
-                if check_block_validity(&block, &parent_block) {
-                    let new_state = blockchain.get_block_state(&block.get_parent()).unwrap();
-                    let (new_state, _) = execute_tx(&new_state, &block.data);
-                    blockchain.block_states.insert(block.hash(), new_state);
-
-                    blockchain.insert(&block.clone());
+            Some (_) => {
+                if !connect_block(blockchain, block) {
+                    self.misbehaving(peer, Misbehavior::InvalidBlock);
+                    return vec![];
                 }
-
-                // check if any orphan blocks can be added to the blockchain
-                // this has to be done until no matches are left
-                let mut found = false;
-                let mut new_block_inserted = block;
-                let mut idx_to_delete = vec![];
-                loop { // search if new_block_inserted_hash has a child in the orphan buffer
-                    let mut idx = 0;
-
-                    for block in orphan_buffer.iter() {
-                        if block.get_parent() == new_block_inserted.hash() { // found a child for new_block_inserted_hash
-                            if check_block_validity(block, new_block_inserted) {
-                                let new_state = blockchain.get_block_state(&block.get_parent()).unwrap();
-                                let (new_state, _) = execute_tx(&new_state, &block.data);
-                                blockchain.block_states.insert(block.hash(), new_state);
-
-                                blockchain.insert(&block.clone());
+                // peers that bring us new blocks are worth keeping when inbound connections are full
+                peer.mark_block_received(now);
+
+                // attach the orphans waiting for this block, and for those, recursively
+                let mut orphans = self.orphans.lock().unwrap();
+                let mut inserted = vec![block.hash()];
+                let mut parents = vec![block.hash()];
+                while let Some(parent) = parents.pop() {
+                    for orphan in orphans.take_children(&parent) {
+                        if connect_block(blockchain, &orphan) {
+                            inserted.push(orphan.hash());
+                            parents.push(orphan.hash());
+                        } else {
+                            // the descendants of an invalid block are invalid too
+                            warn!("Dropping invalid orphan block {:?}", orphan.hash());
+                            let mut invalid = vec![orphan.hash()];
+                            while let Some(hash) = invalid.pop() {
+                                invalid.extend(orphans.take_children(&hash).iter().map(|block| block.hash()));
                             }
-                            new_block_inserted = block;
-                            idx_to_delete.push(idx);
-                            found = true;
-
-                            break;
                         }
-                        idx += 1;
-                    }
-
-                    if !found {
-                        break;
                     }
-
-                    found = false;
-                }
-
-                // cleanup orphan buffers 
-                for i in idx_to_delete.iter() {
-                    orphan_buffer.remove(*i);
                 }
+                inserted
             }
         }
     }
@@ -149,132 +159,292 @@ impl Worker {
                 error!("network worker terminated {}", e);
                 break;
             }
-            let msg = result.unwrap();
-            let (msg, mut peer) = msg;
-            let msg: Message = bincode::deserialize(&msg).unwrap();
-            match msg {
-                Message::Ping(nonce) => {
-                    debug!("Ping: {}", nonce);
-                    peer.write(Message::Pong(nonce.to_string()));
-                }
-                Message::Pong(nonce) => {
-                    debug!("Pong: {}", nonce);
+            let (msg, peer) = result.unwrap();
+            self.handle(msg, peer, Instant::now());
+        }
+    }
+
+    /// Process one message received from `peer`, as the bytes of the frame payload, at time `now`
+    pub fn handle(&self, msg: Vec<u8>, mut peer: peer::Handle, now: Instant) {
+        let msg_len = msg.len();
+        let msg: Message = match bincode::deserialize(&msg) {
+            Ok(msg) => msg,
+            Err(e) => {
+                warn!("Undecodable message from {}: {}", peer.addr(), e);
+                self.misbehaving(&peer, Misbehavior::UndecodableMessage);
+                return;
+            }
+        };
+        // the frame header only vouches for the type the peer claimed
+        if msg_len > MessageType::from(&msg).max_size() as usize {
+            warn!("Peer {} sent a {:?} message of {} bytes", peer.addr(), MessageType::from(&msg), msg_len);
+            self.misbehaving(&peer, Misbehavior::OversizedMessage);
+            return;
+        }
+        match msg {
+            Message::Ping(nonce) => {
+                debug!("Ping: {}", nonce);
+                peer.write(Message::Pong(nonce));
+            }
+            Message::Pong(nonce) => {
+                match peer.pong(nonce, now) {
+                    Some(rtt) => debug!("Pong from {} after {:?}", peer.addr(), rtt),
+                    None => debug!("Unexpected pong {} from {}", nonce, peer.addr()),
                 }
+            }
 
-                // Block messages
-                Message::NewBlockHashes(hashes) => {
-                    let blockchain = self.blockchain.lock().unwrap();
-                    let mut unseen_hashes = vec![];
-                    for hash in hashes.iter() {
-                        match blockchain.get_block(hash) {
-                            None => {
-                                unseen_hashes.push(*hash);
-                            }
-                            Some (_) => {
-                                continue;
-                            }
+            // Block messages
+            Message::NewBlockHashes(hashes) => {
+                let blockchain = self.blockchain.lock().unwrap();
+                let mut unseen_hashes = vec![];
+                for hash in hashes.iter() {
+                    peer.mark_known(*hash);
+                    match blockchain.get_block(hash) {
+                        None => {
+                            unseen_hashes.push(*hash);
+                        }
+                        Some (_) => {
+                            continue;
                         }
                     }
+                }
 
-                    if unseen_hashes.len() > 0 {
-                        peer.write(Message::GetBlocks(unseen_hashes));
-                    }
+                // blocks requested from another peer already are not asked for twice
+                let unseen_hashes = self.sync.lock().unwrap().request_blocks(*peer.addr(), &unseen_hashes, now);
+                if unseen_hashes.len() > 0 {
+                    peer.write(Message::GetBlocks(unseen_hashes));
                 }
-                Message::GetBlocks(hashes) => { 
-                    let blockchain = self.blockchain.lock().unwrap();
-                    let mut blocks_in_chain = vec![];
-                    for hash in hashes.iter() {
-                        match blockchain.get_block(hash) {
-                            Some (block) => {
-                                blocks_in_chain.push(block.clone());
-                            }
-                            None =>  {
-                                continue;
-                            }
+            }
+            Message::GetBlocks(hashes) => { 
+                let blockchain = self.blockchain.lock().unwrap();
+                let mut blocks_in_chain = vec![];
+                for hash in hashes.iter() {
+                    match blockchain.get_block(hash) {
+                        Some (block) => {
+                            blocks_in_chain.push(block.clone());
+                        }
+                        None =>  {
+                            continue;
                         }
                     }
+                }
 
-                    if blocks_in_chain.len() > 0 {
-                        peer.write(Message::Blocks(blocks_in_chain));
-                    }
+                if blocks_in_chain.len() > 0 {
+                    peer.write(Message::Blocks(blocks_in_chain));
                 }
-                Message::Blocks(blocks) => {
-                    let mut blockchain = self.blockchain.lock().unwrap();
-                    let mut new_hashes = vec![];
-                    for block in blocks.iter() {
-                        match blockchain.get_block(&block.hash()) {
-                            None => {
-                                self.handle_new_block(&block, &mut blockchain);
-                                new_hashes.push(block.clone().hash());
-                            }
-                            Some (_) => {
-                                continue;
-                            }
-                        }
+            }
+            Message::Blocks(blocks) => {
+                let mut blockchain = self.blockchain.lock().unwrap();
+                let mut new_hashes = vec![];
+                for block in blocks.iter() {
+                    peer.mark_known(block.hash());
+                    // new blocks are announced with their hashes or as compact blocks, full blocks
+                    // are only taken in answer to our requests
+                    if !self.sync.lock().unwrap().on_block(peer.addr(), &block.hash()) {
+                        debug!("Ignoring unsolicited block {:?} from {}", block.hash(), peer.addr());
+                        continue;
                     }
-                    if new_hashes.len() > 0 {
-                        self.server.broadcast(Message::NewBlockHashes(new_hashes));
+                    match blockchain.get_block(&block.hash()) {
+                        None => {
+                            new_hashes.extend(self.handle_new_block(&block, &mut blockchain, &peer, now));
+                        }
+                        Some (_) => {
+                            continue;
+                        }
                     }
                 }
+                if new_hashes.len() > 0 {
+                    self.server.broadcast(Message::NewBlockHashes(new_hashes));
+                }
+            }
 
-                // Transaction messages
-                Message::NewTransactionHashes(transaction_hashes) => {
-                    let mempool = self.mempool.lock().unwrap();
-                    let mut unseen_hashes = vec![];
-                    for hash in transaction_hashes.iter() {
-                        match mempool.get(hash) {
-                            None => {
-                                unseen_hashes.push(*hash);
-                            }
-                            Some (_) => {
-                                continue;
-                            }
+            // Transaction messages
+            Message::NewTransactionHashes(transaction_hashes) => {
+                let mempool = self.mempool.lock().unwrap();
+                let mut unseen_hashes = vec![];
+                for hash in transaction_hashes.iter() {
+                    peer.mark_known(*hash);
+                    match mempool.get(hash) {
+                        None => {
+                            unseen_hashes.push(*hash);
+                        }
+                        Some (_) => {
+                            continue;
                         }
                     }
+                }
 
-                    if unseen_hashes.len() > 0 {
-                        peer.write(Message::GetTransactions(unseen_hashes));
+                if unseen_hashes.len() > 0 {
+                    peer.write(Message::GetTransactions(unseen_hashes));
+                }
+            }
+            Message::GetTransactions(transaction_hashes) => { 
+                let mempool = self.mempool.lock().unwrap();
+                let mut tx_in_mempool = vec![];
+                for tx_hash in transaction_hashes.iter() {
+                    match mempool.get(tx_hash) {
+                        Some (tx) => {
+                            tx_in_mempool.push(tx.clone());
+                        }
+                        None =>  {
+                            continue;
+                        }
                     }
                 }
-                Message::GetTransactions(transaction_hashes) => { 
-                    let mempool = self.mempool.lock().unwrap();
-                    let mut tx_in_mempool = vec![];
-                    for tx_hash in transaction_hashes.iter() {
-                        match mempool.get(tx_hash) {
-                            Some (tx) => {
-                                tx_in_mempool.push(tx.clone());
-                            }
-                            None =>  {
-                                continue;
+
+                if tx_in_mempool.len() > 0 {
+                    peer.write(Message::Transactions(tx_in_mempool));
+                }
+            }
+            Message::Transactions(transactions) => {
+                let mut mempool = self.mempool.lock().unwrap();
+                let mut new_hashes = vec![];
+                for tx in transactions.iter() {
+                    peer.mark_known(tx.hash());
+                    match mempool.get(&tx.hash()) {
+                        None => {
+                            if check_tx_validity(tx) {
+                                mempool.insert(tx.hash(), tx.clone());
+                                new_hashes.push(tx.hash());
+                            } else {
+                                self.misbehaving(&peer, Misbehavior::InvalidTransaction);
                             }
                         }
+                        Some (_) => {
+                            continue;
+                        }
                     }
+                }
+                if new_hashes.len() > 0 {
+                    self.server.broadcast(Message::NewTransactionHashes(new_hashes));
+                }
+            }
+            Message::GetHeaders(locator) => {
+                let headers = self.blockchain.lock().unwrap().headers_after(&locator, MAX_HEADERS_PER_MESSAGE);
+                peer.write(Message::Headers(headers));
+            }
+            Message::Headers(headers) => {
+                if headers.len() > MAX_HEADERS_PER_MESSAGE {
+                    warn!("Peer {} sent {} headers, ignoring them", peer.addr(), headers.len());
+                    self.misbehaving(&peer, Misbehavior::OversizedMessage);
+                    return;
+                }
+                for header in headers.iter() {
+                    peer.mark_known(header.hash());
+                }
+                let blockchain = self.blockchain.lock().unwrap();
+                let mut sync = self.sync.lock().unwrap();
+                match sync.on_headers(*peer.addr(), headers, &blockchain) {
+                    Ok(true) => peer.write(Message::GetHeaders(sync.locator(&blockchain))),
+                    Ok(false) => {}
+                    Err(e) => {
+                        warn!("Invalid headers from {}: {}", peer.addr(), e);
+                        drop(sync);
+                        self.misbehaving(&peer, Misbehavior::InvalidHeaders);
+                    }
+                }
+            }
+            Message::CompactBlock(compact) => {
+                let hash = compact.hash();
+                peer.mark_known(hash);
+                let mut blockchain = self.blockchain.lock().unwrap();
+                if blockchain.get_block(&hash).is_some() || self.pending_blocks.lock().unwrap().contains(&hash) {
+                    return;
+                }
+                // the transactions of a block whose parent is missing could not be checked
+                // anyway, fetch the full block and let it wait in the orphan pool
+                if blockchain.get_block(&compact.header.parent).is_none() {
+                    let hashes = self.sync.lock().unwrap().request_blocks(*peer.addr(), &[hash], now);
+                    if !hashes.is_empty() {
+                        peer.write(Message::GetBlocks(hashes));
+                    }
+                    return;
+                }
 
-                    if tx_in_mempool.len() > 0 {
-                        peer.write(Message::Transactions(tx_in_mempool));
+                let partial = compact.reconstruct(&self.mempool.lock().unwrap(), now);
+                if let Some(block) = partial.block() {
+                    self.sync.lock().unwrap().on_block(peer.addr(), &hash);
+                    let new_hashes = self.handle_new_block(&block, &mut blockchain, &peer, now);
+                    if !new_hashes.is_empty() {
+                        self.server.broadcast(Message::NewBlockHashes(new_hashes));
                     }
+                    return;
                 }
-                Message::Transactions(transactions) => {
-                    let mut mempool = self.mempool.lock().unwrap();
-                    let mut new_hashes = vec![];
-                    for tx in transactions.iter() {
-                        match mempool.get(&tx.hash()) {
-                            None => {
-                                if check_tx_validity(tx) {
-                                    mempool.insert(tx.hash(), tx.clone());
-                                    new_hashes.push(tx.hash());
-                                }
-                            }
-                            Some (_) => {
-                                continue;
-                            }
-                        }
+
+                // the block is requested from the peer, so that it is fetched in full from
+                // another peer if this one does not send the transactions in time
+                self.sync.lock().unwrap().request_blocks(*peer.addr(), &[hash], now);
+                let indexes = partial.missing();
+                debug!("Compact block {:?} misses {} of {} transactions", hash, indexes.len(), compact.short_ids.len());
+                if self.pending_blocks.lock().unwrap().insert(partial, now) {
+                    peer.write(Message::GetBlockTransactions(BlockTransactionsRequest { block: hash, indexes }));
+                } else {
+                    peer.write(Message::GetBlocks(vec![hash]));
+                }
+            }
+            Message::GetBlockTransactions(request) => {
+                let blockchain = self.blockchain.lock().unwrap();
+                let block = match blockchain.get_block(&request.block) {
+                    Some(block) => block,
+                    None => return,
+                };
+                let transactions: Option<Vec<SignedTransaction>> =
+                    request.indexes.iter().map(|index| block.data.get(*index as usize).cloned()).collect();
+                match transactions {
+                    Some(transactions) => {
+                        peer.write(Message::BlockTransactions(BlockTransactions { block: request.block, transactions }))
                     }
-                    if new_hashes.len() > 0 {
-                        self.server.broadcast(Message::NewTransactionHashes(new_hashes));
+                    None => {
+                        warn!("Peer {} asked for transactions out of block {:?}", peer.addr(), request.block);
+                        self.misbehaving(&peer, Misbehavior::InvalidRequest);
                     }
                 }
-                _ => unimplemented!(),
+            }
+            Message::BlockTransactions(response) => {
+                let mut partial = match self.pending_blocks.lock().unwrap().remove(&response.block) {
+                    Some(partial) => partial,
+                    None => {
+                        debug!("Ignoring transactions of block {:?} not waited for", response.block);
+                        return;
+                    }
+                };
+                if let Err(e) = partial.fill(response.transactions) {
+                    warn!("Peer {} sent wrong transactions of block {:?}: {}", peer.addr(), response.block, e);
+                    self.misbehaving(&peer, Misbehavior::InvalidBlock);
+                    return;
+                }
+                let block = partial.block().unwrap();
+                let mut blockchain = self.blockchain.lock().unwrap();
+                self.sync.lock().unwrap().on_block(peer.addr(), &response.block);
+                if blockchain.get_block(&response.block).is_some() {
+                    return;
+                }
+                let new_hashes = self.handle_new_block(&block, &mut blockchain, &peer, now);
+                if !new_hashes.is_empty() {
+                    self.server.broadcast(Message::NewBlockHashes(new_hashes));
+                }
+            }
+            Message::GetAddr => {
+                let addrs = self.addrman.lock().unwrap().addresses(MAX_ADDR_PER_MESSAGE);
+                if !addrs.is_empty() {
+                    peer.write(Message::Addr(addrs));
+                }
+            }
+            Message::Addr(addrs) => {
+                if addrs.len() > MAX_ADDR_PER_MESSAGE {
+                    warn!("Peer {} sent {} addresses, ignoring them", peer.addr(), addrs.len());
+                    self.misbehaving(&peer, Misbehavior::OversizedMessage);
+                    return;
+                }
+                debug!("Received {} addresses from {}", addrs.len(), peer.addr());
+                let mut addrman = self.addrman.lock().unwrap();
+                for addr in addrs {
+                    addrman.add_from(addr, peer.addr().ip());
+                }
+            }
+            Message::Version(_) | Message::VerAck => {
+                warn!("Peer {} sent a handshake message after the handshake", peer.addr());
+                self.misbehaving(&peer, Misbehavior::UnexpectedMessage);
             }
         }
     }
@@ -289,14 +459,35 @@ fn check_tx_validity(tx: &SignedTransaction) -> bool {
     true
 }
 
-fn check_block_validity(block: &Block, parent: &Block) -> bool {
-    if block.hash() > block.get_difficulty() ||  block.get_difficulty() != parent.get_difficulty() {
+/// Validate a block whose parent is in the blockchain, and add it with the state after it
+fn connect_block(blockchain: &mut Blockchain, block: &Block) -> bool {
+    if !check_block_validity(blockchain, block) {
         return false;
     }
-
+    let parent_state = blockchain.get_block_state(&block.get_parent()).unwrap();
+    let new_state = match blockchain.execute_block(block, parent_state) {
+        Ok((new_state, _)) => new_state,
+        Err(e) => {
+            warn!("Block {:?} is invalid: {}", block.hash(), e);
+            return false;
+        }
+    };
+    blockchain.block_states.insert(block.hash(), new_state);
+    blockchain.insert(block);
     true
 }
 
+fn check_block_validity(blockchain: &Blockchain, block: &Block) -> bool {
+    let parent_hash = block.get_parent();
+    let (parent, parent_state) = match (blockchain.get_block(&parent_hash), blockchain.get_block_state(&parent_hash)) {
+        (Some(parent), Some(parent_state)) => (parent, parent_state),
+        _ => return false,
+    };
+
+    // the uncles are checked when the block is executed
+    blockchain.consensus().verify_seal(block, parent, parent_state)
+}
+
 #[cfg(any(test,test_utilities))]
 struct TestMsgSender {
     s: smol::channel::Sender<(Vec<u8>, peer::Handle)>
@@ -309,30 +500,38 @@ impl TestMsgSender {
     }
 
     fn send(&self, msg: Message) -> PeerTestReceiver {
-        let bytes = bincode::serialize(&msg).unwrap();
+        self.send_bytes(bincode::serialize(&msg).unwrap())
+    }
+
+    fn send_bytes(&self, bytes: Vec<u8>) -> PeerTestReceiver {
         let (handle, r) = peer::Handle::test_handle();
         smol::block_on(self.s.send((bytes, handle))).unwrap();
         r
     }
 }
-// #[cfg(any(test,test_utilities))]
-// /// returns two structs used by tests, and an ordered vector of hashes of all blocks in the blockchain
-// fn generate_test_worker_and_start() -> (TestMsgSender, ServerTestReceiver, Vec<H256>) {
-//     let (server, server_receiver) = ServerHandle::new_for_test();
-//     let (test_msg_sender, msg_chan) = TestMsgSender::new();
-
-//     let blockchain = Arc::new(Mutex::new(Blockchain::new()));
-//     let worker = Worker::new(1, msg_chan, &server, &blockchain);
-//     worker.start(); 
-
-//     let vec_hashes;
-//     {
-//         let blockchain = blockchain.lock().unwrap();
-//         vec_hashes = blockchain.all_blocks_in_longest_chain();
-//     }
+#[cfg(any(test,test_utilities))]
+/// returns two structs used by tests, and an ordered vector of hashes of all blocks in the blockchain
+fn generate_test_worker_and_start() -> (TestMsgSender, ServerTestReceiver, Vec<H256>) {
+    let (server, server_receiver) = ServerHandle::new_for_test();
+    let (test_msg_sender, msg_chan) = TestMsgSender::new();
+
+    let blockchain = Blockchain::with_consensus(Arc::new(crate::consensus::AcceptAll), &Default::default());
+    let blockchain = Arc::new(Mutex::new(blockchain));
+    let mempool = Arc::new(Mutex::new(HashMap::new()));
+    let addrman = Arc::new(Mutex::new(AddrManager::new()));
+    let sync = Arc::new(Mutex::new(SyncManager::new()));
+    let banman = Arc::new(Mutex::new(BanManager::new(std::time::Duration::from_secs(60))));
+    let worker = Worker::new(1, msg_chan, &server, &blockchain, &mempool, &addrman, &sync, &banman);
+    worker.start(); 
+
+    let vec_hashes;
+    {
+        let blockchain = blockchain.lock().unwrap();
+        vec_hashes = blockchain.all_blocks_in_longest_chain();
+    }
 
-//     (test_msg_sender, server_receiver, vec_hashes)
-// }
+    (test_msg_sender, server_receiver, vec_hashes)
+}
 
 // DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. BEFORE TEST
 
@@ -340,8 +539,10 @@ impl TestMsgSender {
 mod test {
     use ntest::timeout;
     use crate::types::block::generate_random_block;
-    use crate::types::hash::Hashable;
+    use crate::types::hash::{Hashable, generate_random_hash};
+    use crate::types::transaction::{SignedTransaction, generate_random_transaction};
 
+    use super::super::compact::{BlockTransactions, CompactBlock};
     use super::super::message::Message;
     use super::generate_test_worker_and_start;
 
@@ -377,6 +578,8 @@ mod test {
     fn reply_blocks() {
         let (test_msg_sender, server_receiver, v) = generate_test_worker_and_start();
         let random_block = generate_random_block(v.last().unwrap());
+        let mut peer_receiver = test_msg_sender.send(Message::NewBlockHashes(vec![random_block.hash()]));
+        peer_receiver.recv();
         let mut _peer_receiver = test_msg_sender.send(Message::Blocks(vec![random_block.clone()]));
         let reply = server_receiver.recv().unwrap();
         if let Message::NewBlockHashes(v) = reply {
@@ -432,6 +635,8 @@ mod test {
             append_index += 1;
         }
 
+        let mut peer_receiver = test_msg_sender.send(Message::NewBlockHashes(new_blocks_hashes.clone()));
+        peer_receiver.recv();
         let mut _peer_receiver = test_msg_sender.send(Message::Blocks(new_blocks.clone()));
 
         let mut peer_receiver = test_msg_sender.send(Message::GetBlocks(new_blocks_hashes.clone()));
@@ -469,6 +674,8 @@ mod test {
             index += 1;
         }
 
+        let mut peer_receiver = test_msg_sender.send(Message::NewBlockHashes(new_hashes.clone()));
+        peer_receiver.recv();
         let mut _peer_receiver = test_msg_sender.send(Message::Blocks(new_blocks.clone()));
         let reply = server_receiver.recv().unwrap();
         if let Message::NewBlockHashes(v) = reply {
@@ -477,6 +684,112 @@ mod test {
             panic!();
         }
     }
+    #[test]
+    #[timeout(60000)]
+    fn survive_undecodable_message() {
+        let (test_msg_sender, _server_receiver, v) = generate_test_worker_and_start();
+        let _peer_receiver = test_msg_sender.send_bytes(vec![0xff; 3]);
+        let random_block = generate_random_block(v.last().unwrap());
+        let mut peer_receiver = test_msg_sender.send(Message::NewBlockHashes(vec![random_block.hash()]));
+        let reply = peer_receiver.recv();
+        if let Message::GetBlocks(v) = reply {
+            assert_eq!(v, vec![random_block.hash()]);
+        } else {
+            panic!();
+        }
+    }
+    #[test]
+    #[timeout(60000)]
+    fn ignore_unsolicited_blocks() {
+        let (test_msg_sender, server_receiver, v) = generate_test_worker_and_start();
+        let orphan = generate_random_block(&[1; 32].into());
+        let pushed = generate_random_block(v.last().unwrap());
+        let _peer_receiver = test_msg_sender.send(Message::Blocks(vec![orphan, pushed]));
+        let random_block = generate_random_block(v.last().unwrap());
+        let mut peer_receiver = test_msg_sender.send(Message::NewBlockHashes(vec![random_block.hash()]));
+        peer_receiver.recv();
+        let _peer_receiver = test_msg_sender.send(Message::Blocks(vec![random_block.clone()]));
+        let reply = server_receiver.recv().unwrap();
+        if let Message::NewBlockHashes(v) = reply {
+            assert_eq!(v, vec![random_block.hash()]);
+        } else {
+            panic!();
+        }
+    }
+    #[test]
+    #[timeout(60000)]
+    fn connect_orphan_descendants() {
+        let (test_msg_sender, server_receiver, v) = generate_test_worker_and_start();
+        let parent = generate_random_block(v.last().unwrap());
+        let first = generate_random_block(&parent.hash());
+        let second = generate_random_block(&parent.hash());
+        let grandchild = generate_random_block(&first.hash());
+        let hashes = vec![parent.hash(), first.hash(), second.hash(), grandchild.hash()];
+
+        // the blocks are requested, so the orphans among them are kept until the parent arrives
+        let mut peer_receiver = test_msg_sender.send(Message::NewBlockHashes(hashes.clone()));
+        peer_receiver.recv();
+        let _peer_receiver = test_msg_sender.send(Message::Blocks(vec![grandchild, first, second]));
+        let _peer_receiver = test_msg_sender.send(Message::Blocks(vec![parent]));
+        let reply = server_receiver.recv().unwrap();
+        if let Message::NewBlockHashes(mut v) = reply {
+            v.sort();
+            let mut expected = hashes;
+            expected.sort();
+            assert_eq!(v, expected);
+        } else {
+            panic!();
+        }
+    }
+    #[test]
+    #[timeout(60000)]
+    fn ask_again_for_missing_ancestor() {
+        let (test_msg_sender, _server_receiver, v) = generate_test_worker_and_start();
+        let parent = generate_random_block(v.last().unwrap());
+        let child = generate_random_block(&parent.hash());
+        let grandchild = generate_random_block(&child.hash());
+
+        let mut peer_receiver = test_msg_sender.send(Message::NewBlockHashes(vec![child.hash(), grandchild.hash()]));
+        assert!(matches!(peer_receiver.recv(), Message::GetBlocks(_)));
+        // an orphan makes the worker ask for the headers it misses
+        let mut peer_receiver = test_msg_sender.send(Message::Blocks(vec![child]));
+        assert!(matches!(peer_receiver.recv(), Message::GetHeaders(_)));
+        // the answer got lost, the next orphan of the chain asks again for the same ancestor
+        let mut peer_receiver = test_msg_sender.send(Message::Blocks(vec![grandchild]));
+        assert!(matches!(peer_receiver.recv(), Message::GetHeaders(_)));
+    }
+    #[test]
+    #[timeout(60000)]
+    fn fetch_missing_compact_block_transactions() {
+        let (test_msg_sender, server_receiver, v) = generate_test_worker_and_start();
+        let mut block = generate_random_block(v.last().unwrap());
+        block.data = (0..3)
+            .map(|_| SignedTransaction {
+                transaction: generate_random_transaction(&Default::default()),
+                signature: vec![],
+                public_key: generate_random_hash().as_ref().to_vec(),
+            })
+            .collect();
+
+        let mut peer_receiver = test_msg_sender.send(Message::CompactBlock(CompactBlock::from_block(&block)));
+        let request = match peer_receiver.recv() {
+            Message::GetBlockTransactions(request) => request,
+            _ => panic!(),
+        };
+        assert_eq!(request.block, block.hash());
+        assert_eq!(request.indexes, vec![0, 1, 2]);
+
+        let _peer_receiver = test_msg_sender.send(Message::BlockTransactions(BlockTransactions {
+            block: block.hash(),
+            transactions: block.data.clone(),
+        }));
+        let reply = server_receiver.recv().unwrap();
+        if let Message::NewBlockHashes(v) = reply {
+            assert_eq!(v, vec![block.hash()]);
+        } else {
+            panic!();
+        }
+    }
 }
 
 // DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. AFTER TEST
\ No newline at end of file
@@ -0,0 +1,776 @@
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BinaryHeap, HashMap};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use ring::signature::{Ed25519KeyPair, KeyPair};
+use smol::Executor;
+
+use crate::blockchain::{Blockchain, MAX_UNCLES};
+use crate::consensus::{self, genesis::GenesisSpec};
+use crate::network::addrman::AddrManager;
+use crate::network::banman::BanManager;
+use crate::network::compact::CompactBlock;
+use crate::network::limits::ConnectionLimits;
+use crate::network::message::Message;
+use crate::network::peer;
+use crate::network::relay::RelayPolicy;
+use crate::network::server::{self, Handle as ServerHandle};
+use crate::network::sync::{SyncManager, TICK_INTERVAL};
+use crate::network::transport::MemoryTransport;
+use crate::network::worker::Worker;
+use crate::types::address::Address;
+use crate::types::block::{Block, Header};
+use crate::types::hash::{H256, Hashable};
+use crate::types::key_pair;
+use crate::types::merkle::MerkleTree;
+use crate::types::transaction::{self, SignedTransaction, State, Transaction, TxKind};
+
+/// How long misbehaving simulated peers stay banned
+const BAN_DURATION: Duration = Duration::from_secs(3600);
+/// Proof-of-work difficulty of the simulated network, about one nonce in 16 seals a block so that
+/// blocks cost next to nothing to make
+const DIFFICULTY: &str = "0fffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff";
+/// Coins the miner of a block gets, the only coins the nodes have to pay each other with
+const BLOCK_REWARD: u32 = 50;
+/// Most coins a node pays in one transaction
+const MAX_PAYMENT: u32 = 10;
+/// Most transactions a simulated miner puts in a block
+const MAX_BLOCK_TRANSACTIONS: usize = 100;
+
+/// How messages travel one way over a link
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LinkConfig {
+    /// Delay of every message
+    pub latency: Duration,
+    /// Up to this much more delay, drawn for each message. Messages still arrive in order.
+    pub jitter: Duration,
+    /// Probability that a message is lost
+    pub loss: f64,
+    /// Bytes per second, messages wait for the ones before them on a busy link. `None` for no limit.
+    pub bandwidth: Option<u64>,
+}
+
+impl Default for LinkConfig {
+    fn default() -> Self {
+        Self {
+            latency: Duration::from_millis(50),
+            jitter: Duration::ZERO,
+            loss: 0.0,
+            bandwidth: None,
+        }
+    }
+}
+
+/// From `start` to `end`, messages between nodes of different groups are lost. The nodes left out
+/// of every group form one more group.
+#[derive(Debug, Clone)]
+pub struct Partition {
+    pub start: Duration,
+    pub end: Duration,
+    pub groups: Vec<Vec<usize>>,
+}
+
+impl Partition {
+    fn separates(&self, a: usize, b: usize, now: Duration) -> bool {
+        let group = |node: usize| self.groups.iter().position(|group| group.contains(&node));
+        now >= self.start && now < self.end && group(a) != group(b)
+    }
+}
+
+/// Counters of a simulation run
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SimStats {
+    pub delivered: u64,
+    pub bytes_delivered: u64,
+    /// Messages lost to the loss rate of their link
+    pub lost: u64,
+    /// Messages lost to a partition
+    pub partitioned: u64,
+    pub blocks_mined: u64,
+    pub transactions: u64,
+}
+
+/// One way of a link
+struct Link {
+    config: LinkConfig,
+    // when the last message sent is fully on the wire, and when it arrives
+    free_at: Duration,
+    last_arrival: Duration,
+}
+
+/// What a node does at random, `mean` apart on average. Rescheduling bumps the generation, which
+/// makes the events of the old schedule stale.
+#[derive(Default)]
+struct Schedule {
+    mean: Option<Duration>,
+    generation: u64,
+}
+
+struct Node {
+    addr: SocketAddr,
+    server: ServerHandle,
+    // what the server of the node read from its peers, for the simulator to put on the links
+    received: smol::channel::Receiver<(Vec<u8>, peer::Handle)>,
+    blockchain: Arc<Mutex<Blockchain>>,
+    mempool: Arc<Mutex<HashMap<H256, SignedTransaction>>>,
+    sync: Arc<Mutex<SyncManager>>,
+    worker: Worker,
+    // signs the payments of the node, and its address gets the block rewards
+    key: Ed25519KeyPair,
+    address: Address,
+    mining: Schedule,
+    paying: Schedule,
+}
+
+impl Node {
+    fn new(index: usize, seed: [u8; 32], spec: &GenesisSpec, transport: &MemoryTransport, ex: &Arc<Executor<'static>>) -> Self {
+        let addr = SocketAddr::from(([10, 0, (index >> 8) as u8, index as u8], 6000));
+        let engine = consensus::new("pow", spec, None).unwrap();
+        // GHOST breaks ties by hash, so that nodes with the same blocks agree on the tip whatever
+        // order the blocks came in
+        let engine = consensus::with_fork_choice(engine, "ghost").unwrap();
+        let blockchain = Arc::new(Mutex::new(Blockchain::with_consensus(engine, spec)));
+        let mempool = Arc::new(Mutex::new(HashMap::new()));
+        let addrman = Arc::new(Mutex::new(AddrManager::new()));
+        let sync = Arc::new(Mutex::new(SyncManager::new()));
+        let banman = Arc::new(Mutex::new(BanManager::new(BAN_DURATION)));
+        let (msg_tx, msg_rx) = smol::channel::unbounded();
+        let (ctx, server) = server::new(
+            addr,
+            msg_tx,
+            &blockchain,
+            &banman,
+            RelayPolicy::Flood,
+            None,
+            None,
+            ConnectionLimits::default(),
+        )
+        .unwrap();
+        ctx.with_transport(Arc::new(transport.clone())).without_pings().start_on(ex).unwrap();
+        // the simulator hands the messages to the worker itself, on the virtual clock
+        let worker = Worker::new(1, msg_rx.clone(), &server, &blockchain, &mempool, &addrman, &sync, &banman);
+        let key = key_pair::from_seed(&seed).unwrap();
+        let address = Address::from_public_key_bytes(key.public_key().as_ref());
+        Self {
+            addr,
+            server,
+            received: msg_rx,
+            blockchain,
+            mempool,
+            sync,
+            worker,
+            key,
+            address,
+            mining: Schedule::default(),
+            paying: Schedule::default(),
+        }
+    }
+
+    fn height(&self) -> u32 {
+        let blockchain = self.blockchain.lock().unwrap();
+        blockchain.get_block(&blockchain.tip()).unwrap().length
+    }
+}
+
+enum Event {
+    /// A message node `to` read from `peer` arrives at its worker
+    Deliver { to: usize, msg: Vec<u8>, peer: peer::Handle },
+    /// A node finds a block, if its mining schedule is still the given generation
+    Mine(usize, u64),
+    /// A node pays another one, if its payment schedule is still the given generation
+    Pay(usize, u64),
+    /// Every node runs its sync driver
+    Sync,
+}
+
+struct Scheduled {
+    at: Duration,
+    seq: u64,
+    event: Event,
+}
+
+impl PartialEq for Scheduled {
+    fn eq(&self, other: &Self) -> bool {
+        (self.at, self.seq) == (other.at, other.seq)
+    }
+}
+
+impl Eq for Scheduled {}
+
+impl PartialOrd for Scheduled {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Scheduled {
+    // the earliest event first out of the max-heap, and of those the one scheduled first
+    fn cmp(&self, other: &Self) -> Ordering {
+        (other.at, other.seq).cmp(&(self.at, self.seq))
+    }
+}
+
+/// Full nodes in one process, connected by simulated links, on a virtual clock. Each node runs
+/// the real P2P server over an in-memory transport, a proof-of-work blockchain with GHOST fork
+/// choice, a mempool, and the real network worker and sync manager. The servers run on one
+/// executor that the simulator drives until they are idle after every event, and what they read
+/// from their peers is handed to the workers after the delay of the link, or lost. Handshakes
+/// take no virtual time. Every random draw comes from one seeded generator, so a run is the same
+/// every time for the same seed.
+pub struct Simulator {
+    nodes: Vec<Node>,
+    ex: Arc<Executor<'static>>,
+    // which node listens at an address, to know where a message comes from
+    listeners: HashMap<SocketAddr, usize>,
+    links: BTreeMap<(usize, usize), Link>,
+    rng: StdRng,
+    now: Duration,
+    // the `Instant` the virtual clock starts at, for the parts of the nodes that take one
+    epoch: Instant,
+    queue: BinaryHeap<Scheduled>,
+    seq: u64,
+    partitions: Vec<Partition>,
+    stats: SimStats,
+}
+
+impl Simulator {
+    pub fn new(nodes: usize, seed: u64) -> Self {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let spec = GenesisSpec {
+            block_reward: BLOCK_REWARD,
+            difficulty: Some(DIFFICULTY.to_string()),
+            ..Default::default()
+        };
+        let transport = MemoryTransport::new();
+        let ex = Arc::new(Executor::new());
+        let nodes: Vec<Node> = (0..nodes).map(|index| Node::new(index, rng.gen(), &spec, &transport, &ex)).collect();
+        let mut simulator = Self {
+            listeners: nodes.iter().enumerate().map(|(index, node)| (node.addr, index)).collect(),
+            nodes,
+            ex,
+            links: BTreeMap::new(),
+            rng,
+            now: Duration::ZERO,
+            epoch: Instant::now(),
+            queue: BinaryHeap::new(),
+            seq: 0,
+            partitions: Vec::new(),
+            stats: SimStats::default(),
+        };
+        simulator.schedule(TICK_INTERVAL, Event::Sync);
+        simulator
+    }
+
+    fn schedule(&mut self, at: Duration, event: Event) {
+        self.queue.push(Scheduled { at, seq: self.seq, event });
+        self.seq += 1;
+    }
+
+    fn instant(&self) -> Instant {
+        self.epoch + self.now
+    }
+
+    /// Run the servers until they are idle, and put what the nodes read from their peers on the
+    /// links. The messages are taken node by node and peer by peer, so that they go on the links
+    /// in the same order whatever order the servers ran their tasks in.
+    fn settle(&mut self) {
+        while self.ex.try_tick() {}
+        for to in 0..self.nodes.len() {
+            let mut received = Vec::new();
+            while let Ok((msg, peer)) = self.nodes[to].received.try_recv() {
+                if let Some(from) = peer.listen_addr().and_then(|addr| self.listeners.get(&addr)) {
+                    received.push((*from, msg, peer));
+                }
+            }
+            received.sort_by_key(|(from, _, _)| *from);
+            for (from, msg, peer) in received {
+                self.send(from, to, msg, peer);
+            }
+        }
+    }
+
+    /// Make a blocking call to a server from another thread, while this one runs the servers
+    fn call<T: Send>(&mut self, call: impl FnOnce() -> T + Send) -> T {
+        self.settle();
+        let ex = &self.ex;
+        let result = thread::scope(|scope| {
+            let call = scope.spawn(call);
+            while !call.is_finished() {
+                if !ex.try_tick() {
+                    thread::yield_now();
+                }
+            }
+            call.join().unwrap()
+        });
+        self.settle();
+        result
+    }
+
+    /// Have node `a` connect to node `b`, with the same link config both ways
+    pub fn connect(&mut self, a: usize, b: usize, config: LinkConfig) {
+        for (from, to) in [(a, b), (b, a)].iter().copied() {
+            let link = Link {
+                config,
+                free_at: self.now,
+                last_arrival: self.now,
+            };
+            self.links.insert((from, to), link);
+        }
+        let server = self.nodes[a].server.clone();
+        let addr = self.nodes[b].addr;
+        self.call(move || server.connect(addr)).unwrap();
+    }
+
+    /// Change the config of the link between `a` and `b`, both ways
+    pub fn set_link(&mut self, a: usize, b: usize, config: LinkConfig) {
+        for (from, to) in [(a, b), (b, a)].iter().copied() {
+            self.links.get_mut(&(from, to)).unwrap().config = config;
+        }
+    }
+
+    /// Connect every node to every other one
+    pub fn connect_all(&mut self, config: LinkConfig) {
+        for a in 0..self.nodes.len() {
+            for b in a + 1..self.nodes.len() {
+                self.connect(a, b, config);
+            }
+        }
+    }
+
+    pub fn partition(&mut self, partition: Partition) {
+        self.partitions.push(partition);
+    }
+
+    /// Have `node` find blocks at random, `mean` apart on average
+    pub fn start_mining(&mut self, node: usize, mean: Duration) {
+        let schedule = &mut self.nodes[node].mining;
+        schedule.mean = Some(mean);
+        schedule.generation += 1;
+        let event = Event::Mine(node, schedule.generation);
+        let delay = self.random_delay(mean);
+        self.schedule(self.now + delay, event);
+    }
+
+    pub fn stop_mining(&mut self, node: usize) {
+        self.nodes[node].mining.mean = None;
+    }
+
+    /// Have `node` pay other nodes at random, `mean` apart on average
+    pub fn start_paying(&mut self, node: usize, mean: Duration) {
+        let schedule = &mut self.nodes[node].paying;
+        schedule.mean = Some(mean);
+        schedule.generation += 1;
+        let event = Event::Pay(node, schedule.generation);
+        let delay = self.random_delay(mean);
+        self.schedule(self.now + delay, event);
+    }
+
+    pub fn stop_paying(&mut self, node: usize) {
+        self.nodes[node].paying.mean = None;
+    }
+
+    /// Time to the next event of something that happens every `mean` on average
+    fn random_delay(&mut self, mean: Duration) -> Duration {
+        let uniform: f64 = self.rng.gen();
+        mean.mul_f64(-(1.0 - uniform).ln())
+    }
+
+    /// Have `node` find a block on its tip right now, with the transactions of its mempool, and
+    /// relay it. Returns the hash of the block.
+    pub fn mine(&mut self, node: usize) -> H256 {
+        let block = {
+            let node = &self.nodes[node];
+            let mut blockchain = node.blockchain.lock().unwrap();
+            let parent = blockchain.get_block(&blockchain.tip()).unwrap().clone();
+            let parent_state = blockchain.get_block_state(&parent.hash()).unwrap().clone();
+            let mut uncles = blockchain.uncle_candidates(&parent.hash());
+            uncles.truncate(MAX_UNCLES);
+            // the mempool is a hash map, take the transactions in a fixed order, each sender's
+            // in the order of their nonces
+            let mut mempool = node.mempool.lock().unwrap();
+            let mut transactions: Vec<SignedTransaction> = mempool.values().cloned().collect();
+            transactions.sort_by_cached_key(|tx| (tx.transaction.account_nonce, tx.hash()));
+            transactions.truncate(MAX_BLOCK_TRANSACTIONS);
+
+            let mut block = Block {
+                length: parent.length + 1,
+                header: Header {
+                    parent: parent.hash(),
+                    nonce: 0,
+                    difficulty: parent.get_difficulty(),
+                    timestamp: self.now.as_millis(),
+                    merkle_root: H256::default(),
+                    signer: Vec::new(),
+                    signature: Vec::new(),
+                    proof: Vec::new(),
+                    beneficiary: node.address,
+                    uncles,
+                },
+                data: transactions,
+            };
+            let (state, valid_tx) = blockchain.execute_block(&block, &parent_state).unwrap();
+            // transactions the block could not take yet wait for the next blocks, the ones whose
+            // nonces are used up are gone for good
+            mempool.retain(|_, tx| {
+                let nonce = state.get(&tx.transaction.sender).map_or(0, |(nonce, _)| *nonce);
+                tx.transaction.account_nonce > nonce
+            });
+            block.data = valid_tx;
+            if !block.data.is_empty() {
+                block.header.merkle_root = MerkleTree::new(&block.data).root();
+            }
+            // the engine draws its nonces from its own generator, draw them from the seeded one
+            // and let the engine check them
+            let engine = blockchain.consensus();
+            loop {
+                block.header.nonce = self.rng.gen();
+                if engine.verify_seal(&block, &parent, &parent_state) {
+                    break;
+                }
+            }
+            blockchain.block_states.insert(block.hash(), state);
+            blockchain.insert(&block);
+            block
+        };
+        self.stats.blocks_mined += 1;
+        self.nodes[node].server.broadcast(Message::CompactBlock(CompactBlock::from_block(&block)));
+        self.settle();
+        block.hash()
+    }
+
+    /// Have `node` pay a few coins to another node, if the coins it has left after the payments
+    /// in its mempool are enough. Returns the hash of the transaction.
+    pub fn pay(&mut self, node: usize) -> Option<H256> {
+        // always draw, so that the draws do not shift with the balances
+        let receiver = (node + self.rng.gen_range(1..self.nodes.len())) % self.nodes.len();
+        let value = self.rng.gen_range(1..=MAX_PAYMENT);
+        let tx = {
+            let payer = &self.nodes[node];
+            let (nonce, balance) = self.state(node).get(&payer.address).copied().unwrap_or_default();
+            let mut mempool = payer.mempool.lock().unwrap();
+            let pending: Vec<&Transaction> = mempool
+                .values()
+                .map(|tx| &tx.transaction)
+                .filter(|tx| tx.sender == payer.address && tx.account_nonce > nonce)
+                .collect();
+            let pending_value: u32 = pending.iter().map(|tx| tx.value).sum();
+            if balance < pending_value + value {
+                return None;
+            }
+            let tx = Transaction {
+                sender: payer.address,
+                receiver: self.nodes[receiver].address,
+                account_nonce: nonce + pending.len() as u32 + 1,
+                value,
+                kind: TxKind::Transfer,
+            };
+            let signature = transaction::sign(&tx, &payer.key);
+            let tx = SignedTransaction {
+                transaction: tx,
+                signature: signature.as_ref().to_vec(),
+                public_key: payer.key.public_key().as_ref().to_vec(),
+            };
+            mempool.insert(tx.hash(), tx.clone());
+            tx
+        };
+        self.stats.transactions += 1;
+        let hash = tx.hash();
+        self.nodes[node].server.broadcast(Message::Transactions(vec![tx]));
+        self.settle();
+        Some(hash)
+    }
+
+    fn send(&mut self, from: usize, to: usize, msg: Vec<u8>, peer: peer::Handle) {
+        let now = self.now;
+        if self.partitions.iter().any(|partition| partition.separates(from, to, now)) {
+            self.stats.partitioned += 1;
+            return;
+        }
+        let link = match self.links.get_mut(&(from, to)) {
+            Some(link) => link,
+            None => return,
+        };
+        let config = link.config;
+        // always draw, so that the draws do not shift with the loss rate
+        let (loss, jitter): (f64, f64) = (self.rng.gen(), self.rng.gen());
+        if loss < config.loss {
+            self.stats.lost += 1;
+            return;
+        }
+        let transmit = match config.bandwidth {
+            Some(bandwidth) => Duration::from_secs_f64(msg.len() as f64 / bandwidth as f64),
+            None => Duration::ZERO,
+        };
+        link.free_at = link.free_at.max(now) + transmit;
+        // like TCP, a link delivers in order
+        let arrival = (link.free_at + config.latency + config.jitter.mul_f64(jitter)).max(link.last_arrival);
+        link.last_arrival = arrival;
+        self.schedule(arrival, Event::Deliver { to, msg, peer });
+    }
+
+    fn process(&mut self, event: Event) {
+        match event {
+            Event::Deliver { to, msg, peer } => {
+                // a peer that got banned is closed, what is still on the wire is lost
+                if peer.is_disconnected() {
+                    return;
+                }
+                self.stats.delivered += 1;
+                self.stats.bytes_delivered += msg.len() as u64;
+                let now = self.instant();
+                self.nodes[to].worker.handle(msg, peer, now);
+                self.settle();
+            }
+            Event::Mine(node, generation) => {
+                let mean = match self.nodes[node].mining {
+                    Schedule { mean: Some(mean), generation: current } if current == generation => mean,
+                    _ => return,
+                };
+                self.mine(node);
+                let delay = self.random_delay(mean);
+                self.schedule(self.now + delay, Event::Mine(node, generation));
+            }
+            Event::Pay(node, generation) => {
+                let mean = match self.nodes[node].paying {
+                    Schedule { mean: Some(mean), generation: current } if current == generation => mean,
+                    _ => return,
+                };
+                self.pay(node);
+                let delay = self.random_delay(mean);
+                self.schedule(self.now + delay, Event::Pay(node, generation));
+            }
+            Event::Sync => {
+                let now = self.instant();
+                for node in 0..self.nodes.len() {
+                    let server = self.nodes[node].server.clone();
+                    let mut peers = self.call(move || server.peers());
+                    let mut requests = {
+                        let node = &self.nodes[node];
+                        let blockchain = node.blockchain.lock().unwrap();
+                        node.sync.lock().unwrap().tick(&blockchain, &peers, now)
+                    };
+                    // the sync manager hands them out in hash map order
+                    requests.sort_by_key(|(addr, _)| *addr);
+                    for (addr, msg) in requests {
+                        if let Some(peer) = peers.iter_mut().find(|peer| *peer.addr() == addr) {
+                            peer.write(msg);
+                        }
+                    }
+                    self.settle();
+                }
+                self.schedule(self.now + TICK_INTERVAL, Event::Sync);
+            }
+        }
+    }
+
+    /// Run the simulation for `duration` of virtual time
+    pub fn run_for(&mut self, duration: Duration) {
+        let end = self.now + duration;
+        while self.queue.peek().is_some_and(|next| next.at <= end) {
+            let next = self.queue.pop().unwrap();
+            self.now = next.at;
+            self.process(next.event);
+        }
+        self.now = end;
+    }
+
+    /// Run until every node has the same tip, for at most `timeout`. Returns whether they do.
+    pub fn run_until_converged(&mut self, timeout: Duration) -> bool {
+        let end = self.now + timeout;
+        while !self.converged() {
+            if self.now >= end {
+                return false;
+            }
+            self.run_for(TICK_INTERVAL.min(end - self.now));
+        }
+        true
+    }
+
+    /// Virtual time since the start of the simulation
+    pub fn now(&self) -> Duration {
+        self.now
+    }
+
+    pub fn tip(&self, node: usize) -> H256 {
+        self.nodes[node].blockchain.lock().unwrap().tip()
+    }
+
+    pub fn height(&self, node: usize) -> u32 {
+        self.nodes[node].height()
+    }
+
+    /// State of the accounts after the tip of `node`
+    pub fn state(&self, node: usize) -> State {
+        let blockchain = self.nodes[node].blockchain.lock().unwrap();
+        blockchain.get_block_state(&blockchain.tip()).unwrap().clone()
+    }
+
+    /// Address the block rewards of `node` go to, and that it pays from
+    pub fn address(&self, node: usize) -> Address {
+        self.nodes[node].address
+    }
+
+    pub fn blockchain(&self, node: usize) -> Arc<Mutex<Blockchain>> {
+        Arc::clone(&self.nodes[node].blockchain)
+    }
+
+    /// Number of transactions waiting in the mempool of `node`
+    pub fn mempool_len(&self, node: usize) -> usize {
+        self.nodes[node].mempool.lock().unwrap().len()
+    }
+
+    /// Whether every node has the same tip
+    pub fn converged(&self) -> bool {
+        let tip = self.tip(0);
+        (1..self.nodes.len()).all(|node| self.tip(node) == tip)
+    }
+
+    pub fn stats(&self) -> &SimStats {
+        &self.stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tips(sim: &Simulator, nodes: usize) -> Vec<H256> {
+        (0..nodes).map(|node| sim.tip(node)).collect()
+    }
+
+    /// Coins that moved between the nodes, going by the account nonces
+    fn payments(state: &State) -> u32 {
+        state.values().map(|(nonce, _)| nonce).sum()
+    }
+
+    fn lossy_network(seed: u64) -> Simulator {
+        let mut sim = Simulator::new(5, seed);
+        let link = LinkConfig {
+            latency: Duration::from_millis(100),
+            jitter: Duration::from_millis(400),
+            loss: 0.05,
+            bandwidth: Some(1_000_000),
+        };
+        sim.connect_all(link);
+        for node in 0..5 {
+            sim.start_mining(node, Duration::from_secs(5));
+            sim.start_paying(node, Duration::from_secs(2));
+        }
+        sim.run_for(Duration::from_secs(120));
+        sim
+    }
+
+    #[test]
+    fn same_seed_same_run() {
+        let first = lossy_network(7);
+        let second = lossy_network(7);
+        assert!(first.stats().blocks_mined > 50);
+        assert!(first.stats().transactions > 50);
+        assert!(first.stats().lost > 0);
+        assert_eq!(first.stats(), second.stats());
+        assert_eq!(tips(&first, 5), tips(&second, 5));
+
+        let other = lossy_network(8);
+        assert_ne!(first.stats(), other.stats());
+    }
+
+    #[test]
+    fn fork_and_converge_after_partition() {
+        let mut sim = Simulator::new(6, 1);
+        sim.connect_all(LinkConfig::default());
+        sim.partition(Partition {
+            start: Duration::ZERO,
+            end: Duration::from_secs(60),
+            groups: vec![vec![0, 1, 2], vec![3, 4, 5]],
+        });
+        for node in 0..6 {
+            sim.start_mining(node, Duration::from_secs(10));
+            sim.start_paying(node, Duration::from_secs(5));
+        }
+        sim.run_for(Duration::from_secs(59));
+        // each side built its own chain, and paid on it
+        assert_ne!(sim.tip(0), sim.tip(3));
+        assert_ne!(sim.state(0), sim.state(3));
+        let heights: Vec<u32> = (0..6).map(|node| sim.height(node)).collect();
+
+        // the next blocks after the partition heals bring both sides together
+        sim.run_for(Duration::from_secs(30));
+        for node in 0..6 {
+            sim.stop_mining(node);
+            sim.stop_paying(node);
+        }
+        assert!(sim.run_until_converged(Duration::from_secs(60)));
+        assert!(sim.height(0) > *heights.iter().max().unwrap());
+        let state = sim.state(0);
+        assert!(payments(&state) > 0);
+        assert!((1..6).all(|node| sim.state(node) == state));
+    }
+
+    #[test]
+    fn payments_reach_every_node() {
+        // 0 - 1 - 2, only 0 mines at first
+        let mut sim = Simulator::new(3, 5);
+        for node in 0..2 {
+            sim.connect(node, node + 1, LinkConfig::default());
+        }
+        for _ in 0..3 {
+            sim.mine(0);
+        }
+        sim.run_for(Duration::from_secs(1));
+        assert_eq!(sim.state(2).get(&sim.address(0)), Some(&(0, 3 * BLOCK_REWARD)));
+
+        // payments relayed to the far end of the line get mined there, in the order of their nonces
+        let payments = [sim.pay(0).unwrap(), sim.pay(0).unwrap()];
+        sim.run_for(Duration::from_secs(1));
+        assert_eq!(sim.mempool_len(2), 2);
+        sim.mine(2);
+        assert!(sim.run_until_converged(Duration::from_secs(10)));
+
+        let blockchain = sim.blockchain(0);
+        let mined: Vec<H256> = {
+            let blockchain = blockchain.lock().unwrap();
+            // tip first
+            let chain = blockchain.all_blocks_in_longest_chain();
+            chain.iter().rev().flat_map(|hash| blockchain.get_block(hash).unwrap().data.iter().map(|tx| tx.hash())).collect()
+        };
+        assert_eq!(mined, payments);
+        let state = sim.state(0);
+        assert_eq!(state.get(&sim.address(0)).unwrap().0, 2);
+        assert!((0..3).all(|node| sim.state(node) == state && sim.mempool_len(node) == 0));
+        // every coin is a block reward, or was in the initial account
+        let coins: u32 = state.values().map(|(_, balance)| balance).sum();
+        assert_eq!(coins, 100 + 4 * BLOCK_REWARD);
+    }
+
+    #[test]
+    fn catch_up_after_lossy_links() {
+        // 0 - 1 - 2 - 3, with a third of the messages lost
+        let mut sim = Simulator::new(4, 3);
+        let lossy = LinkConfig {
+            loss: 0.3,
+            ..Default::default()
+        };
+        for node in 0..3 {
+            sim.connect(node, node + 1, lossy);
+        }
+        for _ in 0..20 {
+            sim.mine(0);
+            sim.run_for(Duration::from_secs(1));
+        }
+        sim.run_for(Duration::from_secs(60));
+        assert!(sim.stats().lost > 0);
+
+        // once the links clear up, the next block shows the nodes behind what they missed
+        for node in 0..3 {
+            sim.set_link(node, node + 1, LinkConfig::default());
+        }
+        sim.mine(0);
+        assert!(sim.run_until_converged(Duration::from_secs(60)));
+        assert_eq!(sim.height(3), 22);
+    }
+}
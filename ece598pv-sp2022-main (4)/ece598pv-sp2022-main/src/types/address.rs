@@ -58,7 +58,22 @@ impl Address {
 
         Address::from(last_bytes_arr)
     }
+
+    /// The account holding the coins staked by `owner`. Stake accounts start with a fixed prefix,
+    /// so they can be told apart from regular accounts when summing up the total stake.
+    pub fn stake_account(owner: &Address) -> Address {
+        let mut buffer: [u8; 20] = [0; 20];
+        buffer[..4].copy_from_slice(&STAKE_ACCOUNT_PREFIX);
+        buffer[4..].copy_from_slice(&owner.0[..16]);
+        Address(buffer)
+    }
+
+    pub fn is_stake_account(&self) -> bool {
+        self.0[..4] == STAKE_ACCOUNT_PREFIX
+    }
 }
+
+const STAKE_ACCOUNT_PREFIX: [u8; 4] = [0xff, 0x57, 0xa4, 0xe0];
 // DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. BEFORE TEST
 
 #[cfg(test)]
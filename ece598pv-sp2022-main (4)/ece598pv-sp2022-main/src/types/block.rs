@@ -1,6 +1,7 @@
 use serde::{Serialize, Deserialize};
 use ring::{digest};
 
+use crate::types::address::Address;
 use crate::types::hash::{H256, Hashable, do_generate_random_hash};
 use crate::types::transaction::SignedTransaction;
 use crate::types::merkle::{MerkleTree};
@@ -38,7 +39,12 @@ pub struct Header {
     pub nonce: u32, // public, as we have to find the correct nonce when mining
     pub difficulty: H256,
     pub timestamp: u128,
-    pub merkle_root: H256
+    pub merkle_root: H256,
+    pub signer: Vec<u8>, // public key of the block signer, empty if the consensus does not sign blocks
+    pub signature: Vec<u8>, // signature of the signer over the seal hash
+    pub proof: Vec<u8>, // proof of leader eligibility, empty if the consensus has no lottery
+    pub beneficiary: Address, // account that receives the block reward
+    pub uncles: Vec<Header>, // headers of stale blocks this block gives credit to
 }
 
 impl Hashable for Header {
@@ -52,6 +58,16 @@ impl Hashable for Header {
     }
 }
 
+impl Header {
+    /// Hash of the header without its signature, which is what the signer signs
+    pub fn seal_hash(&self) -> H256 {
+        let mut unsealed = self.clone();
+        unsealed.signature = Vec::new();
+
+        unsealed.hash()
+    }
+}
+
 impl Hashable for SignedTransaction{
     fn hash(&self) -> H256{
         let serialized = serde_json::to_string(self).unwrap();
@@ -81,8 +97,12 @@ pub fn do_generate_random_block (parent: &H256) -> Block {
             nonce : random_nonce,
             difficulty: difficulty,
             timestamp: timestamp,
-            merkle_root: random_merkle
-
+            merkle_root: random_merkle,
+            signer: Vec::new(),
+            signature: Vec::new(),
+            proof: Vec::new(),
+            beneficiary: Address::default(),
+            uncles: Vec::new(),
         },
         length: 1,
         data:empty_data,
@@ -90,6 +110,30 @@ pub fn do_generate_random_block (parent: &H256) -> Block {
     random_block
 }
 
+/// Proof-of-work difficulty of the genesis block, unless the genesis spec sets another one
+pub const GENESIS_DIFFICULTY: [u8; 32] = hex!("0000800000000000000000000000000000000000000000000000000000000000");
+
+/// The genesis block of the network with the given genesis spec digest and difficulty. It is the
+/// same on every node, so that peers can check they are on the same network.
+pub fn genesis_block(spec_digest: &H256, difficulty: H256) -> Block {
+    Block {
+        header : Header {
+            parent: [0; 32].into(),
+            nonce : 0,
+            difficulty,
+            timestamp: 0,
+            merkle_root: *spec_digest,
+            signer: Vec::new(),
+            signature: Vec::new(),
+            proof: Vec::new(),
+            beneficiary: Address::default(),
+            uncles: Vec::new(),
+        },
+        length: 1,
+        data: Vec::new(),
+    }
+}
+
 #[cfg(any(test, test_utilities))]
 pub fn generate_random_block(parent: &H256) -> Block {
     do_generate_random_block(parent)
@@ -7,3 +7,8 @@ pub fn random() -> Ed25519KeyPair {
     let pkcs8_bytes = Ed25519KeyPair::generate_pkcs8(&rng).unwrap();
     Ed25519KeyPair::from_pkcs8(pkcs8_bytes.as_ref().into()).unwrap()
 }
+
+/// Create a key pair from a 32-byte seed, so that a node can keep the same identity across restarts.
+pub fn from_seed(seed: &[u8]) -> Result<Ed25519KeyPair, String> {
+    Ed25519KeyPair::from_seed_unchecked(seed).map_err(|e| format!("invalid ed25519 seed: {}", e))
+}
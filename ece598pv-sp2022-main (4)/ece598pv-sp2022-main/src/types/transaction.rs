@@ -23,7 +23,20 @@ pub struct Transaction {
     pub sender: Address,
     pub receiver: Address,
     pub account_nonce: u32,
-    pub value: u32
+    pub value: u32,
+    pub kind: TxKind,
+}
+
+/// What a transaction does with its value
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum TxKind {
+    /// Send the value from the sender to the receiver
+    #[default]
+    Transfer,
+    /// Lock the value from the sender's balance into the sender's stake account, the receiver is ignored
+    Stake,
+    /// Release the value from the sender's stake account back to the sender, the receiver is ignored
+    Unstake,
 }
 
 // HashMap<account address, (account nonce, balance)>
@@ -80,7 +93,8 @@ pub fn do_generate_random_transaction(parent_state: &State) -> Transaction {
         sender: Address::from_public_key_bytes(&rand_tx.to_be_bytes()),
         receiver: Address::from_public_key_bytes(&rand_rx.to_be_bytes()),
         account_nonce: sender_acc_nonce+1,
-        value: rng.gen_range(0..100)
+        value: rng.gen_range(0..100),
+        kind: TxKind::Transfer,
     }
 }
 
@@ -89,14 +103,20 @@ pub fn generate_random_transaction(parent_state: &State) -> Transaction {
     do_generate_random_transaction(parent_state)
 }
 
-pub fn generate_tx_loop(theta: u64, network: NetworkServerHandle, blockchain: Arc<Mutex<Blockchain>>) {
+/// Keep generating random transactions every `theta` milliseconds. `stake_percent` percent of them
+/// are staking or unstaking transactions instead of transfers.
+pub fn generate_tx_loop(theta: u64, stake_percent: u32, network: NetworkServerHandle, blockchain: Arc<Mutex<Blockchain>>) {
+    let mut rng = rand::thread_rng();
     loop {
         let parent_state;
         {
             let blockchain = blockchain.lock().unwrap();
             parent_state = blockchain.get_block_state(&blockchain.tip()).unwrap().clone();
         }
-        let random_tx = do_generate_random_transaction(&parent_state);
+        let mut random_tx = do_generate_random_transaction(&parent_state);
+        if rng.gen_range(0..100) < stake_percent {
+            random_tx.kind = if rng.gen() { TxKind::Stake } else { TxKind::Unstake };
+        }
         let key = key_pair::random();
         let signature = sign(&random_tx, &key);
         let signed_tx = SignedTransaction {
@@ -118,21 +138,33 @@ pub fn execute_tx(parent_state: &State, tx_list: &Vec<SignedTransaction>) -> (St
     let mut valid_tx = vec![];
 
     for tx in tx_list {
+        if tx.transaction.kind != TxKind::Transfer {
+            if execute_stake_tx(&mut new_state, &tx.transaction) {
+                valid_tx.push(tx.clone());
+            }
+            continue;
+        }
+
         // debug!("SENDER: :{:?}, RECEIVER:{:?}",tx.transaction.sender,tx.transaction.receiver);
         let receiver = tx.transaction.receiver;
-        let receiver_balance = parent_state.get(&receiver);
+        // coins only move in and out of stake accounts through staking transactions
+        if receiver.is_stake_account() || tx.transaction.sender.is_stake_account() {
+            continue;
+        }
+        // balances and nonces are the ones after the transactions before in the block
+        let receiver_balance = new_state.get(&receiver);
         let receiver_balance = match receiver_balance {
             None => 0,
             Some((_, balance)) => *balance
         };
-        let receiver_acc_nonce = parent_state.get(&receiver);
+        let receiver_acc_nonce = new_state.get(&receiver);
         let receiver_acc_nonce = match receiver_acc_nonce {
             None => 0,
             Some((acc_nonce, _)) => *acc_nonce
         };
 
         let sender = tx.transaction.sender;
-        let sender_balance = parent_state.get(&sender);
+        let sender_balance = new_state.get(&sender);
         let sender_balance = match sender_balance {
             None => {
                 continue;
@@ -144,7 +176,7 @@ pub fn execute_tx(parent_state: &State, tx_list: &Vec<SignedTransaction>) -> (St
             continue;
         }
 
-        let sender_acc_nonce = parent_state.get(&sender);
+        let sender_acc_nonce = new_state.get(&sender);
         let sender_acc_nonce = match sender_acc_nonce {
             None => {
                 continue;
@@ -173,6 +205,38 @@ pub fn execute_tx(parent_state: &State, tx_list: &Vec<SignedTransaction>) -> (St
     (new_state, valid_tx)
 }
 
+/// Execute a staking or unstaking transaction on top of `state`. Returns false and leaves the state
+/// untouched if the transaction is not valid.
+fn execute_stake_tx(state: &mut State, tx: &Transaction) -> bool {
+    let sender = tx.sender;
+    if sender.is_stake_account() {
+        return false;
+    }
+    let (sender_acc_nonce, sender_balance) = match state.get(&sender) {
+        None => return false,
+        Some(account) => *account,
+    };
+    if tx.account_nonce != sender_acc_nonce + 1 {
+        return false;
+    }
+
+    let stake_account = Address::stake_account(&sender);
+    let (stake_acc_nonce, stake_balance) = match state.get(&stake_account) {
+        None => (0, 0),
+        Some(account) => *account,
+    };
+
+    let (sender_balance, stake_balance) = match tx.kind {
+        TxKind::Stake if sender_balance >= tx.value => (sender_balance - tx.value, stake_balance + tx.value),
+        TxKind::Unstake if stake_balance >= tx.value => (sender_balance + tx.value, stake_balance - tx.value),
+        _ => return false,
+    };
+    state.insert(sender, (tx.account_nonce, sender_balance));
+    state.insert(stake_account, (stake_acc_nonce, stake_balance));
+
+    true
+}
+
 // DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. BEFORE TEST
 
 #[cfg(test)]
@@ -183,21 +247,74 @@ mod tests {
     
     #[test]
     fn sign_verify() {
-        let t = generate_random_transaction();
+        let t = generate_random_transaction(&State::new());
         let key = key_pair::random();
         let signature = sign(&t, &key);
         assert!(verify(&t, key.public_key().as_ref(), signature.as_ref()));
     }
     #[test]
     fn sign_verify_two() {
-        let t = generate_random_transaction();
+        let t = generate_random_transaction(&State::new());
         let key = key_pair::random();
         let signature = sign(&t, &key);
         let key_2 = key_pair::random();
-        let t_2 = generate_random_transaction();
+        let t_2 = generate_random_transaction(&State::new());
         assert!(!verify(&t_2, key.public_key().as_ref(), signature.as_ref()));
         assert!(!verify(&t, key_2.public_key().as_ref(), signature.as_ref()));
     }
+    #[test]
+    fn stake_and_unstake() {
+        let key = key_pair::random();
+        let owner = Address::from_public_key_bytes(key.public_key().as_ref());
+        let mut state = State::new();
+        state.insert(owner, (0, 100));
+
+        let mut stake = Transaction { sender: owner, receiver: owner, account_nonce: 1, value: 60, kind: TxKind::Stake };
+        let mut unstake = Transaction { sender: owner, receiver: owner, account_nonce: 2, value: 70, kind: TxKind::Unstake };
+        let txs: Vec<SignedTransaction> = vec![stake.clone(), unstake.clone()].into_iter().map(|t| SignedTransaction {
+            signature: sign(&t, &key).as_ref().to_vec(),
+            public_key: key.public_key().as_ref().to_vec(),
+            transaction: t,
+        }).collect();
+        let (new_state, valid_tx) = execute_tx(&state, &txs);
+        // unstaking more than the stake is rejected
+        assert_eq!(valid_tx.len(), 1);
+        assert_eq!(new_state[&owner], (1, 40));
+        assert_eq!(new_state[&Address::stake_account(&owner)], (0, 60));
+
+        stake.value = 200;
+        assert!(!execute_stake_tx(&mut state.clone(), &stake));
+        unstake.value = 60;
+        let mut staked = new_state.clone();
+        assert!(execute_stake_tx(&mut staked, &unstake));
+        assert_eq!(staked[&owner], (2, 100));
+        assert_eq!(staked[&Address::stake_account(&owner)], (0, 0));
+    }
+    #[test]
+    fn no_double_spend_in_block() {
+        let key = key_pair::random();
+        let owner = Address::from_public_key_bytes(key.public_key().as_ref());
+        let other = Address::from_public_key_bytes(&[1; 32]);
+        let mut state = State::new();
+        state.insert(owner, (0, 100));
+
+        let stake = Transaction { sender: owner, receiver: owner, account_nonce: 1, value: 60, kind: TxKind::Stake };
+        let transfer = Transaction { sender: owner, receiver: other, account_nonce: 1, value: 60, kind: TxKind::Transfer };
+        let next = Transaction { sender: owner, receiver: other, account_nonce: 2, value: 30, kind: TxKind::Transfer };
+        let txs: Vec<SignedTransaction> = vec![stake, transfer, next].into_iter().map(|t| SignedTransaction {
+            signature: sign(&t, &key).as_ref().to_vec(),
+            public_key: key.public_key().as_ref().to_vec(),
+            transaction: t,
+        }).collect();
+        let (new_state, valid_tx) = execute_tx(&state, &txs);
+        // the transfer reuses the nonce of the stake, the one after it spends what is left
+        assert_eq!(valid_tx.len(), 2);
+        assert_eq!(valid_tx[0].transaction.kind, TxKind::Stake);
+        assert_eq!(valid_tx[1].transaction.account_nonce, 2);
+        assert_eq!(new_state[&owner], (2, 10));
+        assert_eq!(new_state[&other], (0, 30));
+        assert_eq!(new_state[&Address::stake_account(&owner)], (0, 60));
+    }
 }
 
 // DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. AFTER TEST
\ No newline at end of file